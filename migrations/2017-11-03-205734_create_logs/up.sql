@@ -1,7 +1,6 @@
--- When creating a table using diesel's migration, ideally we'd use this pragma
--- command, but we receive the error "cannot change into wal mode from within a
--- transaction" instead.
--- PRAGMA journal_mode=WAL;
+-- WAL mode can't be enabled from within the transaction that migrations run
+-- in ("cannot change into wal mode from within a transaction"), so
+-- rrinlog_core::db::setup enables it before migrating.
 CREATE TABLE logs(
     ri INTEGER PRIMARY KEY NOT NULL,
     epoch INT8 NOT NULL,
@@ -0,0 +1,11 @@
+ALTER TABLE logs ADD COLUMN query TEXT;
+ALTER TABLE logs ADD COLUMN fragment TEXT;
+ALTER TABLE logs ADD COLUMN query_params TEXT;
+
+-- Existing rows stored the raw request target in the path, so split off their
+-- query strings. Decoding the query parameters isn't possible in SQL, so
+-- query_params is only populated for newly ingested rows.
+UPDATE logs
+SET    query = substr(path, instr(path, '?') + 1),
+       path = substr(path, 1, instr(path, '?') - 1)
+WHERE  instr(path, '?') > 0;
@@ -0,0 +1,4 @@
+ALTER TABLE logs ADD COLUMN request_time REAL;
+ALTER TABLE logs ADD COLUMN upstream_response_time REAL;
+ALTER TABLE logs ADD COLUMN upstream_addr TEXT;
+ALTER TABLE logs ADD COLUMN upstream_status INT;
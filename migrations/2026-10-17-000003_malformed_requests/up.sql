@@ -0,0 +1,3 @@
+-- Set when the request line couldn't be split into a method, path, and version (eg: TLS
+-- handshakes sent to the plain HTTP port)
+ALTER TABLE logs ADD COLUMN malformed BOOLEAN NOT NULL DEFAULT 0;
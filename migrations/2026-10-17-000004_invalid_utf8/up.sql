@@ -0,0 +1,2 @@
+-- Set when the line wasn't valid UTF-8 and invalid sequences were replaced with U+FFFD
+ALTER TABLE logs ADD COLUMN invalid_utf8 BOOLEAN NOT NULL DEFAULT 0;
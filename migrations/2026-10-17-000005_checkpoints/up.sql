@@ -0,0 +1,6 @@
+-- Position of the last line ingested from each followed log file
+CREATE TABLE checkpoints(
+    path TEXT PRIMARY KEY NOT NULL,
+    inode INT8 NOT NULL,
+    byte_offset INT8 NOT NULL
+);
@@ -0,0 +1,4 @@
+-- The hostname and tag of the syslog message that delivered the line, so that several servers can
+-- log into the same database
+ALTER TABLE logs ADD COLUMN syslog_hostname TEXT;
+ALTER TABLE logs ADD COLUMN syslog_tag TEXT;
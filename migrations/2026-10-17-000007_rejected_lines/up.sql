@@ -0,0 +1,7 @@
+-- Lines that failed to parse, kept so that they can be reprocessed once the format is fixed
+CREATE TABLE rejected_lines(
+    id INTEGER PRIMARY KEY NOT NULL,
+    epoch INT8 NOT NULL,
+    line BLOB NOT NULL,
+    error TEXT NOT NULL
+);
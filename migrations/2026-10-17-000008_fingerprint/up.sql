@@ -0,0 +1,5 @@
+-- A hash of the line that the row was parsed from. It is only set when ingesting with --dedup, so
+-- that a line ingested twice is stored once, and the other rows don't conflict as NULLs are
+-- distinct.
+ALTER TABLE logs ADD COLUMN fingerprint BLOB;
+CREATE UNIQUE INDEX idx_fingerprint ON logs(fingerprint);
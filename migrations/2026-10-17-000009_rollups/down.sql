@@ -0,0 +1,4 @@
+DROP INDEX idx_remote_addr;
+DROP TABLE rollups_day;
+DROP TABLE rollups_hour;
+DROP TABLE rollups_minute;
@@ -0,0 +1,33 @@
+-- Counts and bytes sent per host and status class (status / 100, or 0 without a status), so that
+-- Grafana can graph long ranges without scanning the raw rows. Each bucket is the epoch of the
+-- start of its minute, hour, or day.
+CREATE TABLE rollups_minute(
+    bucket INT8 NOT NULL,
+    host TEXT NOT NULL,
+    status_class INT NOT NULL,
+    requests INT8 NOT NULL,
+    bytes INT8 NOT NULL,
+    PRIMARY KEY (bucket, host, status_class)
+);
+
+CREATE TABLE rollups_hour(
+    bucket INT8 NOT NULL,
+    host TEXT NOT NULL,
+    status_class INT NOT NULL,
+    requests INT8 NOT NULL,
+    bytes INT8 NOT NULL,
+    PRIMARY KEY (bucket, host, status_class)
+);
+
+CREATE TABLE rollups_day(
+    bucket INT8 NOT NULL,
+    host TEXT NOT NULL,
+    status_class INT NOT NULL,
+    requests INT8 NOT NULL,
+    bytes INT8 NOT NULL,
+    PRIMARY KEY (bucket, host, status_class)
+);
+
+-- The rollups don't know who made the requests, so the server subtracts the requests made from its
+-- own address with this index
+CREATE INDEX idx_remote_addr ON logs(remote_addr, epoch);
@@ -0,0 +1,6 @@
+-- Settings that describe how the rows were stored (eg: whether addresses are anonymized), so that
+-- whoever reads the db later knows what the columns hold
+CREATE TABLE metadata (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+);
@@ -0,0 +1,42 @@
+-- SQLite can't drop columns, so the table is rebuilt without them
+CREATE TABLE logs_old(
+    ri INTEGER PRIMARY KEY NOT NULL,
+    epoch INT8 NOT NULL,
+    remote_addr TEXT,
+    remote_user TEXT,
+    status INT,
+    method TEXT,
+    path TEXT,
+    version TEXT,
+    body_bytes_sent INT,
+    referer TEXT,
+    user_agent TEXT,
+    host TEXT NOT NULL,
+    query TEXT,
+    fragment TEXT,
+    query_params TEXT,
+    request_time REAL,
+    upstream_response_time REAL,
+    upstream_addr TEXT,
+    upstream_status INT,
+    malformed BOOLEAN NOT NULL DEFAULT 0,
+    invalid_utf8 BOOLEAN NOT NULL DEFAULT 0,
+    syslog_hostname TEXT,
+    syslog_tag TEXT,
+    fingerprint BLOB
+);
+
+INSERT INTO logs_old
+SELECT ri, epoch, remote_addr, remote_user, status, method, path, version,
+       body_bytes_sent, referer, user_agent, host, query, fragment, query_params,
+       request_time, upstream_response_time, upstream_addr, upstream_status, malformed,
+       invalid_utf8, syslog_hostname, syslog_tag, fingerprint
+FROM   logs;
+
+DROP TABLE logs;
+ALTER TABLE logs_old RENAME TO logs;
+
+CREATE index idx_epoch on logs(epoch);
+CREATE index idx_host ON logs(host);
+CREATE UNIQUE INDEX idx_fingerprint ON logs(fingerprint);
+CREATE INDEX idx_remote_addr ON logs(remote_addr, epoch);
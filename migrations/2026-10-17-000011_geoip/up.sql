@@ -0,0 +1,7 @@
+-- Where the visitor is from, looked up from the address in a MaxMind DB with --geoip. The country
+-- is the ISO code (eg: GB), while the region (eg: England) and city are English names.
+ALTER TABLE logs ADD COLUMN country TEXT;
+ALTER TABLE logs ADD COLUMN region TEXT;
+ALTER TABLE logs ADD COLUMN city TEXT;
+ALTER TABLE logs ADD COLUMN latitude REAL;
+ALTER TABLE logs ADD COLUMN longitude REAL;
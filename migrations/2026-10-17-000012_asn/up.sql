@@ -0,0 +1,3 @@
+-- The autonomous system that announces the visitor's address, looked up with --asn
+ALTER TABLE logs ADD COLUMN asn INT8;
+ALTER TABLE logs ADD COLUMN as_org TEXT;
@@ -1,7 +1,15 @@
 use api::*;
+use chrono::prelude::*;
 use diesel::prelude::*;
 use diesel::sql_query;
-use diesel::sql_types::{BigInt, Integer, Text};
+use diesel::sql_types::{BigInt, Double, Integer, Nullable, Text};
+use errors::DataError;
+use rrinlog_core::db;
+use rrinlog_core::rollup::Resolution;
+use rrinlog_core::shard;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use uom::si::i64::*;
 use uom::si::time::second;
 
@@ -34,10 +42,211 @@ pub struct OutboundData {
     pub bytes: i64,
 }
 
-static BLOG_POST_QUERY: &'static str = r#"
+/// The number of requests in an interval that took the same number of milliseconds
+#[derive(PartialEq, Debug, QueryableByName)]
+pub struct Latency {
+    #[sql_type = "BigInt"]
+    pub ep: i64,
+    #[sql_type = "BigInt"]
+    pub ms: i64,
+    #[sql_type = "Integer"]
+    pub requests: i32,
+}
+
+/// Requests from a city (or a country, when the city isn't known), located at the average
+/// coordinates of its rows
+#[derive(PartialEq, Debug, QueryableByName)]
+pub struct Location {
+    #[sql_type = "Nullable<Text>"]
+    pub country: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    pub city: Option<String>,
+    #[sql_type = "Double"]
+    pub latitude: f64,
+    #[sql_type = "Double"]
+    pub longitude: f64,
+    #[sql_type = "Integer"]
+    pub requests: i32,
+}
+
+impl Location {
+    /// Label of the location on the map (eg: "London, GB")
+    pub fn name(&self) -> String {
+        match (&self.city, &self.country) {
+            (Some(city), Some(country)) => format!("{}, {}", city, country),
+            (Some(x), None) | (None, Some(x)) => x.clone(),
+            (None, None) => String::from("Unknown"),
+        }
+    }
+}
+
+/// Requests and bytes sent to an autonomous system
+#[derive(PartialEq, Debug, QueryableByName)]
+pub struct AsnTraffic {
+    #[sql_type = "BigInt"]
+    pub asn: i64,
+    #[sql_type = "Nullable<Text>"]
+    pub organization: Option<String>,
+    #[sql_type = "Integer"]
+    pub requests: i32,
+    #[sql_type = "BigInt"]
+    pub bytes: i64,
+}
+
+/// Most autonomous systems listed by `top_asns`
+const TOP_ASNS: usize = 25;
+
+/// Most dbs SQLite lets a connection attach (`SQLITE_MAX_ATTACHED`)
+const MAX_ATTACHED: usize = 10;
+
+/// Names the tables of the dbs that a connection queries. Tables in more than one attached shard
+/// are unioned.
+pub struct Tables {
+    schemas: Vec<String>,
+}
+
+impl Tables {
+    fn get(&self, table: &str) -> String {
+        match self.schemas.as_slice() {
+            [schema] => format!("{}.{}", schema, table),
+            schemas => {
+                let selects: Vec<String> = schemas
+                    .iter()
+                    .map(|x| format!("SELECT * FROM {}.{}", x, table))
+                    .collect();
+                format!("({})", selects.join(" UNION ALL "))
+            }
+        }
+    }
+}
+
+/// The dbs that a query is answered from: the db given with `--db`, or with `--monthly-shards`,
+/// the shards that overlap the range. A connection can only attach so many shards, so a long range
+/// is split across connections and their rows are merged.
+pub struct Db {
+    groups: Vec<(SqliteConnection, Tables)>,
+}
+
+impl Db {
+    pub fn single(conn: SqliteConnection) -> Db {
+        Db {
+            groups: vec![(
+                conn,
+                Tables {
+                    schemas: vec![String::from("main")],
+                },
+            )],
+        }
+    }
+
+    /// Attaches the shards of the db that overlap the range. Months without a shard have no rows.
+    pub fn shards(db: &str, range: &Range) -> Result<Db, DataError> {
+        let db = Path::new(db);
+        let paths: Vec<_> = shard::months_between(range.from.timestamp(), range.to.timestamp())
+            .into_iter()
+            .map(|month| month.path(db))
+            .filter(|path| path.exists())
+            .collect();
+
+        let mut groups = Vec::new();
+        for chunk in paths.chunks(MAX_ATTACHED) {
+            let conn = SqliteConnection::establish(":memory:")
+                .map_err(|e| DataError::DbConn(String::from(":memory:"), e))?;
+            let mut schemas = Vec::new();
+            for (i, path) in chunk.iter().enumerate() {
+                let schema = format!("s{}", i);
+                sql_query(format!("ATTACH DATABASE ? AS {}", schema))
+                    .bind::<Text, _>(path.to_string_lossy())
+                    .execute(&conn)
+                    .map_err(|e| DataError::DbQuery(format!("attach {}", path.display()), e))?;
+                check_schema(&conn, &schema, path)?;
+                schemas.push(schema);
+            }
+            groups.push((conn, Tables { schemas }));
+        }
+
+        Ok(Db { groups })
+    }
+
+    /// Runs the query on each connection, concatenating the rows
+    fn query<T, F>(&self, f: F) -> QueryResult<Vec<T>>
+    where
+        F: Fn(&SqliteConnection, &Tables) -> QueryResult<Vec<T>>,
+    {
+        let mut rows = Vec::new();
+        for (conn, tables) in &self.groups {
+            rows.extend(f(conn, tables)?);
+        }
+        Ok(rows)
+    }
+
+    /// Whether the rows of a group by may be split across connections and need to be merged
+    fn is_split(&self) -> bool {
+        self.groups.len() > 1
+    }
+}
+
+#[derive(QueryableByName)]
+struct SchemaVersion {
+    #[sql_type = "Nullable<Text>"]
+    version: Option<String>,
+}
+
+/// Shards created after the server started weren't set up with the others, so each shard is
+/// checked as it is attached. A shard that is behind is migrated, and one with a newer schema than
+/// the server understands is refused.
+fn check_schema(conn: &SqliteConnection, schema: &str, path: &Path) -> Result<(), DataError> {
+    let qs = format!(
+        "SELECT MAX(version) AS version FROM {}.__diesel_schema_migrations",
+        schema
+    );
+    let version = sql_query(qs)
+        .get_result::<SchemaVersion>(conn)
+        .ok()
+        .and_then(|x| x.version);
+    if version.as_deref() == Some(db::SCHEMA_VERSION) {
+        return Ok(());
+    }
+
+    db::setup(&path.to_string_lossy())
+        .map(|_| ())
+        .map_err(DataError::DbSetup)
+}
+
+pub fn blog_posts(db: &Db, range: &Range, ip: &str) -> QueryResult<Vec<BlogPost>> {
+    let rows = db.query(|conn, tables| blog_posts_in(conn, tables, range, ip))?;
+    if !db.is_split() {
+        return Ok(rows);
+    }
+
+    let mut views: HashMap<String, i32> = HashMap::new();
+    for row in rows {
+        *views.entry(row.referer).or_insert(0) += row.views;
+    }
+
+    let mut rows: Vec<BlogPost> = views
+        .into_iter()
+        .map(|(referer, views)| BlogPost { referer, views })
+        .collect();
+    rows.sort_by(|a, b| {
+        b.views
+            .cmp(&a.views)
+            .then_with(|| a.referer.cmp(&b.referer))
+    });
+    Ok(rows)
+}
+
+fn blog_posts_in(
+    conn: &SqliteConnection,
+    tables: &Tables,
+    range: &Range,
+    ip: &str,
+) -> QueryResult<Vec<BlogPost>> {
+    let qs = format!(
+        r#"
 SELECT referer,
        Count(*) AS views
-FROM   logs
+FROM   {logs}
 WHERE  +host = 'comments.nbsoftsolutions.com'
        AND method = 'GET'
        AND path <> '/js/embed.min.js'
@@ -47,28 +256,129 @@ WHERE  +host = 'comments.nbsoftsolutions.com'
        AND remote_addr <> ?
 GROUP  BY referer
 ORDER  BY views DESC
-"#;
+"#,
+        logs = tables.get("logs")
+    );
 
-pub fn blog_posts(conn: &SqliteConnection, range: &Range, ip: &str) -> QueryResult<Vec<BlogPost>> {
-    sql_query(BLOG_POST_QUERY)
+    sql_query(qs)
         .bind::<BigInt, _>(range.from.timestamp())
         .bind::<BigInt, _>(range.to.timestamp())
         .bind::<Text, _>(ip)
         .load(conn)
 }
 
-pub fn sites(conn: &SqliteConnection, range: &Range, interval: Time) -> QueryResult<Vec<Sites>> {
-    let qs = r#"
+/// The whole buckets of the resolution within the range. The partial buckets at either end aren't
+/// in the rollups, as they would count requests outside of the range.
+fn whole_buckets(range: &Range, resolution: Resolution) -> Option<(i64, i64)> {
+    let from = resolution.bucket(range.from.timestamp() + resolution.seconds() - 1);
+    let to = resolution.bucket(range.to.timestamp());
+    if from < to {
+        Some((from, to))
+    } else {
+        None
+    }
+}
+
+/// The parts of the range before and after the whole buckets, which are read from the raw rows
+fn edges(range: &Range, (from, to): (i64, i64)) -> Vec<Range> {
+    vec![
+        Range {
+            from: range.from,
+            to: Utc.timestamp(from, 0),
+        },
+        Range {
+            from: Utc.timestamp(to, 0),
+            to: range.to,
+        },
+    ]
+    .into_iter()
+    .filter(|x| x.from < x.to)
+    .collect()
+}
+
+/// The coarsest rollup that fits the interval and the whole buckets of it within the range.
+/// Intervals that aren't a whole number of minutes have to be computed from the raw rows.
+fn rollup_for(range: &Range, interval: Time) -> Option<(Resolution, (i64, i64))> {
+    let resolution = Resolution::coarsest_for(interval.get::<second>())?;
+    whole_buckets(range, resolution).map(|buckets| (resolution, buckets))
+}
+
+/// Counts requests per host from the coarsest rollup that fits the interval, with the raw rows
+/// filling in the rest of the range
+pub fn sites(db: &Db, range: &Range, interval: Time) -> QueryResult<Vec<Sites>> {
+    let rows = match rollup_for(range, interval) {
+        Some((resolution, buckets)) => {
+            let mut rows =
+                db.query(|conn, tables| sites_rollup(conn, tables, buckets, interval, resolution))?;
+            for edge in &edges(range, buckets) {
+                rows.extend(db.query(|conn, tables| sites_raw(conn, tables, edge, interval))?);
+            }
+            rows
+        }
+        None => db.query(|conn, tables| sites_raw(conn, tables, range, interval))?,
+    };
+
+    let mut views: BTreeMap<(i64, String), i32> = BTreeMap::new();
+    for row in rows {
+        *views.entry((row.ep, row.host)).or_insert(0) += row.views;
+    }
+
+    Ok(views
+        .into_iter()
+        .map(|((ep, host), views)| Sites { ep, host, views })
+        .collect())
+}
+
+fn sites_rollup(
+    conn: &SqliteConnection,
+    tables: &Tables,
+    (from, to): (i64, i64),
+    interval: Time,
+    resolution: Resolution,
+) -> QueryResult<Vec<Sites>> {
+    let secs = interval.get::<second>();
+    let qs = format!(
+        r#"
+SELECT (bucket / {secs}) * {secs} * 1000 AS ep,
+       host,
+       SUM(requests) AS views
+FROM   {table}
+WHERE  host LIKE "%nbsoftsolutions.com"
+       AND bucket >= ?
+       AND bucket < ?
+GROUP BY bucket / {secs},
+         host
+"#,
+        secs = secs,
+        table = tables.get(resolution.table())
+    );
+
+    sql_query(qs)
+        .bind::<BigInt, _>(from)
+        .bind::<BigInt, _>(to)
+        .load(conn)
+}
+
+fn sites_raw(
+    conn: &SqliteConnection,
+    tables: &Tables,
+    range: &Range,
+    interval: Time,
+) -> QueryResult<Vec<Sites>> {
+    let qs = format!(
+        r#"
 SELECT (epoch / ?) * ? * 1000 AS ep,
        host,
        Count(*) AS views
-FROM   logs
+FROM   {logs}
 WHERE  +host LIKE "%nbsoftsolutions.com"
        AND epoch >= ?
        AND epoch < ?
 GROUP BY epoch / ?,
          host
-"#;
+"#,
+        logs = tables.get("logs")
+    );
 
     sql_query(qs)
         .bind::<Integer, _>(interval.get::<second>() as i32)
@@ -79,27 +389,313 @@ GROUP BY epoch / ?,
         .load(conn)
 }
 
+/// Sums the bytes sent to addresses other than `ip` (including rows without an address) from the
+/// coarsest rollup that fits the interval, with the raw rows filling in the rest of the range. The
+/// rollups include requests made by `ip`, so those are looked up in the raw rows and subtracted,
+/// which is cheap as long as `ip` makes few requests. Once the raw rows have been pruned, `ip`'s
+/// requests can no longer be subtracted.
 pub fn outbound_data(
+    db: &Db,
+    range: &Range,
+    ip: &str,
+    interval: Time,
+) -> QueryResult<Vec<OutboundData>> {
+    let rows = match rollup_for(range, interval) {
+        Some((resolution, buckets)) => {
+            let mut rows = db.query(|conn, tables| {
+                outbound_data_rollup(conn, tables, buckets, ip, interval, resolution)
+            })?;
+            for edge in &edges(range, buckets) {
+                rows.extend(
+                    db.query(|conn, tables| outbound_data_raw(conn, tables, edge, ip, interval))?,
+                );
+            }
+            rows
+        }
+        None => db.query(|conn, tables| outbound_data_raw(conn, tables, range, ip, interval))?,
+    };
+
+    let mut totals: BTreeMap<i64, (i32, i64)> = BTreeMap::new();
+    for row in rows {
+        let total = totals.entry(row.ep).or_insert((0, 0));
+        total.0 += row.views;
+        total.1 += row.bytes;
+    }
+
+    Ok(totals
+        .into_iter()
+        .map(|(ep, (views, bytes))| OutboundData { ep, views, bytes })
+        .collect())
+}
+
+fn outbound_data_rollup(
     conn: &SqliteConnection,
+    tables: &Tables,
+    (from, to): (i64, i64),
+    ip: &str,
+    interval: Time,
+    resolution: Resolution,
+) -> QueryResult<Vec<OutboundData>> {
+    let secs = interval.get::<second>();
+    let qs = format!(
+        r#"
+SELECT (bucket / {secs}) * {secs} * 1000 AS ep,
+       SUM(requests) AS views,
+       SUM(bytes) AS data
+FROM   {table}
+WHERE  bucket >= ?
+       AND bucket < ?
+GROUP BY bucket / {secs}
+ORDER BY ep
+"#,
+        secs = secs,
+        table = tables.get(resolution.table())
+    );
+
+    let mut rows: Vec<OutboundData> = sql_query(qs)
+        .bind::<BigInt, _>(from)
+        .bind::<BigInt, _>(to)
+        .load(conn)?;
+
+    let own_qs = format!(
+        r#"
+SELECT (epoch / {secs}) * {secs} * 1000 AS ep,
+       COUNT(*) AS views,
+       COALESCE(SUM(body_bytes_sent), 0) AS data
+FROM   {logs}
+WHERE  remote_addr = ?
+       AND epoch >= ?
+       AND epoch < ?
+GROUP BY epoch / {secs}
+"#,
+        secs = secs,
+        logs = tables.get("logs")
+    );
+
+    let own: Vec<OutboundData> = sql_query(own_qs)
+        .bind::<Text, _>(ip)
+        .bind::<BigInt, _>(from)
+        .bind::<BigInt, _>(to)
+        .load(conn)?;
+
+    let own: HashMap<i64, OutboundData> = own.into_iter().map(|x| (x.ep, x)).collect();
+    for row in &mut rows {
+        if let Some(own) = own.get(&row.ep) {
+            row.views -= own.views;
+            row.bytes -= own.bytes;
+        }
+    }
+
+    rows.retain(|x| x.views > 0);
+    Ok(rows)
+}
+
+fn outbound_data_raw(
+    conn: &SqliteConnection,
+    tables: &Tables,
     range: &Range,
     ip: &str,
     interval: Time,
 ) -> QueryResult<Vec<OutboundData>> {
     let qs = format!(
         r#"
-SELECT (epoch / {}) * {} * 1000 AS ep,
+SELECT (epoch / {secs}) * {secs} * 1000 AS ep,
        COUNT(*) AS views,
-       SUM(body_bytes_sent) as data
-FROM   logs
+       COALESCE(SUM(body_bytes_sent), 0) as data
+FROM   {logs}
 WHERE  epoch >= ?
        AND epoch < ?
-       AND remote_addr <> ?
-GROUP BY epoch / ({})
+       AND remote_addr IS NOT ?
+GROUP BY epoch / ({secs})
 ORDER BY ep
 "#,
-        interval.get::<second>(),
-        interval.get::<second>(),
-        interval.get::<second>()
+        secs = interval.get::<second>(),
+        logs = tables.get("logs")
+    );
+
+    sql_query(qs)
+        .bind::<BigInt, _>(range.from.timestamp())
+        .bind::<BigInt, _>(range.to.timestamp())
+        .bind::<Text, _>(ip)
+        .load(conn)
+}
+
+/// Returns a histogram of the request times (rounded to milliseconds) of each interval, sorted by
+/// interval and then by time so that percentiles can be read off each interval's rows. Only a row
+/// per distinct time is loaded, rather than every request in the range.
+pub fn latency(db: &Db, range: &Range, interval: Time) -> QueryResult<Vec<Latency>> {
+    let rows = db.query(|conn, tables| latency_in(conn, tables, range, interval))?;
+    if !db.is_split() {
+        return Ok(rows);
+    }
+
+    let mut requests: BTreeMap<(i64, i64), i32> = BTreeMap::new();
+    for row in rows {
+        *requests.entry((row.ep, row.ms)).or_insert(0) += row.requests;
+    }
+
+    Ok(requests
+        .into_iter()
+        .map(|((ep, ms), requests)| Latency { ep, ms, requests })
+        .collect())
+}
+
+fn latency_in(
+    conn: &SqliteConnection,
+    tables: &Tables,
+    range: &Range,
+    interval: Time,
+) -> QueryResult<Vec<Latency>> {
+    let qs = format!(
+        r#"
+SELECT (epoch / ?) * ? * 1000 AS ep,
+       CAST(ROUND(request_time * 1000) AS INTEGER) AS ms,
+       COUNT(*) AS requests
+FROM   {logs}
+WHERE  epoch >= ?
+       AND epoch < ?
+       AND request_time IS NOT NULL
+GROUP BY ep,
+         ms
+ORDER BY ep,
+         ms
+"#,
+        logs = tables.get("logs")
+    );
+
+    sql_query(qs)
+        .bind::<Integer, _>(interval.get::<second>() as i32)
+        .bind::<Integer, _>(interval.get::<second>() as i32)
+        .bind::<BigInt, _>(range.from.timestamp())
+        .bind::<BigInt, _>(range.to.timestamp())
+        .load(conn)
+}
+
+/// Counts the requests per location of the rows that were looked up with `rrinlog --geoip`, most
+/// requests first
+pub fn locations(db: &Db, range: &Range, ip: &str) -> QueryResult<Vec<Location>> {
+    let rows = db.query(|conn, tables| locations_in(conn, tables, range, ip))?;
+    if !db.is_split() {
+        return Ok(rows);
+    }
+
+    // The coordinates are weighed by the requests of each connection so that their average is
+    // kept: they are summed here and divided once all the requests are counted
+    let mut merged: BTreeMap<(Option<String>, Option<String>), Location> = BTreeMap::new();
+    for row in rows {
+        let weight = f64::from(row.requests);
+        let entry = merged
+            .entry((row.country.clone(), row.city.clone()))
+            .or_insert(Location {
+                latitude: 0.0,
+                longitude: 0.0,
+                requests: 0,
+                ..row
+            });
+        entry.latitude += row.latitude * weight;
+        entry.longitude += row.longitude * weight;
+        entry.requests += row.requests;
+    }
+
+    let mut rows: Vec<Location> = merged
+        .into_values()
+        .map(|x| Location {
+            latitude: x.latitude / f64::from(x.requests),
+            longitude: x.longitude / f64::from(x.requests),
+            ..x
+        })
+        .collect();
+    rows.sort_by_key(|x| Reverse(x.requests));
+    Ok(rows)
+}
+
+fn locations_in(
+    conn: &SqliteConnection,
+    tables: &Tables,
+    range: &Range,
+    ip: &str,
+) -> QueryResult<Vec<Location>> {
+    let qs = format!(
+        r#"
+SELECT country,
+       city,
+       AVG(latitude) AS latitude,
+       AVG(longitude) AS longitude,
+       COUNT(*) AS requests
+FROM   {logs}
+WHERE  epoch >= ?
+       AND epoch < ?
+       AND latitude IS NOT NULL
+       AND longitude IS NOT NULL
+       AND remote_addr IS NOT ?
+GROUP  BY country,
+          city
+ORDER  BY requests DESC,
+          country,
+          city
+"#,
+        logs = tables.get("logs")
+    );
+
+    sql_query(qs)
+        .bind::<BigInt, _>(range.from.timestamp())
+        .bind::<BigInt, _>(range.to.timestamp())
+        .bind::<Text, _>(ip)
+        .load(conn)
+}
+
+/// Counts the requests and bytes per autonomous system of the rows that were looked up with
+/// `rrinlog --asn`, keeping those with the most requests (and then bytes)
+pub fn top_asns(db: &Db, range: &Range, ip: &str) -> QueryResult<Vec<AsnTraffic>> {
+    let mut rows = db.query(|conn, tables| asns_in(conn, tables, range, ip))?;
+    if db.is_split() {
+        let mut merged: BTreeMap<i64, AsnTraffic> = BTreeMap::new();
+        for row in rows {
+            match merged.get_mut(&row.asn) {
+                Some(entry) => {
+                    entry.requests += row.requests;
+                    entry.bytes += row.bytes;
+                    if entry.organization.is_none() {
+                        entry.organization = row.organization;
+                    }
+                }
+                None => {
+                    merged.insert(row.asn, row);
+                }
+            }
+        }
+
+        rows = merged.into_values().collect();
+        rows.sort_by_key(|x| (Reverse(x.requests), Reverse(x.bytes)));
+    }
+
+    rows.truncate(TOP_ASNS);
+    Ok(rows)
+}
+
+fn asns_in(
+    conn: &SqliteConnection,
+    tables: &Tables,
+    range: &Range,
+    ip: &str,
+) -> QueryResult<Vec<AsnTraffic>> {
+    let qs = format!(
+        r#"
+SELECT asn,
+       MAX(as_org) AS organization,
+       COUNT(*) AS requests,
+       COALESCE(SUM(body_bytes_sent), 0) AS bytes
+FROM   {logs}
+WHERE  epoch >= ?
+       AND epoch < ?
+       AND asn IS NOT NULL
+       AND remote_addr IS NOT ?
+GROUP  BY asn
+ORDER  BY requests DESC,
+          bytes DESC,
+          asn
+"#,
+        logs = tables.get("logs")
     );
 
     sql_query(qs)
@@ -111,19 +707,79 @@ ORDER BY ep
 
 #[cfg(test)]
 mod tests {
+    extern crate tempdir;
+
     use super::*;
-    use chrono::prelude::*;
+    use rrinlog_core::models::NewLog;
+    use rrinlog_core::{db, rollup, schema};
+    use std::path::PathBuf;
 
-    #[test]
-    fn test_blog_posts() {
-        let conn =
-            SqliteConnection::establish("../test-assets/test-access.db").expect("To open db");
-        let rng = Range {
+    fn test_db() -> Db {
+        Db::single(
+            SqliteConnection::establish("../test-assets/test-access.db").expect("To open db"),
+        )
+    }
+
+    /// The hour that the rows of `log` are in
+    fn log_hour() -> Range {
+        Range {
             from: Utc.ymd(2017, 11, 14).and_hms(13, 0, 0),
             to: Utc.ymd(2017, 11, 14).and_hms(14, 0, 0),
-        };
+        }
+    }
+
+    /// A request from the address at the start of `log_hour`
+    fn log(addr: Option<&'static str>) -> NewLog<'static> {
+        NewLog {
+            epoch: log_hour().from.timestamp(),
+            remote_addr: addr.map(|x| x.into()),
+            host: "nbsoftsolutions.com".into(),
+            ..NewLog::default()
+        }
+    }
+
+    /// `log_hour` a few seconds past the interval boundaries
+    fn unaligned_hour() -> Range {
+        Range {
+            from: Utc.ymd(2017, 11, 14).and_hms(13, 0, 3),
+            to: Utc.ymd(2017, 11, 14).and_hms(14, 0, 3),
+        }
+    }
 
-        let result = blog_posts(&conn, &rng, "127.0.0.2").expect("results");
+    /// Stores the rows and their rollups
+    fn store(conn: &SqliteConnection, rows: &[NewLog]) {
+        diesel::insert_into(schema::logs::table)
+            .values(rows)
+            .execute(conn)
+            .unwrap();
+        rollup::record(conn, &rows.iter().collect::<Vec<_>>()).unwrap();
+    }
+
+    /// An in-memory db holding the rows and their rollups
+    fn memory_db(rows: &[NewLog]) -> Db {
+        let conn = db::setup(":memory:").unwrap();
+        store(&conn, rows);
+        Db::single(conn)
+    }
+
+    /// Monthly shards of the returned db path holding the rows and their rollups
+    fn sharded_db(rows: &[NewLog]) -> (tempdir::TempDir, PathBuf) {
+        let dir = tempdir::TempDir::new("rrinlog-dao").unwrap();
+        let path = dir.path().join("logs.db");
+        for row in rows {
+            let shard = shard::Month::of(row.epoch).path(&path);
+            let conn = db::setup(shard.to_str().unwrap()).unwrap();
+            store(&conn, std::slice::from_ref(row));
+        }
+        (dir, path)
+    }
+
+    #[test]
+    fn test_blog_posts() {
+        let db = test_db();
+        let rng = log_hour();
+
+        let result = blog_posts(&db, &rng, "127.0.0.2").expect("results");
         assert_eq!(8, result.len());
 
         assert_eq!(
@@ -197,14 +853,10 @@ mod tests {
 
     #[test]
     fn test_sites() {
-        let conn =
-            SqliteConnection::establish("../test-assets/test-access.db").expect("To open db");
-        let rng = Range {
-            from: Utc.ymd(2017, 11, 14).and_hms(13, 0, 3),
-            to: Utc.ymd(2017, 11, 14).and_hms(14, 0, 3),
-        };
+        let db = test_db();
+        let rng = unaligned_hour();
 
-        let result = sites(&conn, &rng, Time::new::<second>(30)).expect("results");
+        let result = sites(&db, &rng, Time::new::<second>(30)).expect("results");
         assert_eq!(18, result.len());
         assert_eq!(
             Sites {
@@ -218,15 +870,11 @@ mod tests {
 
     #[test]
     fn test_outbound_data() {
-        let conn =
-            SqliteConnection::establish("../test-assets/test-access.db").expect("To open db");
-        let rng = Range {
-            from: Utc.ymd(2017, 11, 14).and_hms(13, 0, 3),
-            to: Utc.ymd(2017, 11, 14).and_hms(14, 0, 3),
-        };
+        let db = test_db();
+        let rng = unaligned_hour();
 
         let result =
-            outbound_data(&conn, &rng, "127.0.0.2", Time::new::<second>(30)).expect("results");
+            outbound_data(&db, &rng, "127.0.0.2", Time::new::<second>(30)).expect("results");
         assert_eq!(18, result.len());
         assert_eq!(
             OutboundData {
@@ -237,4 +885,285 @@ mod tests {
             result[0]
         );
     }
+
+    #[test]
+    fn test_rollups_match_raw_rows() {
+        let db = test_db();
+        let rng = log_hour();
+
+        for &secs in &[60, 5 * 60, 60 * 60] {
+            let interval = Time::new::<second>(secs);
+            let rolled = sites(&db, &rng, interval).expect("results");
+            assert!(!rolled.is_empty());
+            let raw = db.query(|conn, tables| sites_raw(conn, tables, &rng, interval));
+            assert_eq!(rolled, raw.expect("results"));
+
+            let rolled = outbound_data(&db, &rng, "127.0.0.2", interval).expect("results");
+            assert!(!rolled.is_empty());
+            let raw = db
+                .query(|conn, tables| outbound_data_raw(conn, tables, &rng, "127.0.0.2", interval));
+            assert_eq!(rolled, raw.expect("results"));
+        }
+    }
+
+    #[test]
+    fn test_rollups_match_raw_rows_unaligned() {
+        let db = test_db();
+        let ranges = [
+            unaligned_hour(),
+            Range {
+                from: Utc.ymd(2017, 11, 14).and_hms(13, 1, 3),
+                to: Utc.ymd(2017, 11, 14).and_hms(13, 1, 50),
+            },
+            Range {
+                from: Utc.ymd(2017, 11, 14).and_hms(12, 30, 30),
+                to: Utc.ymd(2017, 11, 14).and_hms(15, 10, 10),
+            },
+        ];
+
+        for rng in &ranges {
+            for &secs in &[60, 5 * 60, 60 * 60] {
+                let interval = Time::new::<second>(secs);
+                let raw = db.query(|conn, tables| sites_raw(conn, tables, rng, interval));
+                assert_eq!(sites(&db, rng, interval).unwrap(), raw.unwrap());
+
+                let raw = db.query(|conn, tables| {
+                    outbound_data_raw(conn, tables, rng, "127.0.0.2", interval)
+                });
+                assert_eq!(
+                    outbound_data(&db, rng, "127.0.0.2", interval).unwrap(),
+                    raw.unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_outbound_data_rows_without_address() {
+        let row = |addr: Option<&'static str>| NewLog {
+            body_bytes_sent: Some(10),
+            ..log(addr)
+        };
+        let db = memory_db(&[row(Some("10.0.0.1")), row(None), row(Some("127.0.0.2"))]);
+
+        let rng = log_hour();
+        let interval = Time::new::<second>(60);
+        let raw =
+            db.query(|conn, tables| outbound_data_raw(conn, tables, &rng, "127.0.0.2", interval));
+        let expected = vec![OutboundData {
+            ep: rng.from.timestamp() * 1000,
+            views: 2,
+            bytes: 20,
+        }];
+        assert_eq!(raw.unwrap(), expected);
+        assert_eq!(
+            outbound_data(&db, &rng, "127.0.0.2", interval).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_latency() {
+        let db = test_db();
+        let rng = unaligned_hour();
+
+        let result = latency(&db, &rng, Time::new::<second>(30)).expect("results");
+        assert_eq!(56, result.iter().map(|x| x.requests).sum::<i32>());
+        assert_eq!(
+            Latency {
+                ep: 1510664490000,
+                ms: 57,
+                requests: 1,
+            },
+            result[0]
+        );
+        assert!(result
+            .windows(2)
+            .all(|x| (x[0].ep, x[0].ms) < (x[1].ep, x[1].ms)));
+    }
+
+    #[test]
+    fn test_locations() {
+        let row = |addr: Option<&'static str>, place: Option<(&'static str, f64)>| NewLog {
+            country: place.map(|(country, _)| country.into()),
+            city: place
+                .filter(|&(country, _)| country == "GB")
+                .map(|_| "London".into()),
+            latitude: place.map(|(_, lat)| lat),
+            longitude: place.map(|(_, lat)| lat / 2.0),
+            ..log(addr)
+        };
+        let db = memory_db(&[
+            row(Some("81.2.69.1"), Some(("GB", 51.0))),
+            row(Some("81.2.69.2"), Some(("GB", 52.0))),
+            row(None, Some(("GB", 51.5))),
+            row(Some("127.0.0.2"), Some(("GB", 51.5))),
+            row(Some("175.16.199.1"), Some(("CN", 34.0))),
+            row(Some("10.0.0.1"), None),
+        ]);
+
+        let result = locations(&db, &log_hour(), "127.0.0.2").expect("results");
+        assert_eq!(
+            result,
+            vec![
+                Location {
+                    country: Some(String::from("GB")),
+                    city: Some(String::from("London")),
+                    latitude: 51.5,
+                    longitude: 25.75,
+                    requests: 3,
+                },
+                Location {
+                    country: Some(String::from("CN")),
+                    city: None,
+                    latitude: 34.0,
+                    longitude: 17.0,
+                    requests: 1,
+                },
+            ]
+        );
+        assert_eq!(result[0].name(), "London, GB");
+        assert_eq!(result[1].name(), "CN");
+    }
+
+    #[test]
+    fn test_top_asns() {
+        let row = |addr: &'static str, asn: Option<i64>, bytes: i32| NewLog {
+            body_bytes_sent: Some(bytes),
+            asn,
+            as_org: asn.map(|x| format!("AS{}", x).into()),
+            ..log(Some(addr))
+        };
+        let db = memory_db(&[
+            row("81.2.69.1", Some(20712), 100),
+            row("81.2.69.2", Some(20712), 50),
+            row("89.160.20.1", Some(29518), 1000),
+            row("89.160.20.2", Some(29518), 10),
+            row("2001:db8::1", Some(64496), 5000),
+            row("127.0.0.2", Some(64496), 5000),
+            row("10.0.0.1", None, 100),
+        ]);
+
+        let result = top_asns(&db, &log_hour(), "127.0.0.2").expect("results");
+        let asn = |asn: i64, requests: i32, bytes: i64| AsnTraffic {
+            asn,
+            organization: Some(format!("AS{}", asn)),
+            requests,
+            bytes,
+        };
+        assert_eq!(
+            result,
+            vec![asn(29518, 2, 1010), asn(20712, 2, 150), asn(64496, 1, 5000)]
+        );
+    }
+
+    #[test]
+    fn test_shards_match_single_db() {
+        // A row per day of 2017, so the year spans more shards than a connection can attach
+        let start = Utc.ymd(2017, 1, 1).and_hms(12, 0, 0).timestamp();
+        let rows: Vec<_> = (0..365)
+            .map(|day| NewLog {
+                epoch: start + day * 24 * 60 * 60,
+                remote_addr: Some(
+                    if day % 3 == 0 {
+                        "127.0.0.2"
+                    } else {
+                        "10.0.0.1"
+                    }
+                    .into(),
+                ),
+                method: Some("GET".into()),
+                path: Some("/".into()),
+                body_bytes_sent: Some(10),
+                referer: Some(format!("https://nbsoftsolutions.com/{}", day % 4).into()),
+                host: "comments.nbsoftsolutions.com".into(),
+                request_time: Some(day as f64 / 1000.0),
+                country: Some(if day % 2 == 0 { "GB" } else { "SE" }.into()),
+                latitude: Some(50.0 + (day % 2) as f64),
+                longitude: Some(0.0),
+                asn: Some(64500 + day % 40),
+                as_org: Some(format!("AS{}", 64500 + day % 40).into()),
+                ..NewLog::default()
+            })
+            .collect();
+        let single = memory_db(&rows);
+        let (_dir, path) = sharded_db(&rows);
+
+        let rng = Range {
+            from: Utc.ymd(2017, 1, 1).and_hms(0, 0, 0),
+            to: Utc.ymd(2018, 1, 1).and_hms(0, 0, 0),
+        };
+        let shards = Db::shards(path.to_str().unwrap(), &rng).unwrap();
+        assert!(shards.is_split());
+
+        for &secs in &[7 * 24 * 60 * 60, 7 * 24 * 60 * 60 + 1] {
+            let interval = Time::new::<second>(secs);
+            let mut expected = sites(&single, &rng, interval).unwrap();
+            expected.sort_by_key(|x| x.ep);
+            assert_eq!(sites(&shards, &rng, interval).unwrap(), expected);
+            assert_eq!(
+                outbound_data(&shards, &rng, "127.0.0.2", interval).unwrap(),
+                outbound_data(&single, &rng, "127.0.0.2", interval).unwrap()
+            );
+            assert_eq!(
+                latency(&shards, &rng, interval).unwrap(),
+                latency(&single, &rng, interval).unwrap()
+            );
+        }
+
+        let mut expected = blog_posts(&single, &rng, "127.0.0.2").unwrap();
+        expected.sort_by(|a, b| {
+            b.views
+                .cmp(&a.views)
+                .then_with(|| a.referer.cmp(&b.referer))
+        });
+        assert_eq!(blog_posts(&shards, &rng, "127.0.0.2").unwrap(), expected);
+        assert_eq!(
+            locations(&shards, &rng, "127.0.0.2").unwrap(),
+            locations(&single, &rng, "127.0.0.2").unwrap()
+        );
+
+        // More autonomous systems than are listed, so the shards are merged before truncating
+        let asns = top_asns(&shards, &rng, "127.0.0.2").unwrap();
+        assert_eq!(asns.len(), TOP_ASNS);
+        assert_eq!(asns, top_asns(&single, &rng, "127.0.0.2").unwrap());
+
+        // Only the shards that overlap the range are attached
+        let rng = Range {
+            from: Utc.ymd(2017, 3, 1).and_hms(0, 0, 0),
+            to: Utc.ymd(2017, 3, 2).and_hms(0, 0, 0),
+        };
+        let march = Db::shards(path.to_str().unwrap(), &rng).unwrap();
+        assert_eq!(march.groups[0].1.schemas, vec![String::from("s0")]);
+        assert_eq!(blog_posts(&march, &rng, "127.0.0.2").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_shards_checked_when_attached() {
+        use diesel::connection::SimpleConnection;
+
+        let (_dir, path) = sharded_db(&[]);
+        let rng = log_hour();
+        let shard = shard::Month::of(rng.from.timestamp()).path(&path);
+
+        // A shard that hasn't been set up is migrated
+        SqliteConnection::establish(shard.to_str().unwrap())
+            .unwrap()
+            .batch_execute("CREATE TABLE other (id INTEGER);")
+            .unwrap();
+        let shards = Db::shards(path.to_str().unwrap(), &rng).unwrap();
+        assert_eq!(blog_posts(&shards, &rng, "127.0.0.2").unwrap(), vec![]);
+
+        // A shard written by a newer rrinlog is refused
+        db::setup(shard.to_str().unwrap())
+            .unwrap()
+            .batch_execute(
+                "INSERT INTO __diesel_schema_migrations (version) VALUES ('99991231000000');",
+            )
+            .unwrap();
+        match Db::shards(path.to_str().unwrap(), &rng) {
+            Err(DataError::DbSetup(db::SetupError::NewerSchema(..))) => {}
+            x => panic!("expected a newer schema error: {:?}", x.map(|_| ())),
+        }
+    }
 }
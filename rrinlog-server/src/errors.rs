@@ -1,6 +1,7 @@
 use chrono::prelude::*;
 use diesel::result::ConnectionError;
 use diesel::result::Error as DsError;
+use rrinlog_core::db::SetupError;
 
 #[derive(Fail, Debug)]
 pub enum DataError {
@@ -10,6 +11,9 @@ pub enum DataError {
     #[fail(display = "Unable to execute query: {}: {}", _0, _1)]
     DbQuery(String, #[cause] DsError),
 
+    #[fail(display = "{}", _0)]
+    DbSetup(#[cause] SetupError),
+
     #[fail(display = "One target expected: {} received", _0)]
     OneTarget(usize),
 
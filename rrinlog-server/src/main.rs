@@ -34,6 +34,7 @@ use errors::DataError;
 use failure::Error;
 use itertools::Itertools;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use uom::si::i64::*;
 use uom::si::time::{millisecond, second};
@@ -53,6 +54,7 @@ macro_rules! create_app {
 struct RinState {
     pub db: String,
     pub ip: String,
+    pub monthly_shards: bool,
 }
 
 fn index() -> impl Responder {
@@ -65,18 +67,15 @@ fn search(data: Json<Search>) -> impl Responder {
         "blog_hits".to_string(),
         "sites".to_string(),
         "outbound_data".to_string(),
+        "latency".to_string(),
+        "worldmap".to_string(),
+        "top_asns".to_string(),
     ]))
 }
 
 fn query(query: Json<Query>, opt: Data<RinState>) -> Result<Json<QueryResponse>, Error> {
     debug!("Search received: {:?}", query);
 
-    // Acquire SQLite connection on each request. This can be considered inefficient, but since
-    // there isn't a roundtrip connection cost the benefit to debugging of never having a stale
-    // connection is well worth it.
-    let conn = SqliteConnection::establish(&opt.db)
-        .map_err(|e| DataError::DbConn(opt.db.to_owned(), e))?;
-
     // Grafana can technically ask for more than one target at once. It can ask for "blog_hits" and
     // "sites" in one request, but we're going to keep it simply and work with only with requests
     // that ask for one set of data.
@@ -97,22 +96,33 @@ fn query(query: Json<Query>, opt: Data<RinState>) -> Result<Json<QueryResponse>,
     // should never trust user input)
     let interval: Time = Time::new::<second>(std::cmp::max(query.interval_ms / 1000, 1));
 
+    // Acquire SQLite connection on each request. This can be considered inefficient, but since
+    // there isn't a roundtrip connection cost the benefit to debugging of never having a stale
+    // connection is well worth it. Shards are attached per request too, as the range decides
+    // which are needed.
+    let db = if opt.monthly_shards {
+        dao::Db::shards(&opt.db, &query.range)?
+    } else {
+        let conn = SqliteConnection::establish(&opt.db)
+            .map_err(|e| DataError::DbConn(opt.db.to_owned(), e))?;
+        dao::Db::single(conn)
+    };
+
     let result = match first.target.as_str() {
-        "blog_hits" => get_blog_posts(&conn, &query, &opt),
-        "sites" => get_sites(&conn, &query, interval),
-        "outbound_data" => get_outbound(&conn, &query, &opt, interval),
+        "blog_hits" => get_blog_posts(&db, &query, &opt),
+        "sites" => get_sites(&db, &query, interval),
+        "outbound_data" => get_outbound(&db, &query, &opt, interval),
+        "latency" => get_latency(&db, &query, interval),
+        "worldmap" => get_worldmap(&db, &query, &opt),
+        "top_asns" => get_top_asns(&db, &query, &opt),
         x => Err(DataError::UnrecognizedTarget(String::from(x)).into()),
     };
 
     Ok(Json(result?))
 }
 
-fn get_sites(
-    conn: &SqliteConnection,
-    data: &Query,
-    interval: Time,
-) -> Result<QueryResponse, Error> {
-    let mut rows = dao::sites(conn, &data.range, interval)
+fn get_sites(db: &dao::Db, data: &Query, interval: Time) -> Result<QueryResponse, Error> {
+    let mut rows = dao::sites(db, &data.range, interval)
         .map_err(|e| DataError::DbQuery("sites".to_string(), e))?;
 
     // Just like python, in order to group by host, we need to have the vector sorted by host. We
@@ -160,12 +170,12 @@ fn fill_datapoints(range: &Range, interval: Time, points: &[[u64; 2]]) -> Vec<[u
 }
 
 fn get_outbound(
-    conn: &SqliteConnection,
+    db: &dao::Db,
     data: &Query,
     opt: &RinState,
     interval: Time,
 ) -> Result<QueryResponse, Error> {
-    let rows = dao::outbound_data(conn, &data.range, &opt.ip, interval)
+    let rows = dao::outbound_data(db, &data.range, &opt.ip, interval)
         .map_err(|e| DataError::DbQuery("outbound data".to_string(), e))?;
 
     let p: Vec<_> = rows.iter().map(|x| [x.bytes as u64, x.ep as u64]).collect();
@@ -179,12 +189,53 @@ fn get_outbound(
     Ok(QueryResponse(vec![elem]))
 }
 
-fn get_blog_posts(
-    conn: &SqliteConnection,
-    data: &Query,
-    opt: &RinState,
-) -> Result<QueryResponse, Error> {
-    let rows = dao::blog_posts(conn, &data.range, &opt.ip)
+fn get_latency(db: &dao::Db, data: &Query, interval: Time) -> Result<QueryResponse, Error> {
+    let rows = dao::latency(db, &data.range, interval)
+        .map_err(|e| DataError::DbQuery("latency".to_string(), e))?;
+
+    // Rows arrive sorted by interval and then by request time, so each group is a histogram ready
+    // for ranking. Latencies are charted in milliseconds as datapoints are integers.
+    let mut p50 = Vec::new();
+    let mut p95 = Vec::new();
+    let mut p99 = Vec::new();
+    for (ep, points) in &rows.into_iter().group_by(|x| x.ep) {
+        let histogram: Vec<_> = points.map(|x| (x.ms as u64, x.requests as usize)).collect();
+        p50.push([percentile(&histogram, 50), ep as u64]);
+        p95.push([percentile(&histogram, 95), ep as u64]);
+        p99.push([percentile(&histogram, 99), ep as u64]);
+    }
+
+    let series = vec![("p50", p50), ("p95", p95), ("p99", p99)]
+        .into_iter()
+        .map(|(target, p)| {
+            TargetData::Series(Series {
+                target: target.to_string(),
+                datapoints: fill_datapoints(&data.range, interval, &p),
+            })
+        })
+        .collect();
+
+    Ok(QueryResponse(series))
+}
+
+/// Returns the nearest-rank percentile of the non-empty histogram of values and their counts,
+/// sorted by value
+fn percentile(histogram: &[(u64, usize)], p: usize) -> u64 {
+    let total: usize = histogram.iter().map(|&(_, count)| count).sum();
+    let rank = std::cmp::max((p * total).div_ceil(100), 1);
+    let mut seen = 0;
+    for &(value, count) in histogram {
+        seen += count;
+        if seen >= rank {
+            return value;
+        }
+    }
+
+    histogram[histogram.len() - 1].0
+}
+
+fn get_blog_posts(db: &dao::Db, data: &Query, opt: &RinState) -> Result<QueryResponse, Error> {
+    let rows = dao::blog_posts(db, &data.range, &opt.ip)
         .map_err(|e| DataError::DbQuery("blog posts".to_string(), e))?;
 
     // Grafana expects rows to contain heterogeneous values in the same order as the table columns.
@@ -213,6 +264,84 @@ fn create_blog_table(rows: Vec<Vec<serde_json::value::Value>>) -> api::Table {
     }
 }
 
+/// Formats the requests per location for the worldmap panel, whose location data is set to
+/// "table" with the latitude and longitude fields mapped to the columns of the same name
+fn get_worldmap(db: &dao::Db, data: &Query, opt: &RinState) -> Result<QueryResponse, Error> {
+    let rows = dao::locations(db, &data.range, &opt.ip)
+        .map_err(|e| DataError::DbQuery("locations".to_string(), e))?;
+
+    let r: Vec<_> = rows
+        .into_iter()
+        .map(|x| {
+            vec![
+                json!(x.name()),
+                json!(x.latitude),
+                json!(x.longitude),
+                json!(x.requests),
+            ]
+        })
+        .collect();
+
+    Ok(QueryResponse(vec![TargetData::Table(
+        create_worldmap_table(r),
+    )]))
+}
+
+fn create_worldmap_table(rows: Vec<Vec<serde_json::value::Value>>) -> api::Table {
+    let column = |text: &str, _type: &str| api::Column {
+        text: text.to_string(),
+        _type: _type.to_string(),
+    };
+
+    api::Table {
+        _type: "table".to_string(),
+        columns: vec![
+            column("name", "string"),
+            column("latitude", "number"),
+            column("longitude", "number"),
+            column("metric", "number"),
+        ],
+        rows,
+    }
+}
+
+fn get_top_asns(db: &dao::Db, data: &Query, opt: &RinState) -> Result<QueryResponse, Error> {
+    let rows = dao::top_asns(db, &data.range, &opt.ip)
+        .map_err(|e| DataError::DbQuery("top asns".to_string(), e))?;
+
+    let r: Vec<_> = rows
+        .into_iter()
+        .map(|x| {
+            vec![
+                json!(x.asn),
+                json!(x.organization),
+                json!(x.requests),
+                json!(x.bytes),
+            ]
+        })
+        .collect();
+
+    Ok(QueryResponse(vec![TargetData::Table(create_asn_table(r))]))
+}
+
+fn create_asn_table(rows: Vec<Vec<serde_json::value::Value>>) -> api::Table {
+    let column = |text: &str, _type: &str| api::Column {
+        text: text.to_string(),
+        _type: _type.to_string(),
+    };
+
+    api::Table {
+        _type: "table".to_string(),
+        columns: vec![
+            column("asn", "number"),
+            column("organization", "string"),
+            column("requests", "number"),
+            column("bytes", "number"),
+        ],
+        rows,
+    }
+}
+
 fn init_logging() -> Result<(), log::SetLoggerError> {
     Builder::from_default_env()
         .format(|buf, record| {
@@ -231,12 +360,35 @@ fn init_logging() -> Result<(), log::SetLoggerError> {
 fn main() -> std::io::Result<()> {
     init_logging().expect("Logging to initialize");
     let opts = options::Opt::from_args();
+
+    // Create or upgrade the schema once, rather than on each request. A schema newer than this
+    // server understands is refused, as its queries may no longer be correct. Shards that the
+    // ingestor creates later are checked as they are attached.
+    let dbs = if opts.monthly_shards {
+        rrinlog_core::shard::existing(Path::new(&opts.db))
+            .map(|x| x.into_iter().map(|(_, path)| path).collect())
+            .unwrap_or_else(|e| {
+                error!("Unable to list the shards of {}: {}", opts.db, e);
+                std::process::exit(1);
+            })
+    } else {
+        vec![PathBuf::from(&opts.db)]
+    };
+
+    for db in dbs {
+        if let Err(e) = rrinlog_core::db::setup(&db.to_string_lossy()) {
+            error!("{}: {}", db.display(), e);
+            std::process::exit(1);
+        }
+    }
+
     let (addr, state) = {
         (
             opts.addr,
             RinState {
                 db: opts.db,
                 ip: opts.ip,
+                monthly_shards: opts.monthly_shards,
             },
         )
     };
@@ -295,11 +447,28 @@ mod tests {
         assert_eq!([1, fill_time], actual[2]);
     }
 
+    #[test]
+    fn test_percentile() {
+        let times: Vec<_> = (1..=100).map(|x| (x, 1)).collect();
+        assert_eq!(percentile(&times, 50), 50);
+        assert_eq!(percentile(&times, 95), 95);
+        assert_eq!(percentile(&times, 99), 99);
+        assert_eq!(percentile(&[(300, 1)], 50), 300);
+        assert_eq!(percentile(&[(100, 1), (200, 1), (300, 1)], 50), 200);
+        assert_eq!(percentile(&[(100, 1), (200, 1), (300, 1)], 99), 300);
+
+        // Repeated times count once per request
+        assert_eq!(percentile(&[(10, 94), (20, 5), (900, 1)], 95), 20);
+        assert_eq!(percentile(&[(10, 94), (20, 5), (900, 1)], 99), 20);
+        assert_eq!(percentile(&[(10, 94), (20, 4), (900, 2)], 99), 900);
+    }
+
     fn create_test_server() -> actix_http_test::TestServerRuntime {
         actix_http_test::TestServer::new(|| {
             actix_http::HttpService::new(create_app!(RinState {
                 db: "../test-assets/test-access.db".to_string(),
                 ip: "127.0.0.2".to_string(),
+                monthly_shards: false,
             }))
         })
     }
@@ -333,7 +502,7 @@ mod tests {
         let bytes = srv.block_on(response.body()).unwrap();
         assert_eq!(
             str::from_utf8(&bytes).unwrap(),
-            r#"["blog_hits","sites","outbound_data"]"#
+            r#"["blog_hits","sites","outbound_data","latency","worldmap","top_asns"]"#
         );
     }
 
@@ -489,4 +658,189 @@ mod tests {
         assert!(response.status().is_success());
         assert_eq!(response.content_type(), "application/json");
     }
+
+    #[test]
+    fn test_query_latency_results() {
+        let mut srv = create_test_server();
+        let request = srv
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .send_body(
+                r#"
+{
+  "panelId": 1,
+  "range": {
+    "from": "2017-11-14T13:00:00.866Z",
+    "to": "2017-11-14T14:00:00.866Z",
+    "raw": {
+      "from": "now-1h",
+      "to": "now"
+    }
+  },
+  "rangeRaw": {
+    "from": "now-1h",
+    "to": "now"
+  },
+  "interval": "30s",
+  "intervalMs": 30000,
+  "targets": [
+     { "target": "latency", "refId": "A", "type": "timeserie" }
+  ],
+  "format": "json",
+  "maxDataPoints": 550
+}
+"#,
+            );
+
+        let mut response = srv.block_on(request).unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(response.content_type(), "application/json");
+
+        // Each of the hour's 121 intervals has a datapoint, and those that saw requests carry the
+        // percentile of the request times (in ms) stored in the test db. The 95th and 99th
+        // percentiles agree as no interval has more than a handful of requests.
+        let bytes = srv.block_on(response.body()).unwrap();
+        let data: Vec<TargetData> = serde_json::from_slice(&bytes).unwrap();
+        let p50 = [
+            83, 16, 27, 34, 71, 56, 15, 26, 11, 22, 44, 66, 14, 88, 36, 21, 58, 54,
+        ];
+        let p99 = [
+            94, 79, 90, 60, 82, 93, 78, 89, 48, 59, 81, 92, 77, 99, 47, 21, 69, 80,
+        ];
+        let eps: Vec<u64> = [
+            3, 4, 9, 20, 21, 32, 44, 51, 54, 57, 62, 67, 71, 80, 99, 101, 102, 103,
+        ]
+        .iter()
+        .map(|i| (1_510_664_400 + i * 30) * 1000)
+        .collect();
+
+        let targets = vec![("p50", &p50), ("p95", &p99), ("p99", &p99)];
+        assert_eq!(data.len(), targets.len());
+        for (series, (target, values)) in data.into_iter().zip(targets) {
+            let series = match series {
+                TargetData::Series(x) => x,
+                TargetData::Table(_) => panic!("expected a series"),
+            };
+
+            assert_eq!(series.target, target);
+            assert_eq!(series.datapoints.len(), 121);
+            let filled: Vec<_> = series
+                .datapoints
+                .into_iter()
+                .filter(|x| x[0] != 0)
+                .collect();
+            let expected: Vec<_> = values
+                .iter()
+                .zip(&eps)
+                .map(|(&value, &ep)| [value, ep])
+                .collect();
+            assert_eq!(filled, expected);
+        }
+    }
+
+    #[test]
+    fn test_query_worldmap_results() {
+        let mut srv = create_test_server();
+        let request = srv
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .send_body(
+                r#"
+{
+  "panelId": 1,
+  "range": {
+    "from": "2017-11-14T13:00:00.866Z",
+    "to": "2017-11-14T14:00:00.866Z",
+    "raw": {
+      "from": "now-1h",
+      "to": "now"
+    }
+  },
+  "rangeRaw": {
+    "from": "now-1h",
+    "to": "now"
+  },
+  "interval": "30s",
+  "intervalMs": 30000,
+  "targets": [
+     { "target": "worldmap", "refId": "A", "type": "table" }
+  ],
+  "format": "json",
+  "maxDataPoints": 550
+}
+"#,
+            );
+
+        let mut response = srv.block_on(request).unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(response.content_type(), "application/json");
+
+        // The rows of the test db were located alongside their ASNs, and the server's own requests
+        // aren't counted
+        let bytes = srv.block_on(response.body()).unwrap();
+        let data: Vec<TargetData> = serde_json::from_slice(&bytes).unwrap();
+        let expected = create_worldmap_table(vec![
+            vec![json!("Seattle, US"), json!(47.5), json!(-122.25), json!(53)],
+            vec![json!("Berlin, DE"), json!(52.5), json!(13.25), json!(27)],
+        ]);
+        assert_eq!(data, vec![TargetData::Table(expected)]);
+    }
+
+    #[test]
+    fn test_query_top_asns_results() {
+        let mut srv = create_test_server();
+        let request = srv
+            .post("/query")
+            .header(header::CONTENT_TYPE, "application/json")
+            .send_body(
+                r#"
+{
+  "panelId": 1,
+  "range": {
+    "from": "2017-11-14T13:00:00.866Z",
+    "to": "2017-11-14T14:00:00.866Z",
+    "raw": {
+      "from": "now-1h",
+      "to": "now"
+    }
+  },
+  "rangeRaw": {
+    "from": "now-1h",
+    "to": "now"
+  },
+  "interval": "30s",
+  "intervalMs": 30000,
+  "targets": [
+     { "target": "top_asns", "refId": "A", "type": "table" }
+  ],
+  "format": "json",
+  "maxDataPoints": 550
+}
+"#,
+            );
+
+        let mut response = srv.block_on(request).unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(response.content_type(), "application/json");
+
+        // The rows of the test db were given documentation ASNs, and the server's own requests
+        // aren't counted
+        let bytes = srv.block_on(response.body()).unwrap();
+        let data: Vec<TargetData> = serde_json::from_slice(&bytes).unwrap();
+        let expected = create_asn_table(vec![
+            vec![
+                json!(64496),
+                json!("Example Hosting"),
+                json!(53),
+                json!(198_771),
+            ],
+            vec![
+                json!(64511),
+                json!("Example Transit"),
+                json!(27),
+                json!(123_047),
+            ],
+        ]);
+        assert_eq!(data, vec![TargetData::Table(expected)]);
+    }
 }
@@ -18,6 +18,12 @@ pub struct Opt {
     )]
     pub db: String,
 
+    #[structopt(
+        long = "monthly-shards",
+        help = "Query the monthly shards of --db written by rrinlog --monthly-shards"
+    )]
+    pub monthly_shards: bool,
+
     #[structopt(long = "ip", help = "Local IP address to ignore from logs")]
     pub ip: String,
 }
@@ -0,0 +1,172 @@
+//! Anonymizes the visitor's address (`remote_addr`) as lines are ingested, for when full addresses
+//! can't be kept (eg: GDPR). Lines are filtered before their address is anonymized, so `ip` filter
+//! rules still match against the full address.
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Key in the metadata table of the mode that the addresses were stored with
+pub const METADATA_KEY: &str = "ip_anonymization";
+
+/// Mode recorded once rows have been stored with different modes
+pub const MIXED: &str = "mixed";
+
+/// Number of bytes of the HMAC that are kept. Visitors have to be told apart among at most the
+/// 2^128 IPv6 addresses, so a longer hash would only make the column wider.
+const HMAC_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Keep the /24 of IPv4 and the /48 of IPv6 addresses
+    Truncate,
+
+    /// Replace the address with a keyed hash, so that requests from the same address can still be
+    /// told apart from others without the address being recoverable
+    Hmac,
+
+    /// Don't store the address at all
+    Drop,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "truncate" => Ok(Mode::Truncate),
+            "hmac" => Ok(Mode::Hmac),
+            "drop" => Ok(Mode::Drop),
+            _ => Err(format!("Expected truncate, hmac, or drop: `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Mode::Truncate => "truncate",
+            Mode::Hmac => "hmac",
+            Mode::Drop => "drop",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone)]
+pub enum Anonymizer {
+    Truncate,
+    Hmac(Box<Hmac<Sha256>>),
+    Drop,
+}
+
+impl Anonymizer {
+    /// The secret is only used, and required, by `Mode::Hmac`
+    pub fn new(mode: Mode, secret: Option<&str>) -> Result<Anonymizer, String> {
+        match (mode, secret) {
+            (Mode::Truncate, _) => Ok(Anonymizer::Truncate),
+            (Mode::Drop, _) => Ok(Anonymizer::Drop),
+            (Mode::Hmac, Some(secret)) if !secret.is_empty() => {
+                Hmac::new_from_slice(secret.as_bytes())
+                    .map(|mac| Anonymizer::Hmac(Box::new(mac)))
+                    .map_err(|e| e.to_string())
+            }
+            (Mode::Hmac, _) => Err(String::from(
+                "Hashing addresses requires a secret, supplied with --anonymize-secret",
+            )),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        match *self {
+            Anonymizer::Truncate => Mode::Truncate,
+            Anonymizer::Hmac(_) => Mode::Hmac,
+            Anonymizer::Drop => Mode::Drop,
+        }
+    }
+
+    /// The address to store in place of the given one, if any. Truncating drops addresses that
+    /// aren't ip addresses, as there is no telling what part of them identifies the visitor.
+    pub fn anonymize(&self, addr: &str) -> Option<String> {
+        match *self {
+            Anonymizer::Truncate => addr.parse().ok().map(|x| truncate(x).to_string()),
+            Anonymizer::Hmac(ref mac) => {
+                // Hash the canonical form, so that `::1` and `0::1` hash the same
+                let canonical = addr
+                    .parse::<IpAddr>()
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|_| String::from(addr));
+                let mut mac = (**mac).clone();
+                mac.update(canonical.as_bytes());
+                let code = mac.finalize().into_bytes();
+                Some(
+                    code[..HMAC_LEN]
+                        .iter()
+                        .map(|x| format!("{:02x}", x))
+                        .collect(),
+                )
+            }
+            Anonymizer::Drop => None,
+        }
+    }
+}
+
+/// Zeroes all but the /24 of an IPv4 address and the /48 of an IPv6 address
+fn truncate(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 80))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode() {
+        assert_eq!("hmac".parse(), Ok(Mode::Hmac));
+        assert!("hash".parse::<Mode>().is_err());
+        assert_eq!(Mode::Truncate.to_string(), "truncate");
+    }
+
+    #[test]
+    fn test_truncate() {
+        let anonymizer = Anonymizer::new(Mode::Truncate, None).unwrap();
+        let truncated = |x| anonymizer.anonymize(x);
+        assert_eq!(
+            truncated("203.0.113.195"),
+            Some(String::from("203.0.113.0"))
+        );
+        assert_eq!(
+            truncated("2001:db8:85a3:8d3:1319:8a2e:370:7348"),
+            Some(String::from("2001:db8:85a3::"))
+        );
+        assert_eq!(truncated("unix:"), None);
+    }
+
+    #[test]
+    fn test_hmac() {
+        assert!(Anonymizer::new(Mode::Hmac, None).is_err());
+        assert!(Anonymizer::new(Mode::Hmac, Some("")).is_err());
+
+        let anonymizer = Anonymizer::new(Mode::Hmac, Some("secret")).unwrap();
+        let hashed = anonymizer.anonymize("203.0.113.195").unwrap();
+        assert_eq!(hashed.len(), HMAC_LEN * 2);
+        assert_eq!(anonymizer.anonymize("203.0.113.195"), Some(hashed.clone()));
+        assert_ne!(anonymizer.anonymize("203.0.113.196"), Some(hashed.clone()));
+        assert_eq!(anonymizer.anonymize("::1"), anonymizer.anonymize("0::1"));
+
+        // Without the secret, the addresses can't be hashed to find a match
+        let other = Anonymizer::new(Mode::Hmac, Some("other")).unwrap();
+        assert_ne!(other.anonymize("203.0.113.195"), Some(hashed));
+    }
+
+    #[test]
+    fn test_drop() {
+        let anonymizer = Anonymizer::new(Mode::Drop, None).unwrap();
+        assert_eq!(anonymizer.anonymize("203.0.113.195"), None);
+    }
+}
@@ -0,0 +1,194 @@
+//! Looks up the autonomous system (AS) that announces visitors' addresses, so that traffic can be
+//! attributed to networks (eg: a cloud provider hosting a crawler). The database given with
+//! `--asn` is either a MaxMind DB (eg: GeoLite2-ASN.mmdb) or, when it ends in `.csv`, a CSV of
+//! `network,asn,organization` rows like GeoLite2-ASN-Blocks-IPv4.csv. The longest matching network
+//! of a CSV wins.
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use rrinlog_core::filter::{self, Cidr};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutonomousSystem {
+    pub number: i64,
+    pub organization: Option<String>,
+}
+
+pub enum AsnDb {
+    MaxMind(Reader<Vec<u8>>),
+    Prefixes(Prefixes),
+}
+
+impl AsnDb {
+    pub fn open(path: &Path) -> Result<AsnDb, String> {
+        let db = if path.extension().is_some_and(|x| x == "csv") {
+            Prefixes::open(path).map(AsnDb::Prefixes)
+        } else {
+            Reader::open_readfile(path)
+                .map(AsnDb::MaxMind)
+                .map_err(|e| e.to_string())
+        };
+
+        db.map_err(|e| format!("Unable to open ASN db {}: {}", path.display(), e))
+    }
+
+    /// The autonomous system of the address. Addresses that aren't in the db, like private
+    /// addresses, have none.
+    pub fn lookup(&self, addr: &str) -> Option<AutonomousSystem> {
+        let ip: IpAddr = addr.parse().ok()?;
+        match self {
+            AsnDb::MaxMind(reader) => {
+                let asn: geoip2::Asn = match reader.lookup(ip) {
+                    Ok(asn) => asn,
+                    Err(MaxMindDBError::AddressNotFoundError(_)) => return None,
+                    Err(e) => {
+                        warn!("Unable to look up the ASN of {}: {}", addr, e);
+                        return None;
+                    }
+                };
+
+                Some(AutonomousSystem {
+                    number: i64::from(asn.autonomous_system_number?),
+                    organization: asn.autonomous_system_organization.map(String::from),
+                })
+            }
+            AsnDb::Prefixes(prefixes) => prefixes.lookup(ip).cloned(),
+        }
+    }
+}
+
+/// Autonomous systems by network. Networks are found by masking the address with each prefix
+/// length in the CSV, longest first.
+pub struct Prefixes {
+    lengths: BTreeSet<(bool, u8)>,
+    networks: HashMap<(IpAddr, u8), AutonomousSystem>,
+}
+
+impl Prefixes {
+    fn open(path: &Path) -> Result<Prefixes, String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(path)
+            .map_err(|e| e.to_string())?;
+
+        let mut prefixes = Prefixes {
+            lengths: BTreeSet::new(),
+            networks: HashMap::new(),
+        };
+
+        for (i, record) in reader.records().enumerate() {
+            let record = record.map_err(|e| e.to_string())?;
+            let network = record.get(0).unwrap_or("");
+            let cidr: Cidr = match network.parse() {
+                Ok(cidr) => cidr,
+
+                // The header of a GeoLite2 CSV
+                Err(_) if i == 0 => continue,
+                Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+            };
+
+            let number = record.get(1).unwrap_or("");
+            let number = number
+                .trim_start_matches("AS")
+                .parse()
+                .map_err(|_| format!("line {}: invalid ASN `{}`", i + 1, number))?;
+            let organization = record.get(2).filter(|x| !x.is_empty()).map(String::from);
+
+            prefixes
+                .lengths
+                .insert((cidr.network().is_ipv4(), cidr.prefix()));
+            prefixes.networks.insert(
+                (cidr.network(), cidr.prefix()),
+                AutonomousSystem {
+                    number,
+                    organization,
+                },
+            );
+        }
+
+        Ok(prefixes)
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<&AutonomousSystem> {
+        let ip = ip.to_canonical();
+        self.lengths
+            .iter()
+            .rev()
+            .filter(|&&(ipv4, _)| ipv4 == ip.is_ipv4())
+            .find_map(|&(_, prefix)| self.networks.get(&(filter::mask(ip, prefix), prefix)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+
+    fn lookup(db: &AsnDb, addr: &str) -> Option<(i64, Option<String>)> {
+        db.lookup(addr).map(|x| (x.number, x.organization))
+    }
+
+    #[test]
+    fn test_lookup_mmdb() {
+        let db = AsnDb::open(Path::new("../test-assets/GeoLite2-ASN-Test.mmdb")).unwrap();
+        assert_eq!(
+            lookup(&db, "81.2.69.160"),
+            Some((20712, Some(String::from("Andrews & Arnold Ltd"))))
+        );
+        assert_eq!(
+            lookup(&db, "2001:db8::1"),
+            Some((64496, Some(String::from("Example Networks"))))
+        );
+        assert_eq!(
+            lookup(&db, "175.16.199.1"),
+            Some((4200000000, Some(String::from("Example Private AS"))))
+        );
+        assert_eq!(lookup(&db, "10.0.0.1"), None);
+        assert_eq!(lookup(&db, "unix:"), None);
+    }
+
+    #[test]
+    fn test_lookup_csv() {
+        let db = AsnDb::open(Path::new("../test-assets/GeoLite2-ASN-Test.csv")).unwrap();
+
+        // The /24 is more specific than the /16 it is in
+        assert_eq!(
+            lookup(&db, "81.2.69.160"),
+            Some((20712, Some(String::from("Andrews & Arnold Ltd"))))
+        );
+        assert_eq!(
+            lookup(&db, "81.2.70.1"),
+            Some((64500, Some(String::from("Example Transit"))))
+        );
+        assert_eq!(
+            lookup(&db, "89.160.20.1"),
+            Some((29518, Some(String::from("Bredband2 AB"))))
+        );
+        assert_eq!(lookup(&db, "2001:db8:1::1").map(|x| x.0), Some(64496));
+        assert_eq!(lookup(&db, "175.16.199.1"), Some((4200000000, None)));
+        assert_eq!(lookup(&db, "10.0.0.1"), None);
+        assert_eq!(lookup(&db, "::ffff:81.2.69.160").map(|x| x.0), Some(20712));
+    }
+
+    #[test]
+    fn test_open_invalid() {
+        assert!(AsnDb::open(Path::new("missing.mmdb")).is_err());
+        assert!(AsnDb::open(Path::new("missing.csv")).is_err());
+
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let path = dir.path().join("asn.csv");
+        ::std::fs::write(
+            &path,
+            "network,asn\n10.0.0.0/8,AS64500\n10.0.0.0/33,64501\n",
+        )
+        .unwrap();
+        let err = AsnDb::open(&path).err().unwrap();
+        assert!(err.ends_with(
+            "line 3: Expected an ip address or CIDR block (eg: 10.0.0.0/8): `10.0.0.0/33`"
+        ));
+    }
+}
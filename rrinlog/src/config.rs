@@ -0,0 +1,413 @@
+//! Reads the ingestor's settings from a TOML file given with `--config`. Every flag has a
+//! counterpart in the file, and flags given on the command line take precedence over the file.
+//!
+//! ```toml
+//! db = "/var/lib/rrinlog/logs.db"
+//! monthly-shards = true
+//! dedup = true
+//! retention = "90days"
+//!
+//! [input]
+//! follow = ["/var/log/nginx/access.log"]
+//!
+//! [parser]
+//! format = "combined"
+//! host = "example.com"
+//!
+//! [filter]
+//! ips = ["127.0.0.1"]
+//! rules = ["deny path ^/health$"]
+//!
+//! [anonymize]
+//! ip = "hmac"
+//! secret = "..."
+//!
+//! [enrich]
+//! geoip = "/var/lib/GeoIP/GeoLite2-City.mmdb"
+//! asn = "/var/lib/GeoIP/GeoLite2-ASN.mmdb"
+//!
+//! [flush]
+//! buffer = 100
+//! interval = "5s"
+//! ```
+
+use anonymize;
+use humantime;
+use options::Opt;
+use rrinlog_core::filter::{Address, Rule};
+use rrinlog_core::format::LogFormat;
+use serde::de::{self, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::clap::ArgMatches;
+use toml;
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum ConfigError {
+    #[fail(display = "Unable to read config {}: {}", _0, _1)]
+    Read(String, String),
+
+    /// The message names the offending key (eg: "... for key `flush.interval` at line 1 column 1").
+    /// toml only knows the position of the table the key is in.
+    #[fail(display = "Invalid config {}: {}", _0, _1)]
+    Invalid(String, String),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    db: Option<String>,
+    monthly_shards: Option<bool>,
+    dedup: Option<bool>,
+
+    #[serde(default, deserialize_with = "duration")]
+    retention: Option<Duration>,
+
+    #[serde(default)]
+    input: Input,
+
+    #[serde(default)]
+    parser: Parser,
+
+    #[serde(default)]
+    filter: Filter,
+
+    #[serde(default)]
+    anonymize: Anonymize,
+
+    #[serde(default)]
+    enrich: Enrich,
+
+    #[serde(default)]
+    flush: Flush,
+}
+
+/// Where lines are read from. Without any, lines are read from stdin.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Input {
+    #[serde(default)]
+    follow: Vec<PathBuf>,
+    syslog_udp: Option<String>,
+    syslog_tcp: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Parser {
+    #[serde(default, deserialize_with = "from_str")]
+    format: Option<LogFormat>,
+    host: Option<String>,
+    json: Option<bool>,
+
+    /// Maps JSON keys to the variable they contain (eg: `ua = "http_user_agent"`)
+    #[serde(default)]
+    json_keys: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Filter {
+    #[serde(default, deserialize_with = "from_str_vec")]
+    ips: Vec<Address>,
+
+    #[serde(default, deserialize_with = "from_str_vec")]
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Anonymize {
+    #[serde(default, deserialize_with = "from_str")]
+    ip: Option<anonymize::Mode>,
+    secret: Option<String>,
+}
+
+/// Databases that rows are enriched from
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Enrich {
+    geoip: Option<PathBuf>,
+    asn: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Flush {
+    buffer: Option<usize>,
+
+    #[serde(default, deserialize_with = "duration")]
+    interval: Option<Duration>,
+}
+
+/// Values are validated as they are deserialized, so that errors carry the key they belong to
+fn from_str<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: FromStr,
+    T::Err: Display,
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(de::Error::custom)
+}
+
+fn from_str_vec<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    T: FromStr,
+    T::Err: Display,
+    D: Deserializer<'de>,
+{
+    let values = Vec::<String>::deserialize(deserializer)?;
+    values
+        .iter()
+        .map(|x| x.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+fn duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value)
+        .map(Some)
+        .map_err(|e| de::Error::custom(format!("{} (eg: \"5s\")", e)))
+}
+
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let name = path.display().to_string();
+    let data =
+        fs::read_to_string(path).map_err(|e| ConfigError::Read(name.clone(), e.to_string()))?;
+    parse(&data).map_err(|e| ConfigError::Invalid(name, e))
+}
+
+fn parse(data: &str) -> Result<Config, String> {
+    let config: Config = toml::from_str(data).map_err(|e| e.to_string())?;
+    let input = &config.input;
+    if !input.follow.is_empty() && (input.syslog_udp.is_some() || input.syslog_tcp.is_some()) {
+        return Err(String::from(
+            "`input.follow` can't be combined with `input.syslog-udp` or `input.syslog-tcp`",
+        ));
+    }
+
+    Ok(config)
+}
+
+/// Overwrites the option with the value from the file unless the flag was given
+fn set<T>(field: &mut T, value: Option<T>, given: bool) {
+    if let (Some(value), false) = (value, given) {
+        *field = value;
+    }
+}
+
+impl Config {
+    /// Fills in the options that weren't given on the command line
+    pub fn apply(self, opt: &mut Opt, matches: &ArgMatches) {
+        let given = |name: &str| matches.occurrences_of(name) > 0;
+        set(&mut opt.db, self.db, given("db"));
+        set(
+            &mut opt.monthly_shards,
+            self.monthly_shards,
+            given("monthly-shards"),
+        );
+        set(&mut opt.dedup, self.dedup, given("dedup"));
+        set(
+            &mut opt.retention,
+            self.retention.map(Some),
+            given("retention"),
+        );
+
+        // Input sources are taken as a whole, so that following a file on the command line
+        // doesn't also listen on the socket in the file. Subcommands and dry runs read their own
+        // input.
+        let input_given = opt.cmd.is_some()
+            || opt.dry_run
+            || given("follow")
+            || given("syslog-udp")
+            || given("syslog-tcp");
+        if !input_given {
+            opt.follow = self.input.follow;
+            opt.syslog_udp = self.input.syslog_udp;
+            opt.syslog_tcp = self.input.syslog_tcp;
+        }
+
+        let parser = self.parser;
+        set(&mut opt.format, parser.format, given("format"));
+        set(&mut opt.host, parser.host.map(Some), given("host"));
+        set(&mut opt.json, parser.json, given("json"));
+        let json_keys: Vec<String> = parser
+            .json_keys
+            .into_iter()
+            .map(|(key, variable)| format!("{}={}", key, variable))
+            .collect();
+        set(
+            &mut opt.json_keys,
+            Some(json_keys).filter(|x| !x.is_empty()),
+            given("json-keys"),
+        );
+
+        let filter = self.filter;
+        set(
+            &mut opt.filter_ips,
+            Some(filter.ips).filter(|x| !x.is_empty()),
+            given("filter-ips"),
+        );
+        set(
+            &mut opt.filters,
+            Some(filter.rules).filter(|x| !x.is_empty()),
+            given("filters"),
+        );
+
+        set(
+            &mut opt.anonymize_ip,
+            self.anonymize.ip.map(Some),
+            given("anonymize-ip"),
+        );
+        set(
+            &mut opt.anonymize_secret,
+            self.anonymize.secret.map(Some),
+            given("anonymize-secret"),
+        );
+        set(&mut opt.geoip, self.enrich.geoip.map(Some), given("geoip"));
+        set(&mut opt.asn, self.enrich.asn.map(Some), given("asn"));
+
+        set(&mut opt.buffer, self.flush.buffer, given("buffer"));
+        set(
+            &mut opt.flush_interval,
+            self.flush.interval.map(Some),
+            given("flush-interval"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn opt_with(args: &[&str], config: &str) -> Opt {
+        let matches = Opt::clap().get_matches_from(args);
+        let mut opt = Opt::from_clap(&matches);
+        parse(config).unwrap().apply(&mut opt, &matches);
+        opt
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let config = r#"
+            db = "/var/lib/rrinlog/logs.db"
+            monthly-shards = true
+            dedup = true
+            retention = "90days"
+
+            [input]
+            follow = ["/var/log/nginx/access.log"]
+
+            [parser]
+            format = "apache_common"
+            host = "example.com"
+            json-keys = { ua = "http_user_agent" }
+
+            [filter]
+            ips = ["10.0.0.0/8"]
+            rules = ["deny path ^/health$"]
+
+            [anonymize]
+            ip = "hmac"
+            secret = "hunter2"
+
+            [enrich]
+            geoip = "/var/lib/GeoIP/GeoLite2-City.mmdb"
+            asn = "/var/lib/GeoIP/GeoLite2-ASN.mmdb"
+
+            [flush]
+            buffer = 100
+            interval = "5s"
+        "#;
+
+        let opt = opt_with(&["rrinlog"], config);
+        assert_eq!(opt.db, "/var/lib/rrinlog/logs.db");
+        assert!(opt.monthly_shards);
+        assert!(opt.dedup);
+        assert_eq!(opt.retention, Some(Duration::from_secs(90 * 24 * 60 * 60)));
+        assert_eq!(opt.follow, vec![PathBuf::from("/var/log/nginx/access.log")]);
+        assert_eq!(opt.format, LogFormat::preset("apache_common").unwrap());
+        assert_eq!(opt.host, Some(String::from("example.com")));
+        assert_eq!(opt.json_keys, vec![String::from("ua=http_user_agent")]);
+        assert_eq!(opt.filter_ips, vec!["10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(opt.filters[0].to_string(), "deny path ^/health$");
+        assert_eq!(opt.anonymize_ip, Some(anonymize::Mode::Hmac));
+        assert_eq!(opt.anonymize_secret, Some(String::from("hunter2")));
+        assert_eq!(
+            opt.geoip,
+            Some(PathBuf::from("/var/lib/GeoIP/GeoLite2-City.mmdb"))
+        );
+        assert_eq!(
+            opt.asn,
+            Some(PathBuf::from("/var/lib/GeoIP/GeoLite2-ASN.mmdb"))
+        );
+        assert_eq!(opt.buffer, 100);
+        assert_eq!(opt.flush_interval, Some(Duration::from_secs(5)));
+
+        // Flags take precedence, even when they are set to the default
+        let opt = opt_with(
+            &[
+                "rrinlog",
+                "--buffer",
+                "10",
+                "--host",
+                "a.com",
+                "--syslog-udp",
+                "127.0.0.1:5140",
+            ],
+            config,
+        );
+        assert_eq!(opt.db, "/var/lib/rrinlog/logs.db");
+        assert_eq!(opt.buffer, 10);
+        assert_eq!(opt.host, Some(String::from("a.com")));
+        assert_eq!(opt.syslog_udp, Some(String::from("127.0.0.1:5140")));
+        assert!(opt.follow.is_empty());
+
+        // Without a config, the defaults are untouched
+        let opt = opt_with(&["rrinlog"], "");
+        assert_eq!(opt.db, "logs.db");
+        assert_eq!(opt.buffer, 10);
+        assert_eq!(opt.format, LogFormat::preset("vhost").unwrap());
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let err = |config: &str| parse(config).unwrap_err();
+
+        assert!(err("[flush]\nbufer = 1").contains("unknown field `bufer`"));
+        assert!(err("[flush]\nbuffer = \"1\"").contains("for key `flush.buffer`"));
+
+        let msg = err("[flush]\ninterval = \"5 parsecs\"");
+        assert!(msg.contains("(eg: \"5s\") for key `flush.interval`"));
+
+        let msg = err("[filter]\nrules = [\"deny referer x\"]");
+        assert!(msg.contains("found `referer` for key `filter.rules`"));
+
+        let msg = err("[filter]\nips = [\"10.0.0.0/33\"]");
+        assert!(msg.contains("for key `filter.ips`"));
+
+        let msg = err("[parser]\nformat = \"$remote_addr $host\"");
+        assert!(msg.contains("`$time_local`, `$time_iso8601`, or `$msec` for key `parser.format`"));
+
+        let msg = err("[input]\nfollow = [\"a.log\"]\nsyslog-udp = \"127.0.0.1:5140\"");
+        assert!(msg.contains("`input.follow` can't be combined"));
+    }
+
+    #[test]
+    fn test_load_missing_config() {
+        let err = load(Path::new("/nonexistent/rrinlog.toml")).unwrap_err();
+        match err {
+            ConfigError::Read(path, _) => assert_eq!(path, "/nonexistent/rrinlog.toml"),
+            _ => panic!("expected a read error"),
+        }
+    }
+}
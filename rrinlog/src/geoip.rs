@@ -0,0 +1,120 @@
+//! Looks up where visitors are from in a local MaxMind DB (eg: GeoLite2-City.mmdb) given with
+//! `--geoip`, so that rows are enriched with a location as they are inserted without calling out
+//! to a service. Country databases work too, but only fill in the country and its coordinates.
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Language of the names that are stored
+const LANGUAGE: &str = "en";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Location {
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp, String> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| format!("Unable to open GeoIP db {}: {}", path.display(), e))?;
+        debug!(
+            "Looking up locations in {} ({})",
+            path.display(),
+            reader.metadata.database_type
+        );
+        Ok(GeoIp { reader })
+    }
+
+    /// The location of the address. Addresses that aren't in the db, like private addresses, have
+    /// none.
+    pub fn lookup(&self, addr: &str) -> Option<Location> {
+        let ip: IpAddr = addr.parse().ok()?;
+        let city: geoip2::City = match self.reader.lookup(ip) {
+            Ok(city) => city,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return None,
+            Err(e) => {
+                warn!("Unable to look up the location of {}: {}", addr, e);
+                return None;
+            }
+        };
+
+        let location = city.location.as_ref();
+        Some(Location {
+            country: city
+                .country
+                .as_ref()
+                .and_then(|x| x.iso_code)
+                .map(String::from),
+            region: city
+                .subdivisions
+                .as_ref()
+                .and_then(|x| x.first())
+                .and_then(|x| name(&x.names)),
+            city: city.city.as_ref().and_then(|x| name(&x.names)),
+            latitude: location.and_then(|x| x.latitude),
+            longitude: location.and_then(|x| x.longitude),
+        })
+    }
+}
+
+fn name(names: &Option<BTreeMap<&str, &str>>) -> Option<String> {
+    names
+        .as_ref()
+        .and_then(|x| x.get(LANGUAGE))
+        .map(|x| String::from(*x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> GeoIp {
+        GeoIp::open(Path::new("../test-assets/GeoLite2-City-Test.mmdb")).unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let geoip = fixture();
+        assert_eq!(
+            geoip.lookup("81.2.69.160"),
+            Some(Location {
+                country: Some(String::from("GB")),
+                region: Some(String::from("England")),
+                city: Some(String::from("London")),
+                latitude: Some(51.5142),
+                longitude: Some(-0.0931),
+            })
+        );
+
+        let location = geoip.lookup("2001:db8::1").unwrap();
+        assert_eq!(location.city, Some(String::from("Boxford")));
+
+        let location = geoip.lookup("175.16.199.1").unwrap();
+        assert_eq!(location.country, Some(String::from("CN")));
+        assert_eq!(location.city, None);
+        assert_eq!(location.latitude, Some(34.7725));
+    }
+
+    #[test]
+    fn test_lookup_unknown() {
+        let geoip = fixture();
+        assert_eq!(geoip.lookup("10.0.0.1"), None);
+        assert_eq!(geoip.lookup("81.2.70.1"), None);
+        assert_eq!(geoip.lookup("unix:"), None);
+    }
+
+    #[test]
+    fn test_open_missing() {
+        assert!(GeoIp::open(Path::new("missing.mmdb")).is_err());
+    }
+}
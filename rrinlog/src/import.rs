@@ -0,0 +1,107 @@
+//! Opens the files given to `rrinlog import`. Patterns are expanded like a shell would, so
+//! `/var/log/nginx/access.log.*.gz` can be quoted to avoid the shell's argument limit, and files
+//! ending in `.gz` are decompressed as they are read.
+
+use flate2::read::MultiGzDecoder;
+use glob::glob;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Expands each glob pattern into the files it matches. A pattern that matches nothing is an
+/// error, as it's most likely a typo.
+pub fn expand_patterns(patterns: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let matches = glob(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+        let len = paths.len();
+        for entry in matches {
+            match entry {
+                Ok(path) => paths.push(path),
+                Err(e) => error!("Unable to read {}: {}", e.path().display(), e.error()),
+            }
+        }
+
+        if paths.len() == len {
+            return Err(format!("No files match {}", pattern));
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Opens the log for reading, decompressing it if it is gzipped. logrotate writes a single gzip
+/// member, but concatenated members are read too.
+pub fn open_log(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if path.extension() == Some(OsStr::new("gz")) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn test_open_gzipped_log() {
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let plain = dir.path().join("access.log.1");
+        File::create(&plain).unwrap().write_all(b"a\nb\n").unwrap();
+
+        let gzipped = dir.path().join("access.log.2.gz");
+        let mut encoder = GzEncoder::new(File::create(&gzipped).unwrap(), Compression::default());
+        encoder.write_all(b"c\nd\n").unwrap();
+        encoder.finish().unwrap();
+
+        let lines: Vec<String> = open_log(&plain)
+            .unwrap()
+            .lines()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(lines, vec!["a", "b"]);
+
+        let lines: Vec<String> = open_log(&gzipped)
+            .unwrap()
+            .lines()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(lines, vec!["c", "d"]);
+    }
+
+    #[test]
+    fn test_expand_patterns() {
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        for name in &["access.log", "access.log.1", "access.log.2.gz", "error.log"] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+
+        let dir_str = dir.path().to_str().unwrap();
+        let patterns = vec![
+            format!("{}/access.log.*", dir_str),
+            format!("{}/error.log", dir_str),
+        ];
+        assert_eq!(
+            expand_patterns(&patterns),
+            Ok(vec![
+                dir.path().join("access.log.1"),
+                dir.path().join("access.log.2.gz"),
+                dir.path().join("error.log"),
+            ])
+        );
+
+        let missing = vec![format!("{}/other.log*", dir_str)];
+        assert_eq!(
+            expand_patterns(&missing),
+            Err(format!("No files match {}/other.log*", dir_str))
+        );
+    }
+}
@@ -1,36 +1,222 @@
 #![recursion_limit = "128"]
 
 extern crate chrono;
+extern crate csv;
+#[macro_use]
 extern crate diesel;
 extern crate env_logger;
+#[macro_use]
 extern crate failure;
+extern crate flate2;
+extern crate glob;
+extern crate hmac;
+extern crate humantime;
 #[macro_use]
 extern crate log;
+extern crate maxminddb;
 extern crate rrinlog_core;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate sha2;
 #[macro_use]
 extern crate structopt;
+extern crate toml;
 
 use chrono::prelude::*;
 use diesel::prelude::*;
 use env_logger::{Builder, Target};
-use rrinlog_core::models::NewLog;
-use rrinlog_core::parser;
-use std::collections::HashSet;
+use rrinlog_core::filter::{Filter, Matcher, Rule};
+use rrinlog_core::format::LogFormat;
+use rrinlog_core::json::JsonFormat;
+use rrinlog_core::models::{Checkpoint, NewLog, NewRejectedLine, RejectedLine};
+use rrinlog_core::parser::{LogParser, ParseError};
+use rrinlog_core::rollup;
+use rrinlog_core::syslog::SyslogParser;
+use rrinlog_core::vhost::VhostParser;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
+use std::ops::AddAssign;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use store::Store;
 use structopt::StructOpt;
 
+mod anonymize;
+mod asn;
+mod config;
+mod geoip;
+mod import;
 mod options;
+mod prune;
+mod store;
+mod syslog;
+mod tail;
 
 fn main() {
     init_logging().expect("Logging to initialize");
 
-    let opt = options::Opt::from_args();
-    let ips: HashSet<String> = opt.filter_ips.into_iter().collect();
-    if opt.dry_run {
-        dry_run();
+    let matches = options::Opt::clap().get_matches();
+    let mut opt = options::Opt::from_clap(&matches);
+    if let Some(path) = opt.config.clone() {
+        config::load(&path)
+            .unwrap_or_else(|e| exit_with(&e.to_string()))
+            .apply(&mut opt, &matches);
+    }
+
+    let rules = opt
+        .filter_ips
+        .iter()
+        .cloned()
+        .map(|x| Rule::deny(Matcher::Ip(x)));
+    let filter = Filter::new(rules.chain(opt.filters.iter().cloned()).collect());
+    let anonymizer = opt.anonymize_ip.map(|mode| {
+        anonymize::Anonymizer::new(mode, opt.anonymize_secret.as_deref())
+            .unwrap_or_else(|e| exit_with(&e))
+    });
+    let geoip = opt
+        .geoip
+        .as_ref()
+        .map(|path| geoip::GeoIp::open(path).unwrap_or_else(|e| exit_with(&e)));
+    let asn = opt
+        .asn
+        .as_ref()
+        .map(|path| asn::AsnDb::open(path).unwrap_or_else(|e| exit_with(&e)));
+    let mut parser = create_parser(opt.format, opt.json, &opt.json_keys, opt.host.as_deref())
+        .unwrap_or_else(|e| exit_with(&e));
+    let listen = opt.syslog_udp.is_some() || opt.syslog_tcp.is_some();
+    if listen {
+        parser = Box::new(SyslogParser::new(parser));
+    }
+    if !parser.has_host() {
+        exit_with("Log format does not contain the host, so it must be supplied with --host");
+    }
+
+    debug!("Parsing lines formatted as: {}", parser.describe());
+    let pipeline = Pipeline {
+        parser: parser.as_ref(),
+        filter: &filter,
+        dedup: opt.dedup,
+        pruner: opt.retention.map(prune::Pruner::new),
+        anonymizer,
+        geoip,
+        asn,
+        recorded: RefCell::default(),
+    };
+
+    match opt.cmd {
+        Some(options::Command::Import { ref files }) => {
+            if !opt.follow.is_empty() {
+                exit_with("Files can't be followed while importing");
+            }
+
+            let paths = import::expand_patterns(files).unwrap_or_else(|e| exit_with(&e));
+            if opt.dry_run {
+                for path in &paths {
+                    match import::open_log(path) {
+                        Ok(reader) => dry_run(parser.as_ref(), reader),
+                        Err(e) => error!("Unable to open {}: {}", path.display(), e),
+                    }
+                }
+            } else {
+                import_logs(&pipeline, &open_store(&opt.db, opt.monthly_shards), &paths);
+            }
+        }
+        Some(options::Command::Backfill) => {
+            if opt.dry_run {
+                exit_with("Rollups can't be backfilled in a dry run");
+            }
+
+            backfill(&open_store(&opt.db, opt.monthly_shards));
+        }
+        Some(options::Command::Prune { dry_run }) => match opt.retention {
+            Some(retention) => prune_now(
+                &open_store(&opt.db, opt.monthly_shards),
+                retention,
+                dry_run || opt.dry_run,
+            ),
+            None => exit_with("The retention must be supplied with --retention to prune"),
+        },
+        Some(options::Command::Reprocess) => {
+            if opt.dry_run {
+                exit_with("Rejected lines can't be reprocessed in a dry run");
+            }
+
+            reprocess(&pipeline, &open_store(&opt.db, opt.monthly_shards));
+        }
+        None if opt.dry_run => dry_run(parser.as_ref(), io::stdin().lock()),
+        None if listen => listen_syslog(
+            &pipeline,
+            opt.buffer,
+            &open_store(&opt.db, opt.monthly_shards),
+            opt.syslog_udp.as_deref(),
+            opt.syslog_tcp.as_deref(),
+            opt.flush_interval.unwrap_or(SYSLOG_FLUSH_INTERVAL),
+        ),
+        None if !opt.follow.is_empty() => follow_logs(
+            &pipeline,
+            opt.buffer,
+            &open_store(&opt.db, opt.monthly_shards),
+            &opt.follow,
+        ),
+        None => match opt.flush_interval {
+            Some(interval) => persist_logs_with_interval(
+                &pipeline,
+                opt.buffer,
+                &open_store(&opt.db, opt.monthly_shards),
+                interval,
+            ),
+            None => persist_logs(
+                &pipeline,
+                opt.buffer,
+                &open_store(&opt.db, opt.monthly_shards),
+            ),
+        },
+    }
+}
+
+fn exit_with(msg: &str) -> ! {
+    error!("{}", msg);
+    std::process::exit(1);
+}
+
+/// Selects the parser for the format given on the command line
+fn create_parser(
+    format: LogFormat,
+    json: bool,
+    json_keys: &[String],
+    host: Option<&str>,
+) -> Result<Box<dyn LogParser>, String> {
+    // A host given for a format that logs its own would never be stored
+    let logged_host = "The log format contains the host, so --host can't be supplied";
+    if json {
+        let json_format = json_keys
+            .iter()
+            .try_fold(JsonFormat::default(), |acc, x| acc.map_key_str(x))
+            .map_err(|e| e.to_string())?;
+        match host {
+            Some(_) if json_format.has_host() => Err(String::from(logged_host)),
+            Some(host) => Ok(Box::new(json_format.with_default_host(host))),
+            None => Ok(Box::new(json_format)),
+        }
     } else {
-        persist_logs(opt.buffer, &opt.db, &ips);
+        match host {
+            Some(_) if format.has_host() => Err(String::from(logged_host)),
+            Some(host) => Ok(Box::new(format.with_default_host(host))),
+
+            // The default format has a dedicated parser that is much faster than a compiled one
+            None if LogFormat::preset("vhost").as_ref() == Some(&format) => {
+                Ok(Box::new(VhostParser::default()))
+            }
+            None => Ok(Box::new(format)),
+        }
     }
 }
 
@@ -49,44 +235,302 @@ fn init_logging() -> Result<(), log::SetLoggerError> {
         .try_init()
 }
 
-fn persist_logs(threshold: usize, db: &str, ips: &HashSet<String>) {
-    let conn =
-        SqliteConnection::establish(db).unwrap_or_else(|_| panic!("Error connecting to {}", db));
+/// How lines are turned into rows, whether they are read from stdin, files, or sockets
+struct Pipeline<'a> {
+    parser: &'a dyn LogParser,
+
+    /// Decides which of the parsed lines are stored
+    filter: &'a Filter,
+
+    /// Fingerprint the lines so that lines that have already been inserted are skipped
+    dedup: bool,
+
+    /// Deletes rows that are older than the retention every so often
+    pruner: Option<prune::Pruner>,
+
+    /// Replaces the address of the rows that are stored
+    anonymizer: Option<anonymize::Anonymizer>,
+
+    /// Looks up the location of the rows that are stored
+    geoip: Option<geoip::GeoIp>,
+
+    /// Looks up the autonomous system of the rows that are stored
+    asn: Option<asn::AsnDb>,
+
+    /// The dbs whose anonymization mode has been recorded
+    recorded: RefCell<HashSet<store::Key>>,
+}
+
+/// Tally of what happened to the lines that were read
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct LineCounts {
+    parsed: usize,
+    failed: usize,
+    filtered: usize,
+    duplicates: usize,
+}
+
+impl fmt::Display for LineCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} parsed, {} failed, {} filtered, {} duplicates",
+            self.parsed, self.failed, self.filtered, self.duplicates
+        )
+    }
+}
+
+impl AddAssign for LineCounts {
+    fn add_assign(&mut self, other: LineCounts) {
+        self.parsed += other.parsed;
+        self.failed += other.failed;
+        self.filtered += other.filtered;
+        self.duplicates += other.duplicates;
+    }
+}
+
+/// Connects to the db, or its shards, creating or upgrading their schema first
+fn open_store(db: &str, monthly: bool) -> Store {
+    Store::open(db, monthly).unwrap_or_else(|e| exit_with(&e))
+}
+
+fn persist_logs(pipeline: &Pipeline, threshold: usize, store: &Store) {
+    ingest(io::stdin().lock(), store, pipeline, threshold);
+}
+
+/// Same as `persist_logs`, but a partially filled buffer is inserted once its oldest line has
+/// waited for the interval. stdin is read on another thread, so that the buffer can be flushed
+/// while waiting for the next line.
+fn persist_logs_with_interval(
+    pipeline: &Pipeline,
+    threshold: usize,
+    store: &Store,
+    interval: Duration,
+) {
+    let (tx, rx) = mpsc::sync_channel(CHANNEL_CAPACITY);
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut locked_stdin = stdin.lock();
+        loop {
+            let mut line = Vec::new();
+            if !read_line(&mut locked_stdin, &mut line) || tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    insert_received(rx, store, pipeline, threshold, interval);
+}
+
+/// Number of lines inserted per transaction when importing. Unlike stdin, there is no one waiting
+/// on the lines to show up, so larger batches are used to make the import faster.
+const IMPORT_BUFFER: usize = 1000;
+
+fn import_logs(pipeline: &Pipeline, store: &Store, paths: &[PathBuf]) {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    for path in paths {
+        let counts = match import::open_log(path) {
+            Ok(reader) => ingest(reader, store, pipeline, IMPORT_BUFFER),
+            Err(e) => {
+                error!("Unable to open {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        let _ = writeln!(&mut handle, "{}: {}", path.display(), counts);
+    }
+}
 
+/// Reads lines until the reader is exhausted, inserting them in batches of `threshold` lines
+fn ingest<R: BufRead>(
+    mut reader: R,
+    store: &Store,
+    pipeline: &Pipeline,
+    threshold: usize,
+) -> LineCounts {
     // To avoid allocating a string for each line read from stdin and to buffer data so that we
     // batch insert into the db, we keep around the same `n` strings for the whole duration of the
     // application. Since these strings are kept around forever, they will grow to the maximum url
     // size allowed by nginx, which defaults to 1k. So if the buffer size is 10, these strings will
-    // contribute a max of 10k to mem usage.
-    let mut buffer: Vec<String> = vec![String::new(); threshold];
+    // contribute a max of 10k to mem usage. Lines are read as bytes, as a line that isn't valid
+    // UTF-8 (eg: a raw binary request) would otherwise fail the read.
+    let mut buffer: Vec<Vec<u8>> = vec![Vec::new(); threshold];
     let mut buf_ind = 0;
-    let stdin = io::stdin();
-    let mut locked_stdin = stdin.lock();
-    while locked_stdin.read_line(&mut buffer[buf_ind]).unwrap_or(0) > 0 {
+    let mut counts = LineCounts::default();
+    while read_line(&mut reader, &mut buffer[buf_ind]) {
         buf_ind += 1;
         if buf_ind >= threshold {
-            insert_buffer(&conn, &buffer, ips);
+            counts += insert_buffer(store, pipeline, &buffer, None);
             buf_ind = 0;
 
             // Remove the parsed lines, but keep the allocated space for them
-            buffer.iter_mut().for_each(String::clear);
+            buffer.iter_mut().for_each(Vec::clear);
         }
     }
 
     // Flush anything else that exists in the buffer
     if buf_ind > 0 {
-        insert_buffer(&conn, &buffer[..buf_ind], ips)
+        counts += insert_buffer(store, pipeline, &buffer[..buf_ind], None);
+    }
+
+    counts
+}
+
+/// Number of received messages that can be queued while the db is busy before listeners block
+const CHANNEL_CAPACITY: usize = 10_000;
+
+/// Maximum time a received message waits in the buffer before it is inserted, unless overridden
+/// by `--flush-interval`
+const SYSLOG_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn listen_syslog(
+    pipeline: &Pipeline,
+    threshold: usize,
+    store: &Store,
+    udp: Option<&str>,
+    tcp: Option<&str>,
+    interval: Duration,
+) {
+    let (tx, rx) = mpsc::sync_channel(CHANNEL_CAPACITY);
+    if let Some(addr) = udp {
+        syslog::listen_udp(addr, tx.clone())
+            .unwrap_or_else(|e| exit_with(&format!("Unable to listen on {}: {}", addr, e)));
+    }
+
+    if let Some(addr) = tcp {
+        syslog::listen_tcp(addr, tx.clone())
+            .unwrap_or_else(|e| exit_with(&format!("Unable to listen on {}: {}", addr, e)));
+    }
+
+    drop(tx);
+    insert_received(rx, store, pipeline, threshold, interval);
+}
+
+/// Inserts lines received from other threads once `threshold` lines are buffered or the oldest
+/// line has waited for `flush_interval`, whichever comes first
+fn insert_received(
+    rx: Receiver<Vec<u8>>,
+    store: &Store,
+    pipeline: &Pipeline,
+    threshold: usize,
+    flush_interval: Duration,
+) -> LineCounts {
+    let mut buffer = Vec::with_capacity(threshold);
+    let mut counts = LineCounts::default();
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let received = match deadline {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(line) => {
+                if buffer.is_empty() {
+                    deadline = Some(Instant::now() + flush_interval);
+                }
+
+                buffer.push(line);
+                if buffer.len() < threshold {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        counts += insert_buffer(store, pipeline, &buffer, None);
+        buffer.clear();
+        deadline = None;
+    }
+
+    if !buffer.is_empty() {
+        counts += insert_buffer(store, pipeline, &buffer, None);
+    }
+
+    counts
+}
+
+/// How long to wait before checking followed files for new lines once they have been caught up
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+fn follow_logs(pipeline: &Pipeline, threshold: usize, store: &Store, paths: &[PathBuf]) {
+    let mut tailers: Vec<tail::Tailer> = paths
+        .iter()
+        .map(|path| {
+            store
+                .load_checkpoint(path)
+                .and_then(|cp| tail::Tailer::open(path, cp.as_ref()).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    exit_with(&format!("Unable to follow {}: {}", path.display(), e))
+                })
+        })
+        .collect();
+
+    loop {
+        let mut idle = true;
+        for tailer in &mut tailers {
+            // The checkpoint is stored in the same transaction as the lines of each db, so a
+            // crash can neither lose nor duplicate lines
+            let res = match tailer.read_lines(threshold) {
+                Ok(ref lines) if lines.is_empty() => tailer.check_rotation(),
+                Ok(lines) => {
+                    let checkpoint = tailer.checkpoint();
+                    match try_insert_buffer(store, pipeline, &lines, Some(&checkpoint)) {
+                        Ok(_) => {
+                            idle = false;
+                            Ok(())
+                        }
+
+                        // Without the checkpoint stored, the lines are read again on the next
+                        // poll rather than skipped
+                        Err(_) => tailer.rewind(),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(ref e) = res {
+                error!("Unable to follow {}: {}", tailer.checkpoint().path, e);
+            }
+        }
+
+        if idle {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Reads a line into the buffer, returning false once there is nothing left to read
+fn read_line<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>) -> bool {
+    match reader.read_until(b'\n', buf) {
+        Ok(read) => read > 0,
+        Err(ref e) => {
+            error!("Unable to read line: {}", e);
+            false
+        }
     }
 }
 
-fn dry_run() {
+/// Parses a line that was decoded with `String::from_utf8_lossy`. Rows are marked when their line
+/// wasn't valid UTF-8 so that they can be told apart from lines that contain U+FFFD.
+fn parse_line<'a>(
+    parser: &'a dyn LogParser,
+    line: &'a Cow<'a, str>,
+) -> Result<NewLog<'a>, ParseError> {
+    let mut log = parser.parse(line.trim())?;
+    log.invalid_utf8 = matches!(*line, Cow::Owned(_));
+    Ok(log)
+}
+
+fn dry_run<R: BufRead>(parser: &dyn LogParser, mut reader: R) {
     let stdout = io::stdout();
     let mut handle = stdout.lock();
-    let stdin = io::stdin();
-    let mut line = String::new();
-    let mut locked_stdin = stdin.lock();
-    while locked_stdin.read_line(&mut line).unwrap_or(0) > 0 {
-        match parser::parse_nginx_line(line.trim()) {
+    let mut buf = Vec::new();
+    while read_line(&mut reader, &mut buf) {
+        let line = String::from_utf8_lossy(&buf);
+        match parse_line(parser, &line) {
             // Both Ok and Err branches halt writing if the line can't be ouput.
             // For instance, this occurs when rrinlog output is piped to head
             Ok(log) => {
@@ -95,64 +539,603 @@ fn dry_run() {
                 }
             }
             Err(ref e) => {
-                if writeln!(&mut handle, "error: {}", e).is_err() {
+                let res = match e.excerpt() {
+                    Some(excerpt) => writeln!(&mut handle, "error: {}\n{}", e, excerpt),
+                    None => writeln!(&mut handle, "error: {}", e),
+                };
+
+                if res.is_err() {
                     break;
                 }
             }
         }
 
-        line.clear();
+        buf.clear();
     }
 }
 
-/// If SQLite transaction successfully acquired, `insert_buffer` will drain the provided buffer of
-/// log lines even if the line can't be parsed or inserted.
-fn insert_buffer<T: AsRef<str>>(conn: &SqliteConnection, buffer: &[T], ips: &HashSet<String>) {
+impl<'a> Pipeline<'a> {
+    /// Parses the line decoded from the raw bytes, fingerprinting the raw bytes in dedup mode.
+    /// A hash of the line would give away the address it contains, so the address is anonymized
+    /// in the bytes that are fingerprinted.
+    fn parse<'b>(&'b self, raw: &[u8], line: &'b Cow<'b, str>) -> Result<NewLog<'b>, ParseError> {
+        let mut log = parse_line(self.parser, line)?;
+        if self.dedup {
+            let raw = trim_newline(raw);
+            log.fingerprint = Some(match (&self.anonymizer, &log.remote_addr) {
+                (Some(anonymizer), Some(addr)) => {
+                    let anonymized = anonymizer.anonymize(addr).unwrap_or_default();
+                    fingerprint(&replace_first(raw, addr.as_bytes(), anonymized.as_bytes()))
+                }
+                _ => fingerprint(raw),
+            });
+        }
+        Ok(log)
+    }
+
+    fn is_filtered(&self, log: &NewLog) -> bool {
+        self.filter.is_denied(log)
+    }
+
+    /// Readies a row that passed the filter for insertion: its location and autonomous system are
+    /// looked up while the full address is at hand, and then the address is anonymized
+    fn prepare(&self, log: &mut NewLog) {
+        if let Some(ref geoip) = self.geoip {
+            let location = log.remote_addr.as_ref().and_then(|x| geoip.lookup(x));
+            if let Some(location) = location {
+                log.country = location.country.map(Cow::Owned);
+                log.region = location.region.map(Cow::Owned);
+                log.city = location.city.map(Cow::Owned);
+                log.latitude = location.latitude;
+                log.longitude = location.longitude;
+            }
+        }
+
+        if let Some(ref asn) = self.asn {
+            if let Some(system) = log.remote_addr.as_ref().and_then(|x| asn.lookup(x)) {
+                log.asn = Some(system.number);
+                log.as_org = system.organization.map(Cow::Owned);
+            }
+        }
+
+        if let Some(ref anonymizer) = self.anonymizer {
+            log.remote_addr = log
+                .remote_addr
+                .as_ref()
+                .and_then(|x| anonymizer.anonymize(x))
+                .map(Cow::Owned);
+        }
+    }
+
+    /// Inserts the rows and adds them to the rollups, returning the number inserted. In dedup
+    /// mode, rows with a fingerprint that already exists are skipped. They are inserted one at a
+    /// time so that the skipped rows are left out of the rollups.
+    fn insert(&self, conn: &SqliteConnection, lines: &[NewLog]) -> QueryResult<usize> {
+        use rrinlog_core::schema::logs;
+
+        let inserted: Vec<&NewLog> = if lines.is_empty() {
+            Vec::new()
+        } else if self.dedup {
+            let mut inserted = Vec::with_capacity(lines.len());
+            for line in lines {
+                if diesel::insert_or_ignore_into(logs::table)
+                    .values(line)
+                    .execute(conn)?
+                    > 0
+                {
+                    inserted.push(line);
+                }
+            }
+            inserted
+        } else {
+            diesel::insert_into(logs::table)
+                .values(lines)
+                .execute(conn)?;
+            lines.iter().collect()
+        };
+
+        rollup::record(conn, &inserted)?;
+        Ok(inserted.len())
+    }
+
+    /// Records how the addresses of the rows about to be inserted are stored, so that every db
+    /// (and shard) that holds rows says how their addresses were stored. This is done ahead of the
+    /// transaction that inserts the rows, and as the mode can't change while running, only once
+    /// per db.
+    fn record_mode_once(&self, conn: &SqliteConnection, key: store::Key) -> QueryResult<()> {
+        if self.recorded.borrow().contains(&key) {
+            return Ok(());
+        }
+
+        let mode = self.anonymizer.as_ref().map(|x| x.mode().to_string());
+        record_mode(conn, mode.as_deref().unwrap_or("none"))?;
+        self.recorded.borrow_mut().insert(key);
+        Ok(())
+    }
+}
+
+/// Records the anonymization mode of the rows about to be inserted. A db whose rows were stored
+/// with another mode (rows stored before modes were recorded had none) is marked as mixed, as
+/// its addresses can no longer be treated alike.
+fn record_mode(conn: &SqliteConnection, mode: &str) -> QueryResult<()> {
+    use rrinlog_core::db;
     use rrinlog_core::schema::logs;
 
+    let stored = match db::metadata(conn, anonymize::METADATA_KEY)? {
+        Some(stored) => Some(stored),
+        None => diesel::select(diesel::dsl::exists(logs::table.select(logs::epoch)))
+            .get_result::<bool>(conn)?
+            .then(|| String::from("none")),
+    };
+
+    let recorded = match stored.as_deref() {
+        None => mode,
+        Some(stored) if stored == mode || stored == anonymize::MIXED => stored,
+        Some(stored) => {
+            warn!(
+                "Addresses were stored with an ip anonymization of {} but are now stored with {}, so the db is marked as {}",
+                stored,
+                mode,
+                anonymize::MIXED
+            );
+            anonymize::MIXED
+        }
+    };
+
+    if stored.as_deref() == Some(recorded) {
+        Ok(())
+    } else {
+        db::set_metadata(conn, anonymize::METADATA_KEY, recorded)
+    }
+}
+
+/// Number of bytes of the line's SHA-256 hash that are kept. 128 bits keeps the chance of two
+/// different lines colliding negligible while halving the size of the index.
+const FINGERPRINT_LEN: usize = 16;
+
+fn fingerprint(line: &[u8]) -> Vec<u8> {
+    Sha256::digest(line)[..FINGERPRINT_LEN].to_vec()
+}
+
+/// Replaces the first occurrence of `from` in the bytes
+fn replace_first<'a>(bytes: &'a [u8], from: &[u8], to: &[u8]) -> Cow<'a, [u8]> {
+    if from.is_empty() {
+        return Cow::Borrowed(bytes);
+    }
+
+    match bytes.windows(from.len()).position(|x| x == from) {
+        Some(ind) => Cow::Owned([&bytes[..ind], to, &bytes[ind + from.len()..]].concat()),
+        None => Cow::Borrowed(bytes),
+    }
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let len = line
+        .iter()
+        .rposition(|&b| b != b'\n' && b != b'\r')
+        .map_or(0, |x| x + 1);
+    &line[..len]
+}
+
+fn backfill(store: &Store) {
+    let mut buckets = 0;
+    for key in store.keys().unwrap_or_else(|e| exit_with(&e)) {
+        match store
+            .conn(key)
+            .and_then(|conn| rollup::backfill(&conn).map_err(|e| e.to_string()))
+        {
+            Ok(written) => buckets += written,
+            Err(e) => exit_with(&format!(
+                "Unable to backfill rollups of {}: {}",
+                store.path(key),
+                e
+            )),
+        }
+    }
+
+    println!("Wrote {} rollup buckets", buckets);
+}
+
+fn prune_now(store: &Store, retention: Duration, dry_run: bool) {
+    let cutoff = prune::cutoff(retention, Utc::now());
+    let cutoff_str = cutoff.to_rfc3339();
+    if dry_run {
+        let shards = prune::expired_shards(store, cutoff).unwrap_or_else(|e| exit_with(&e));
+        for month in shards {
+            println!("{} would be deleted", store.path(Some(month)));
+        }
+
+        match prune::expired_rows(store, cutoff) {
+            Ok(prune::Expired {
+                rows,
+                oldest: Some(oldest),
+            }) => println!(
+                "{} rows from {} until {} would be deleted",
+                rows,
+                Utc.timestamp(oldest, 0).to_rfc3339(),
+                cutoff_str
+            ),
+            Ok(_) => println!("No rows older than {} to delete", cutoff_str),
+            Err(e) => exit_with(&format!("Unable to count rows to prune: {}", e)),
+        }
+    } else {
+        match prune::prune_store(store, cutoff) {
+            Ok((removed, rows)) => {
+                for path in removed {
+                    println!("Deleted {}", path);
+                }
+                println!("Deleted {} rows older than {}", rows, cutoff_str);
+            }
+            Err(e) => exit_with(&format!("Unable to prune rows: {}", e)),
+        }
+    }
+}
+
+fn reprocess(pipeline: &Pipeline, store: &Store) {
+    let mut counts = LineCounts::default();
+    for key in store.keys().unwrap_or_else(|e| exit_with(&e)) {
+        match reprocess_rejected(store, key, pipeline) {
+            Ok(shard_counts) => counts += shard_counts,
+            Err(e) => exit_with(&format!(
+                "Unable to reprocess rejected lines of {}: {}",
+                store.path(key),
+                e
+            )),
+        }
+    }
+
+    println!("{}", counts);
+}
+
+/// Number of rejected lines parsed per transaction when reprocessing
+const REPROCESS_BATCH: i64 = 1000;
+
+/// Parses the rejected lines of the db again, moving the lines that now parse into the logs table.
+/// Lines that still fail have their error updated.
+fn reprocess_rejected(
+    store: &Store,
+    key: store::Key,
+    pipeline: &Pipeline,
+) -> Result<LineCounts, String> {
+    use rrinlog_core::schema::rejected_lines;
+
+    let conn = store.conn(key)?;
+    let conn = &*conn;
+    let mut counts = LineCounts::default();
+    let mut last_id = 0;
+    loop {
+        let rejected: Vec<RejectedLine> = rejected_lines::table
+            .filter(rejected_lines::id.gt(last_id))
+            .order(rejected_lines::id)
+            .limit(REPROCESS_BATCH)
+            .load(conn)
+            .map_err(|e| e.to_string())?;
+        last_id = match rejected.last() {
+            Some(row) => row.id,
+            None => return Ok(counts),
+        };
+
+        let decoded: Vec<Cow<str>> = rejected
+            .iter()
+            .map(|row| String::from_utf8_lossy(&row.line))
+            .collect();
+
+        let mut lines = Vec::new();
+        let mut resolved = Vec::new();
+        let mut errors = Vec::new();
+        for (row, line) in rejected.iter().zip(decoded.iter()) {
+            match pipeline.parse(&row.line, line) {
+                Ok(ref log) if pipeline.is_filtered(log) => {
+                    counts.filtered += 1;
+                    resolved.push(row.id);
+                }
+                Ok(mut log) => {
+                    resolved.push(row.id);
+                    pipeline.prepare(&mut log);
+                    lines.push(log);
+                }
+                Err(e) => {
+                    counts.failed += 1;
+                    errors.push((row.id, e.to_string()));
+                }
+            }
+        }
+
+        // A line rejected at the turn of a month may belong to the shard of the previous month.
+        // Those are inserted before the rejected lines are removed, so a failure leaves them to be
+        // reprocessed again.
+        let (lines, others): (Vec<NewLog>, Vec<NewLog>) =
+            lines.into_iter().partition(|x| store.key(x.epoch) == key);
+        let mut groups: BTreeMap<store::Key, Vec<NewLog>> = BTreeMap::new();
+        for line in others {
+            groups.entry(store.key(line.epoch)).or_default().push(line);
+        }
+        for (other, lines) in groups {
+            let other_conn = store.conn(other)?;
+            let inserted = pipeline
+                .record_mode_once(&other_conn, other)
+                .and_then(|_| other_conn.transaction(|| pipeline.insert(&other_conn, &lines)))
+                .map_err(|e| format!("{}: {}", store.path(other), e))?;
+            counts.parsed += inserted;
+            counts.duplicates += lines.len() - inserted;
+        }
+
+        pipeline
+            .record_mode_once(conn, key)
+            .map_err(|e| e.to_string())?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let inserted = pipeline.insert(conn, &lines)?;
+            counts.parsed += inserted;
+            counts.duplicates += lines.len() - inserted;
+            diesel::delete(rejected_lines::table.filter(rejected_lines::id.eq_any(&resolved)))
+                .execute(conn)?;
+            for (id, error) in &errors {
+                diesel::update(rejected_lines::table.find(id))
+                    .set(rejected_lines::error.eq(error))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+        .map_err(|e| e.to_string())?;
+    }
+}
+
+/// If SQLite transaction successfully acquired, `insert_buffer` will drain the provided buffer of
+/// log lines even if the line can't be parsed or inserted. Lines that can't be parsed are stored
+/// in `rejected_lines` and the checkpoint of a followed file is stored alongside the lines. Lines
+/// that fail to be inserted are counted as failed.
+fn insert_buffer<T: AsRef<[u8]>>(
+    store: &Store,
+    pipeline: &Pipeline,
+    buffer: &[T],
+    checkpoint: Option<&Checkpoint>,
+) -> LineCounts {
+    try_insert_buffer(store, pipeline, buffer, checkpoint).unwrap_or_else(|counts| counts)
+}
+
+/// Same as `insert_buffer`, but the counts are an error when lines failed to be inserted, in which
+/// case the checkpoint wasn't stored in the home db. Lines of the batch that other dbs stored are
+/// skipped when it is inserted again.
+fn try_insert_buffer<T: AsRef<[u8]>>(
+    store: &Store,
+    pipeline: &Pipeline,
+    buffer: &[T],
+    checkpoint: Option<&Checkpoint>,
+) -> Result<LineCounts, LineCounts> {
+    use rrinlog_core::schema::{checkpoints, rejected_lines};
+
     let start = Utc::now();
     let init_len = buffer.len();
 
-    let lines: Vec<NewLog> = buffer
+    let decoded: Vec<Cow<str>> = buffer
         .iter()
-        .map(|line| line.as_ref().trim())
-        .map(|line| parser::parse_nginx_line(line))
-        .inspect(|line| {
-            // If we can't parse a line, yeah that sucks but it's bound to happen so discard
-            // the line after it's logged for the attentive sysadmin
-            if let Err(ref e) = *line {
-                error!("Parsing error: {}", e);
-            }
+        .map(|line| String::from_utf8_lossy(line.as_ref()))
+        .collect();
+
+    // Where each line ends in the followed file, so that lines stored by an earlier attempt at the
+    // batch can be told apart
+    let ends = line_ends(buffer, checkpoint);
+
+    let mut rejected = Vec::new();
+    let lines: Vec<(i64, NewLog)> = buffer
+        .iter()
+        .zip(decoded.iter())
+        .zip(ends.iter())
+        .filter_map(
+            |((raw, line), &end)| match pipeline.parse(raw.as_ref(), line) {
+                Ok(log) => Some((end, log)),
+                Err(e) => {
+                    // If we can't parse a line, yeah that sucks but it's bound to happen so set the
+                    // line aside after it's logged for the attentive sysadmin, who can reprocess it
+                    // once the format is fixed
+                    error!("Parsing error: {}", e);
+
+                    // The address of a line that can't be parsed can't be anonymized, so the line
+                    // isn't kept when it should be
+                    if pipeline.anonymizer.is_none() {
+                        rejected.push(NewRejectedLine {
+                            epoch: start.timestamp(),
+                            line: trim_newline(raw.as_ref()),
+                            error: e.to_string(),
+                        });
+                    }
+                    None
+                }
+            },
+        )
+        .collect();
+    let parsed_len = lines.len();
+
+    if pipeline.anonymizer.is_some() && parsed_len < init_len {
+        warn!(
+            "{} lines that failed to parse were dropped rather than kept in rejected_lines, as their addresses can't be anonymized",
+            init_len - parsed_len
+        );
+    }
+
+    // Filter out lines denied by the rules, which see the address before it is anonymized
+    let lines: Vec<(i64, NewLog)> = lines
+        .into_iter()
+        .filter(|x| !pipeline.is_filtered(&x.1))
+        .map(|(end, mut x)| {
+            pipeline.prepare(&mut x);
+            (end, x)
         })
-        .filter_map(Result::ok)
-        // Filter out black listed ips
-        .filter(|x| x.remote_addr.map(|s| !ips.contains(s)).unwrap_or(true))
         .collect();
 
+    let mut counts = LineCounts {
+        parsed: lines.len(),
+        failed: init_len - parsed_len,
+        filtered: parsed_len - lines.len(),
+        duplicates: 0,
+    };
+
+    // Rows go to the db of their epoch. Rejected lines go to the home db, which is written last,
+    // and every db stores the checkpoint in the same transaction as its rows. A followed file is
+    // resumed from the checkpoint in the home db, so it isn't moved past lines that failed to be
+    // inserted into another db, while the checkpoints of the other dbs keep their lines from being
+    // inserted twice when the batch is read again.
+    let newest = lines.iter().map(|x| x.1.epoch).max();
+    let home = store.home(newest.unwrap_or_else(|| start.timestamp()));
+    let mut groups: BTreeMap<store::Key, Group> = BTreeMap::new();
+    for (end, line) in lines {
+        let group = groups.entry(store.key(line.epoch)).or_default();
+        group.ends.push(end);
+        group.lines.push(line);
+    }
+    let home_lines = groups.remove(&home).unwrap_or_default();
+    let mut groups: Vec<(store::Key, Group)> = groups.into_iter().collect();
+
+    // The home db is where the file is resumed from, so it needs a checkpoint before another db
+    // stores one past it
+    let mut anchored = true;
+    if let (false, Some(checkpoint)) = (groups.is_empty(), checkpoint) {
+        let read = buffer.iter().map(|x| x.as_ref().len() as i64).sum::<i64>();
+        let from = Checkpoint {
+            byte_offset: checkpoint.byte_offset - read,
+            ..checkpoint.clone()
+        };
+        let res = store.conn(home).and_then(|conn| {
+            diesel::insert_or_ignore_into(checkpoints::table)
+                .values(&from)
+                .execute(&*conn)
+                .map_err(|e| e.to_string())
+        });
+        if let Err(ref e) = res {
+            error!(
+                "Unable to store the checkpoint in {}: {}",
+                store.path(home),
+                e
+            );
+            anchored = false;
+        }
+    }
+
     // Now that we have all the successfully parsed logs, insert them into the db. If no lines need
     // to be inserted, skip needlessly locking the db
-    if !lines.is_empty() {
-        let db_res = diesel::insert_into(logs::table)
-            .values(&lines)
-            .execute(conn);
+    if !home_lines.lines.is_empty() || !rejected.is_empty() || checkpoint.is_some() {
+        groups.push((home, home_lines));
+    }
+
+    let mut failed = false;
+    for (key, Group { ends, lines }) in groups {
+        let is_home = key == home;
+        let db_res = if !anchored || (failed && is_home) {
+            Err(String::from(
+                "lines before the checkpoint failed to be inserted",
+            ))
+        } else {
+            store.conn(key).and_then(|conn| {
+                pipeline
+                    .record_mode_once(&conn, key)
+                    .map_err(|e| e.to_string())?;
+                conn.transaction::<_, diesel::result::Error, _>(|| {
+                    let stored = match checkpoint {
+                        Some(checkpoint) => stored_lines(&conn, checkpoint, &ends)?,
+                        None => 0,
+                    };
+                    let inserted = pipeline.insert(&conn, &lines[stored..])?;
+
+                    if is_home && !rejected.is_empty() {
+                        diesel::insert_into(rejected_lines::table)
+                            .values(&rejected)
+                            .execute(&*conn)?;
+                    }
+
+                    if let Some(checkpoint) = checkpoint {
+                        diesel::replace_into(checkpoints::table)
+                            .values(checkpoint)
+                            .execute(&*conn)?;
+                    }
+
+                    Ok(inserted)
+                })
+                .map_err(|e| e.to_string())
+            })
+        };
 
         // If inserting into the db fails, log the error, but still discard the messages, so we
         // remain light on memory usage. Never panic as we're supposed to be a long lived
         // application
-        if let Err(ref e) = db_res {
-            error!("Insertion error: {}", e);
-            return;
+        match db_res {
+            Ok(inserted) => counts.duplicates += lines.len() - inserted,
+            Err(ref e) => {
+                error!("Insertion error into {}: {}", store.path(key), e);
+                counts.failed += lines.len();
+                counts.parsed -= lines.len();
+                failed = true;
+            }
         }
     }
 
+    counts.parsed -= counts.duplicates;
+    if failed {
+        return Err(counts);
+    }
+
     let end = Utc::now();
     let dur = end.signed_duration_since(start);
     info!(
-        "Parsing and inserting {} out of {} records took {}us",
-        lines.len(),
+        "Parsing and inserting {} out of {} records ({} duplicates skipped) took {}us",
+        counts.parsed,
         init_len,
+        counts.duplicates,
         dur.num_microseconds().unwrap()
     );
+
+    if let Some(ref pruner) = pipeline.pruner {
+        pruner.maybe_prune(store);
+    }
+
+    Ok(counts)
+}
+
+/// Rows bound for a db, along with where their lines end in the followed file
+#[derive(Default)]
+struct Group<'a> {
+    ends: Vec<i64>,
+    lines: Vec<NewLog<'a>>,
+}
+
+/// The offset that each line ends at in the followed file, or zeros when lines aren't followed
+fn line_ends<T: AsRef<[u8]>>(buffer: &[T], checkpoint: Option<&Checkpoint>) -> Vec<i64> {
+    let mut end = checkpoint.map_or(0, |x| x.byte_offset);
+    let mut ends: Vec<i64> = buffer
+        .iter()
+        .rev()
+        .map(|line| {
+            let line_end = end;
+            if checkpoint.is_some() {
+                end -= line.as_ref().len() as i64;
+            }
+            line_end
+        })
+        .collect();
+    ends.reverse();
+    ends
+}
+
+/// Number of the db's lines (given by where they end) that were stored by an earlier attempt at
+/// the batch, which left the db with a checkpoint within the batch
+fn stored_lines(
+    conn: &SqliteConnection,
+    checkpoint: &Checkpoint,
+    ends: &[i64],
+) -> QueryResult<usize> {
+    use rrinlog_core::schema::checkpoints;
+
+    let stored: Option<Checkpoint> = checkpoints::table
+        .find(&checkpoint.path)
+        .first(conn)
+        .optional()?;
+    let offset = match stored {
+        Some(ref x) if x.inode == checkpoint.inode && x.byte_offset <= checkpoint.byte_offset => {
+            x.byte_offset
+        }
+        _ => return Ok(0),
+    };
+
+    Ok(ends.iter().take_while(|&&end| end <= offset).count())
 }
 
 #[cfg(test)]
@@ -161,8 +1144,22 @@ mod tests {
     extern crate environment;
     extern crate tempdir;
 
-    use std::env;
-    use std::path::PathBuf;
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    /// Stores every line as it was parsed
+    fn test_pipeline<'a>(parser: &'a dyn LogParser, filter: &'a Filter) -> Pipeline<'a> {
+        Pipeline {
+            parser,
+            filter,
+            dedup: false,
+            pruner: None,
+            anonymizer: None,
+            geoip: None,
+            asn: None,
+            recorded: RefCell::default(),
+        }
+    }
 
     #[test]
     fn test_dry_run_empty_input() {
@@ -188,50 +1185,911 @@ mod tests {
     }
 
     #[test]
-    fn run_db_test() {
-        let tmp_dir = tempdir::TempDir::new("rrinlog").unwrap();
-        let tmp_path = tmp_dir.path().join("logs.db");
-        let tmp = tmp_path.to_str().unwrap();
-        let migration_dir = PathBuf::from(r"../migrations");
-        let migration = migration_dir.to_str().unwrap();
-        println!("Current dir: {:?}", env::current_dir());
-        assert_cli::Assert::command(&["diesel"])
-            .with_args(&["setup", "--migration-dir", migration, "--database-url", tmp])
+    fn test_dry_run_error_excerpt() {
+        let line =
+            r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/2.0" 200 20480 "-" "-""#;
+        assert_cli::Assert::main_binary()
+            .with_args(&["--dry-run"])
+            .stdin(line)
             .succeeds()
+            .stdout()
+            .is(concat!(
+                "error: Unable to parse $http_user_agent at byte 75: expected `\" \"` after the value\n",
+                r#"...ET / HTTP/2.0" 200 20480 "-" "-""#,
+                "\n",
+                "                                 ^"
+            ))
             .unwrap();
+    }
+
+    #[test]
+    fn test_dry_run_invalid_utf8() {
+        let mut input = Vec::new();
+        input.extend_from_slice(
+            br#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "bad"#,
+        );
+        input.push(0xff);
+        input.extend_from_slice(br#"" "a.com""#);
+        input.extend_from_slice(b"\nCats are alright");
+        assert_cli::Assert::main_binary()
+            .with_args(&["--dry-run"])
+            .stdin(input)
+            .succeeds()
+            .stdout()
+            .contains("line: 1509818735 127.0.0.1 - 200 GET / 1.1 0 - bad\u{fffd} a.com")
+            .stdout()
+            .contains("error: ")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parse_line_marks_invalid_utf8() {
+        let parser = VhostParser::default();
+        let line =
+            br#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#;
+        let decoded = String::from_utf8_lossy(line);
+        assert!(!parse_line(&parser, &decoded).unwrap().invalid_utf8);
+
+        let mut line = line.to_vec();
+        line.insert(71, 0xc3);
+        let decoded = String::from_utf8_lossy(&line);
+        let log = parse_line(&parser, &decoded).unwrap();
+        assert!(log.invalid_utf8);
+        assert_eq!(log.referer, Some("-".into()));
+        assert_eq!(log.user_agent, Some("\u{fffd}-".into()));
+    }
+
+    #[test]
+    fn test_dry_run_custom_format() {
+        let line = r#"10.0.0.1 [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 304 example.com"#;
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "--dry-run",
+                "--format",
+                r#"$remote_addr [$time_local] "$request" $status $host"#,
+            ])
+            .stdin(line)
+            .succeeds()
+            .stdout()
+            .is("line: 1509818735 10.0.0.1 NA 304 GET / 1.1 0 NA NA example.com")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_dry_run_apache_with_host() {
+        let line = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#;
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "--dry-run",
+                "--format",
+                "apache_common",
+                "--host",
+                "example.com",
+            ])
+            .stdin(line)
+            .succeeds()
+            .stdout()
+            .is("line: 971211336 127.0.0.1 frank 200 GET /apache_pb.gif 1.0 2326 NA NA example.com")
+            .unwrap();
+
+        assert_cli::Assert::main_binary()
+            .with_args(&["--dry-run", "--format", "apache_common"])
+            .stdin(line)
+            .fails()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_follow_conflicts_with_dry_run() {
+        assert_cli::Assert::main_binary()
+            .with_args(&["--follow", "access.log", "--dry-run"])
+            .fails()
+            .stderr()
+            .contains("cannot be used with")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_syslog_conflicts_with_dry_run() {
+        for listen in &["--syslog-udp", "--syslog-tcp"] {
+            assert_cli::Assert::main_binary()
+                .with_args(&[listen, "127.0.0.1:5140", "--dry-run"])
+                .fails()
+                .stderr()
+                .contains("cannot be used with")
+                .unwrap();
+
+            assert_cli::Assert::main_binary()
+                .with_args(&[listen, "127.0.0.1:5140", "--follow", "access.log"])
+                .fails()
+                .stderr()
+                .contains("cannot be used with")
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_host_with_logged_host() {
+        for args in &[&["--dry-run"][..], &["--dry-run", "--json"][..]] {
+            assert_cli::Assert::main_binary()
+                .with_args(args)
+                .with_args(&["--host", "example.com"])
+                .stdin("")
+                .fails()
+                .stdout()
+                .contains("The log format contains the host, so --host can't be supplied")
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_dry_run_json() {
+        let line = r#"{"t":"04/Nov/2017:13:05:35 -0500","request":"GET /a\"b HTTP/1.1","status":200,"host":"example.com"}"#;
+        assert_cli::Assert::main_binary()
+            .with_args(&["--dry-run", "--json", "--json-key", "t=time_local"])
+            .stdin(line)
+            .succeeds()
+            .stdout()
+            .is(r#"line: 1509818735 NA NA 200 GET /a"b 1.1 0 NA NA example.com"#)
+            .unwrap();
+    }
+
+    /// A db in a temporary directory that the binary is run against
+    struct TestDb {
+        dir: tempdir::TempDir,
+        path: PathBuf,
+    }
+
+    impl TestDb {
+        /// Points at a db that doesn't exist yet
+        fn missing() -> TestDb {
+            let dir = tempdir::TempDir::new("rrinlog").unwrap();
+            let path = dir.path().join("logs.db");
+            TestDb { dir, path }
+        }
+
+        /// Creates a db with the latest schema
+        fn new() -> TestDb {
+            let test = TestDb::missing();
+            rrinlog_core::db::setup(test.db()).unwrap();
+            test
+        }
+
+        fn db(&self) -> &str {
+            self.path.to_str().unwrap()
+        }
+
+        fn conn(&self) -> SqliteConnection {
+            SqliteConnection::establish(self.db()).unwrap()
+        }
+
+        /// Runs the binary against the db
+        fn run(&self, args: &[&str]) -> assert_cli::Assert {
+            assert_cli::Assert::main_binary()
+                .with_args(&["--db", self.db()])
+                .with_args(args)
+        }
+
+        /// Runs the binary against the db with the input on stdin, which must succeed
+        fn ingest(&self, args: &[&str], input: &str) {
+            self.run(args).stdin(input).succeeds().unwrap();
+        }
+
+        fn rows(&self) -> i64 {
+            rrinlog_core::schema::logs::table
+                .count()
+                .get_result(&self.conn())
+                .unwrap()
+        }
+
+        fn rejected(&self) -> i64 {
+            rrinlog_core::schema::rejected_lines::table
+                .count()
+                .get_result(&self.conn())
+                .unwrap()
+        }
+    }
+
+    /// A line in the default format from the address
+    fn access_line(addr: &str) -> String {
+        format!(
+            r#"{} - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#,
+            addr
+        )
+    }
+
+    #[test]
+    fn run_db_test() {
+        // The db doesn't exist yet, so it is created on startup
+        let test = TestDb::missing();
 
         let fail_line = "Cats are alright";
         let success_line = r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET /js/embed.min.js HTTP/2.0" 200 20480 "https://nbsoftsolutions.com/blog/monitoring-windows-system-metrics-with-grafana" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/61.0.3163.100 Safari/537.36" "comments.nbsoftsolutions.com""#;
         let skip_line = r#"127.0.0.2 - - [04/Nov/2017:13:05:35 -0500] "GET /js/embed.min.js HTTP/2.0" 200 20480 "https://nbsoftsolutions.com/blog/monitoring-windows-system-metrics-with-grafana" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/61.0.3163.100 Safari/537.36" "comments.nbsoftsolutions.com""#;
+        test.run(&[
+            "--buffer",
+            "1",
+            "--filter-ip",
+            "127.0.0.2",
+            "--filter-ip",
+            "127.0.0.3",
+        ])
+        .with_env(environment::Environment::inherit().insert("RUST_LOG", "INFO"))
+        .stdin(format!("{}\n{}\n{}", fail_line, success_line, skip_line))
+        .succeeds()
+        .stdout()
+        .satisfies(|out| out.lines().count() == 4, "4 lines")
+        .stdout()
+        .contains("Unable to parse $remote_addr at byte 0: expected ` - ` after the value")
+        .stdout()
+        .satisfies(
+            |out| {
+                let lines: Vec<&str> = out.lines().skip(1).collect();
+                lines.len() == 3
+                    && lines[0].contains("inserting 0 out of 1 records")
+                    && lines[1].contains("inserting 1 out of 1 records")
+                    && lines[2].contains("inserting 0 out of 1 records")
+            },
+            "correct lines",
+        )
+        .unwrap();
+    }
+
+    fn write_gzipped(path: &std::path::Path, data: &str) {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let file = std::fs::File::create(path).unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(data.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_dry_run_import() {
+        let tmp_dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let line =
+            r#"10.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#;
+        write_gzipped(&tmp_dir.path().join("access.log.2.gz"), line);
+        let pattern = tmp_dir.path().join("access.log.*");
         assert_cli::Assert::main_binary()
-            .with_env(environment::Environment::inherit().insert("RUST_LOG", "INFO"))
-            .with_args(&[
-                "--buffer",
-                "1",
-                "--filter-ip",
-                "127.0.0.2",
+            .with_args(&["--dry-run", "import", pattern.to_str().unwrap()])
+            .succeeds()
+            .stdout()
+            .is("line: 1509818735 10.0.0.1 - 200 GET / 1.1 0 - - a.com")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_config_file() {
+        let tmp_dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let config = tmp_dir.path().join("rrinlog.toml");
+        std::fs::write(
+            &config,
+            "[parser]\nformat = \"apache_common\"\nhost = \"a.com\"\n",
+        )
+        .unwrap();
+        let line = r#"10.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0"#;
+        assert_cli::Assert::main_binary()
+            .with_args(&["--dry-run", "--config", config.to_str().unwrap()])
+            .stdin(line)
+            .succeeds()
+            .stdout()
+            .is("line: 1509818735 10.0.0.1 - 200 GET / 1.1 0 NA NA a.com")
+            .unwrap();
+
+        std::fs::write(&config, "[flush]\nbuffer = -1\n").unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&["--dry-run", "--config", config.to_str().unwrap()])
+            .fails()
+            .stdout()
+            .contains("for key `flush.buffer`")
+            .unwrap();
+    }
+
+    #[test]
+    fn run_prune_db_test() {
+        let test = TestDb::new();
+        let old_line = access_line("127.0.0.1");
+        let new_line = format!(
+            r#"127.0.0.1 - - [{}] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#,
+            Local::now().format("%d/%b/%Y:%H:%M:%S %z")
+        );
+        test.ingest(&[], &format!("{}\n{}\n", old_line, new_line));
+        test.run(&["prune", "--dry-run"]).fails().unwrap();
+
+        test.run(&["--retention", "30days", "prune", "--dry-run"])
+            .succeeds()
+            .stdout()
+            .contains("1 rows from 2017-11-04T18:05:35+00:00 until")
+            .unwrap();
+        assert_eq!(test.rows(), 2);
+
+        test.run(&["--retention", "30days", "prune"])
+            .succeeds()
+            .stdout()
+            .contains("Deleted 1 rows older than")
+            .unwrap();
+        assert_eq!(test.rows(), 1);
+    }
+
+    #[test]
+    fn run_anonymize_db_test() {
+        let test = TestDb::new();
+        let input = [
+            access_line("10.0.0.5"),
+            access_line("10.0.0.5"),
+            access_line("10.0.0.6"),
+        ]
+        .join("\n");
+
+        // The filter sees the full address, so only 10.0.0.6 is denied
+        test.ingest(
+            &[
+                "--dedup",
+                "--anonymize-ip",
+                "truncate",
                 "--filter-ip",
-                "127.0.0.3",
-                "--db",
-                tmp,
-            ])
-            .stdin(format!("{}\n{}\n{}", fail_line, success_line, skip_line))
+                "10.0.0.6",
+            ],
+            &input,
+        );
+
+        let conn = test.conn();
+        let addrs: Vec<Option<String>> = rrinlog_core::schema::logs::table
+            .select(rrinlog_core::schema::logs::remote_addr)
+            .load(&conn)
+            .unwrap();
+        assert_eq!(addrs, vec![Some(String::from("10.0.0.0"))]);
+        assert_eq!(
+            rrinlog_core::db::metadata(&conn, anonymize::METADATA_KEY).unwrap(),
+            Some(String::from("truncate"))
+        );
+
+        test.run(&["--anonymize-ip", "hmac"])
+            .stdin(access_line("10.0.0.5"))
+            .fails()
+            .and()
+            .stdout()
+            .contains("requires a secret")
+            .unwrap();
+
+        // Lines that fail to parse aren't kept with their full address
+        test.run(&["--anonymize-ip", "truncate"])
+            .with_env(environment::Environment::inherit().insert("RUST_LOG", "WARN"))
+            .stdin("10.0.0.7 Cats are alright")
             .succeeds()
             .stdout()
-            .satisfies(|out| out.lines().count() == 4, "4 lines")
+            .contains("1 lines that failed to parse were dropped")
+            .unwrap();
+        assert_eq!(test.rejected(), 0);
+
+        // Storing addresses with another mode marks the db as mixed
+        test.ingest(&[], &access_line("10.0.0.7"));
+        assert_eq!(
+            rrinlog_core::db::metadata(&conn, anonymize::METADATA_KEY).unwrap(),
+            Some(String::from("mixed"))
+        );
+
+        test.ingest(&["--anonymize-ip", "truncate"], &access_line("10.0.0.8"));
+        assert_eq!(
+            rrinlog_core::db::metadata(&conn, anonymize::METADATA_KEY).unwrap(),
+            Some(String::from("mixed"))
+        );
+    }
+
+    #[test]
+    fn test_record_mode_of_existing_rows() {
+        use rrinlog_core::db::metadata;
+        use rrinlog_core::schema;
+
+        let conn = rrinlog_core::db::setup(":memory:").unwrap();
+        record_mode(&conn, "truncate").unwrap();
+        assert_eq!(
+            metadata(&conn, anonymize::METADATA_KEY).unwrap(),
+            Some(String::from("truncate"))
+        );
+
+        // Rows stored before modes were recorded weren't anonymized
+        diesel::delete(schema::metadata::table)
+            .execute(&conn)
+            .unwrap();
+        diesel::insert_into(schema::logs::table)
+            .values(&NewLog::default())
+            .execute(&conn)
+            .unwrap();
+        record_mode(&conn, "truncate").unwrap();
+        assert_eq!(
+            metadata(&conn, anonymize::METADATA_KEY).unwrap(),
+            Some(String::from("mixed"))
+        );
+    }
+
+    #[test]
+    fn test_mode_recorded_once() {
+        use rrinlog_core::db::metadata;
+        use rrinlog_core::schema;
+
+        let store = Store::single(rrinlog_core::db::setup(":memory:").unwrap());
+        let conn = store.conn(None).unwrap();
+        let parser = VhostParser::default();
+        let filter = Filter::default();
+        let pipeline = test_pipeline(&parser, &filter);
+        let line =
+            r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#;
+
+        insert_buffer(&store, &pipeline, &[line], None);
+        assert_eq!(
+            metadata(&conn, anonymize::METADATA_KEY).unwrap(),
+            Some(String::from("none"))
+        );
+
+        // Later batches don't look at the metadata again
+        diesel::delete(schema::metadata::table)
+            .execute(&*conn)
+            .unwrap();
+        insert_buffer(&store, &pipeline, &[line], None);
+        assert_eq!(metadata(&conn, anonymize::METADATA_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn run_geoip_db_test() {
+        use rrinlog_core::schema::logs::dsl::*;
+        let test = TestDb::new();
+        let input = [access_line("81.2.69.160"), access_line("10.0.0.5")].join("\n");
+
+        // The location is looked up from the full address, before it is truncated
+        test.ingest(
+            &[
+                "--geoip",
+                "../test-assets/GeoLite2-City-Test.mmdb",
+                "--anonymize-ip",
+                "truncate",
+            ],
+            &input,
+        );
+
+        let conn = test.conn();
+        let places: Vec<(Option<String>, Option<String>, Option<String>)> = logs
+            .select((country, region, city))
+            .order(ri)
+            .load(&conn)
+            .unwrap();
+        assert_eq!(
+            places,
+            vec![
+                (
+                    Some(String::from("GB")),
+                    Some(String::from("England")),
+                    Some(String::from("London")),
+                ),
+                (None, None, None),
+            ]
+        );
+
+        let coordinates: Vec<(Option<f64>, Option<f64>)> = logs
+            .select((latitude, longitude))
+            .order(ri)
+            .load(&conn)
+            .unwrap();
+        assert_eq!(
+            coordinates,
+            vec![(Some(51.5142), Some(-0.0931)), (None, None)]
+        );
+
+        test.run(&["--geoip", "missing.mmdb"])
+            .stdin(access_line("10.0.0.5"))
+            .fails()
+            .and()
             .stdout()
-            .contains("Text did not match regex `Cats are alright`")
+            .contains("Unable to open GeoIP db missing.mmdb")
+            .unwrap();
+    }
+
+    #[test]
+    fn run_asn_db_test() {
+        use rrinlog_core::schema::logs;
+        let test = TestDb::new();
+        let input = [
+            access_line("81.2.69.160"),
+            access_line("81.2.70.1"),
+            access_line("10.0.0.5"),
+        ]
+        .join("\n");
+
+        test.ingest(
+            &[
+                "--asn",
+                "../test-assets/GeoLite2-ASN-Test.csv",
+                "--anonymize-ip",
+                "drop",
+            ],
+            &input,
+        );
+
+        let conn = test.conn();
+        let systems: Vec<(Option<i64>, Option<String>)> = logs::table
+            .select((logs::asn, logs::as_org))
+            .order(logs::ri)
+            .load(&conn)
+            .unwrap();
+        assert_eq!(
+            systems,
+            vec![
+                (Some(20712), Some(String::from("Andrews & Arnold Ltd"))),
+                (Some(64500), Some(String::from("Example Transit"))),
+                (None, None),
+            ]
+        );
+
+        test.run(&["--asn", "missing.csv"])
+            .stdin(access_line("10.0.0.5"))
+            .fails()
+            .and()
             .stdout()
-            .satisfies(
-                |out| {
-                    let lines: Vec<&str> = out.lines().skip(1).collect();
-                    lines.len() == 3
-                        && lines[0].contains("inserting 0 out of 1 records")
-                        && lines[1].contains("inserting 1 out of 1 records")
-                        && lines[2].contains("inserting 0 out of 1 records")
-                },
-                "correct lines",
+            .contains("Unable to open ASN db missing.csv")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_replace_first() {
+        assert_eq!(&*replace_first(b"a b a", b"a", b"cd"), b"cd b a");
+        assert_eq!(&*replace_first(b"a b a", b"c", b"cd"), b"a b a");
+        assert_eq!(&*replace_first(b"a b", b"", b"cd"), b"a b");
+    }
+
+    #[test]
+    fn run_monthly_shards_test() {
+        let test = TestDb::missing();
+        let nov_line = access_line("127.0.0.1");
+        let dec_line =
+            r#"127.0.0.1 - - [01/Dec/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#;
+        test.ingest(
+            &["--monthly-shards"],
+            &format!("{}\n{}\nCats are alright\n", nov_line, dec_line),
+        );
+
+        let nov = test.dir.path().join("logs-2017-11.db");
+        let dec = test.dir.path().join("logs-2017-12.db");
+        assert!(!test.path.exists());
+        let count = |path: &std::path::Path| -> (i64, i64) {
+            use rrinlog_core::schema::{logs, rejected_lines};
+            let conn = SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+            (
+                logs::table.count().get_result(&conn).unwrap(),
+                rejected_lines::table.count().get_result(&conn).unwrap(),
             )
+        };
+
+        // The rejected line is kept with the last line read
+        assert_eq!(count(&nov), (1, 0));
+        assert_eq!(count(&dec), (1, 1));
+
+        // Each shard is visited, though its rows were already rolled up as they were inserted
+        test.run(&["--monthly-shards", "backfill"])
+            .succeeds()
+            .stdout()
+            .contains("Wrote 0 rollup buckets")
+            .unwrap();
+
+        // November is older than the retention as a whole, so its file is deleted
+        let retention = format!(
+            "{}days",
+            (Utc::now().timestamp() - 1_512_086_400) / (24 * 60 * 60)
+        );
+        test.run(&[
+            "--monthly-shards",
+            "--retention",
+            &retention,
+            "prune",
+            "--dry-run",
+        ])
+        .succeeds()
+        .stdout()
+        .contains("logs-2017-11.db would be deleted")
+        .unwrap();
+        assert!(nov.exists());
+
+        test.run(&["--monthly-shards", "--retention", &retention, "prune"])
+            .succeeds()
+            .stdout()
+            .contains("Deleted 0 rows older than")
+            .unwrap();
+        assert!(!nov.exists());
+        assert_eq!(count(&dec), (1, 1));
+    }
+
+    #[test]
+    fn run_import_db_test() {
+        let test = TestDb::new();
+        let success_line = access_line("127.0.0.1");
+        let skip_line = access_line("127.0.0.2");
+        let plain = test.dir.path().join("access.log.1");
+        std::fs::write(&plain, format!("{}\nCats are alright\n", success_line)).unwrap();
+        let gzipped = test.dir.path().join("access.log.2.gz");
+        write_gzipped(
+            &gzipped,
+            &format!("{}\n{}\n{}\n", success_line, skip_line, success_line),
+        );
+
+        let pattern = test.dir.path().join("access.log.*");
+        let plain_counts = format!("{}: 1 parsed, 1 failed, 0 filtered", plain.display());
+        let gzipped_counts = format!("{}: 2 parsed, 0 failed, 1 filtered", gzipped.display());
+        test.run(&["--filter-ip=127.0.0.2", "import", pattern.to_str().unwrap()])
+            .succeeds()
+            .stdout()
+            .contains(plain_counts.as_str())
+            .stdout()
+            .contains(gzipped_counts.as_str())
+            .unwrap();
+        assert_eq!(test.rows(), 3);
+    }
+
+    #[test]
+    fn test_insert_received_flushes_partial_buffer() {
+        let test = TestDb::new();
+        let db = String::from(test.db());
+
+        let (tx, rx) = mpsc::sync_channel(10);
+        let ingestor = thread::spawn(move || {
+            let store = Store::single(SqliteConnection::establish(&db).unwrap());
+            let parser = VhostParser::default();
+            let filter = Filter::default();
+            let pipeline = test_pipeline(&parser, &filter);
+            let interval = Duration::from_millis(50);
+            insert_received(rx, &store, &pipeline, 10, interval)
+        });
+
+        tx.send(access_line("127.0.0.1").into_bytes()).unwrap();
+
+        // The line is inserted while the channel is still open and the buffer isn't full
+        let conn = test.conn();
+        let start = Instant::now();
+        let mut count = 0;
+        while count == 0 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+
+            // The db is locked while the ingestor is inserting
+            count = rrinlog_core::schema::logs::table
+                .count()
+                .get_result::<i64>(&conn)
+                .unwrap_or(0);
+        }
+        assert_eq!(count, 1);
+
+        drop(tx);
+        let counts = ingestor.join().unwrap();
+        assert_eq!(
+            counts,
+            LineCounts {
+                parsed: 1,
+                failed: 0,
+                filtered: 0,
+                duplicates: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_retry_after_home_shard_fails() {
+        use diesel::connection::SimpleConnection;
+        use rrinlog_core::schema::logs;
+
+        let test = TestDb::missing();
+        let store = Store::open(test.db(), true).unwrap();
+        let parser = VhostParser::default();
+        let filter = Filter::default();
+        let pipeline = test_pipeline(&parser, &filter);
+
+        // A late line for November and a line for December, whose shard is the home db
+        let line = |date: &str| {
+            format!(
+                "127.0.0.1 - - [{} +0000] \"GET / HTTP/1.1\" 200 0 \"-\" \"-\" \"a.com\"\n",
+                date
+            )
+        };
+        let buffer = vec![line("30/Nov/2017:13:05:35"), line("01/Dec/2017:13:05:35")];
+        let nov = store.key(Utc.ymd(2017, 11, 30).and_hms(13, 5, 35).timestamp());
+        let dec = store.key(Utc.ymd(2017, 12, 1).and_hms(13, 5, 35).timestamp());
+        let read = buffer.iter().map(|x| x.len() as i64).sum::<i64>();
+        let checkpoint = Checkpoint {
+            path: String::from("access.log"),
+            inode: 1,
+            byte_offset: 100 + read,
+        };
+
+        let rows = |key| -> i64 {
+            logs::table
+                .count()
+                .get_result(&*store.conn(key).unwrap())
+                .unwrap()
+        };
+        let resumed_at = || {
+            let cp = store.load_checkpoint(Path::new("access.log")).unwrap();
+            cp.map(|x| x.byte_offset)
+        };
+
+        // November is stored before December fails, so the file is still resumed from the start
+        // of the batch
+        store
+            .conn(dec)
+            .unwrap()
+            .batch_execute(
+                "CREATE TRIGGER fail BEFORE INSERT ON logs BEGIN SELECT RAISE(ABORT, 'full'); END;",
+            )
+            .unwrap();
+        assert!(try_insert_buffer(&store, &pipeline, &buffer, Some(&checkpoint)).is_err());
+        assert_eq!((rows(nov), rows(dec)), (1, 0));
+        assert_eq!(resumed_at(), Some(100));
+
+        // Reading the batch again doesn't store November's line twice
+        store
+            .conn(dec)
+            .unwrap()
+            .batch_execute("DROP TRIGGER fail;")
+            .unwrap();
+        let counts = try_insert_buffer(&store, &pipeline, &buffer, Some(&checkpoint)).unwrap();
+        assert_eq!((counts.parsed, counts.duplicates), (1, 1));
+        assert_eq!((rows(nov), rows(dec)), (1, 1));
+        assert_eq!(resumed_at(), Some(100 + read));
+    }
+
+    #[test]
+    fn run_reprocess_db_test() {
+        let test = TestDb::new();
+
+        // Lines without a host are rejected by the default format
+        let fail_line = "Cats are alright";
+        let apache_line = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#;
+        test.ingest(&[], &format!("{}\r\n{}\n", fail_line, apache_line));
+
+        use rrinlog_core::schema::{logs, rejected_lines};
+        let conn = test.conn();
+        let rejected: Vec<RejectedLine> = rejected_lines::table.load(&conn).unwrap();
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0].line, fail_line.as_bytes());
+        assert_eq!(
+            rejected[0].error,
+            "Unable to parse $remote_addr at byte 0: expected ` - ` after the value"
+        );
+        assert_eq!(rejected[1].line, apache_line.as_bytes());
+
+        test.run(&[
+            "--format",
+            "apache_common",
+            "--host",
+            "example.com",
+            "reprocess",
+        ])
+        .succeeds()
+        .stdout()
+        .is("1 parsed, 1 failed, 0 filtered, 0 duplicates")
+        .unwrap();
+
+        let rejected: Vec<RejectedLine> = rejected_lines::table.load(&conn).unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].line, fail_line.as_bytes());
+        assert!(rejected[0].error.contains("$remote_user"));
+
+        let hosts: Vec<String> = logs::table.select(logs::host).load(&conn).unwrap();
+        assert_eq!(hosts, vec![String::from("example.com")]);
+    }
+
+    #[test]
+    fn test_trim_newline() {
+        assert_eq!(trim_newline(b"a\r\n"), b"a");
+        assert_eq!(trim_newline(b"a\n"), b"a");
+        assert_eq!(trim_newline(b"a"), b"a");
+        assert_eq!(trim_newline(b"\n"), b"");
+    }
+
+    #[test]
+    fn run_dedup_db_test() {
+        let test = TestDb::new();
+        let line1 = access_line("127.0.0.1");
+        let line2 =
+            r#"127.0.0.1 - - [04/Nov/2017:13:05:36 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#;
+        let first = test.dir.path().join("access.log.2");
+        std::fs::write(&first, format!("{}\n{}\n", line1, line1)).unwrap();
+        let second = test.dir.path().join("access.log.1");
+        std::fs::write(&second, format!("{}\n{}\n", line1, line2)).unwrap();
+
+        let first_counts = format!(
+            "{}: 1 parsed, 0 failed, 0 filtered, 1 duplicates",
+            first.display()
+        );
+        test.run(&["--dedup", "import", first.to_str().unwrap()])
+            .succeeds()
+            .stdout()
+            .contains(first_counts.as_str())
+            .unwrap();
+
+        let second_counts = format!(
+            "{}: 1 parsed, 0 failed, 0 filtered, 1 duplicates",
+            second.display()
+        );
+        test.run(&["--dedup", "import", second.to_str().unwrap()])
+            .with_env(environment::Environment::inherit().insert("RUST_LOG", "INFO"))
+            .succeeds()
+            .stdout()
+            .contains("inserting 1 out of 2 records (1 duplicates skipped)")
+            .stdout()
+            .contains(second_counts.as_str())
+            .unwrap();
+
+        assert_eq!(test.rows(), 2);
+
+        // The skipped duplicates aren't counted in the rollups either, and backfilling rows that
+        // were stored before the rollups existed arrives at the same result
+        use rrinlog_core::schema::{rollups_day, rollups_hour, rollups_minute};
+        let conn = test.conn();
+        let requests = || -> Vec<i64> {
+            rollups_day::table
+                .select(rollups_day::requests)
+                .load(&conn)
+                .unwrap()
+        };
+        assert_eq!(requests(), vec![2]);
+
+        diesel::delete(rollups_minute::table)
+            .execute(&conn)
+            .unwrap();
+        diesel::delete(rollups_hour::table).execute(&conn).unwrap();
+        diesel::delete(rollups_day::table).execute(&conn).unwrap();
+        test.run(&["backfill"])
+            .succeeds()
+            .stdout()
+            .contains("Wrote 3 rollup buckets")
+            .unwrap();
+        assert_eq!(requests(), vec![2]);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint(b"a").len(), FINGERPRINT_LEN);
+        assert_eq!(fingerprint(b"a"), fingerprint(b"a"));
+        assert_ne!(fingerprint(b"a"), fingerprint(b"b"));
+    }
+
+    #[test]
+    fn run_filter_db_test() {
+        let test = TestDb::new();
+
+        let lines = [
+            r#"10.1.2.3 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#,
+            r#"10.0.0.5 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#,
+            r#"192.168.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET /health HTTP/1.1" 200 0 "-" "-" "a.com""#,
+            r#"192.168.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#,
+            r#"172.16.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#,
+        ];
+        let log = test.dir.path().join("access.log");
+        std::fs::write(&log, lines.join("\n")).unwrap();
+
+        let counts = format!(
+            "{}: 2 parsed, 0 failed, 3 filtered, 0 duplicates",
+            log.display()
+        );
+        test.run(&[
+            "--filter-ip",
+            "172.16.0.0/12",
+            "--filter",
+            "allow ip 10.0.0.5",
+            "--filter",
+            "deny ip 10.0.0.0/8",
+            "--filter",
+            "deny path ^/health$",
+            "import",
+            log.to_str().unwrap(),
+        ])
+        .succeeds()
+        .stdout()
+        .contains(counts.as_str())
+        .unwrap();
+
+        test.run(&["--filter", "deny referer x", "import", "x"])
+            .fails()
+            .stderr()
+            .contains("Expected a field of `ip`")
             .unwrap();
     }
 }
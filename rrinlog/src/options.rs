@@ -1,9 +1,22 @@
+use anonymize;
+use rrinlog_core::filter::{Address, Rule};
+use rrinlog_core::format::LogFormat;
+use std::path::PathBuf;
+use std::time::Duration;
+
 #[derive(StructOpt, Debug)]
 #[structopt(
     name = "rrinlog",
     about = "Ingests nginx access logs and persists them to SQLite"
 )]
 pub struct Opt {
+    #[structopt(
+        long = "config",
+        help = "TOML file with settings for any of the flags. Flags given on the command line take precedence",
+        parse(from_os_str)
+    )]
+    pub config: Option<PathBuf>,
+
     #[structopt(
         short = "d",
         long = "dry-run",
@@ -11,8 +24,44 @@ pub struct Opt {
     )]
     pub dry_run: bool,
 
-    #[structopt(long = "filter-ip", help = "Do not store given ip address in the db")]
-    pub filter_ips: Vec<String>,
+    #[structopt(
+        long = "filter-ip",
+        help = "Do not store given ip address or CIDR block in the db (shorthand for --filter 'deny ip <addr>'). Addresses that aren't ips (eg: unix:) are matched as is"
+    )]
+    pub filter_ips: Vec<Address>,
+
+    #[structopt(
+        long = "filter",
+        help = "Allow or deny lines with a rule like 'deny ip 10.0.0.0/8', 'deny path ^/health$', 'deny user_agent bot', 'deny host ^staging', or 'allow status >=500'. The first rule to match decides",
+        number_of_values = 1
+    )]
+    pub filters: Vec<Rule>,
+
+    #[structopt(
+        long = "anonymize-ip",
+        help = "Anonymize addresses after filtering and before storing them: truncate (to the /24 or /48), hmac (keyed hash, requires --anonymize-secret), or drop. Lines that fail to parse are then dropped with a warning rather than kept in rejected_lines"
+    )]
+    pub anonymize_ip: Option<anonymize::Mode>,
+
+    #[structopt(
+        long = "anonymize-secret",
+        help = "Secret key that addresses are hashed with by --anonymize-ip hmac. Prefer setting it in the --config file, which keeps it out of the process list"
+    )]
+    pub anonymize_secret: Option<String>,
+
+    #[structopt(
+        long = "geoip",
+        help = "Look up the country, region, city, and coordinates of each address in a MaxMind DB (eg: GeoLite2-City.mmdb) before storing it",
+        parse(from_os_str)
+    )]
+    pub geoip: Option<PathBuf>,
+
+    #[structopt(
+        long = "asn",
+        help = "Look up the autonomous system number and organization of each address in a MaxMind DB (eg: GeoLite2-ASN.mmdb) or a CSV of network,asn,organization rows",
+        parse(from_os_str)
+    )]
+    pub asn: Option<PathBuf>,
 
     #[structopt(
         short = "b",
@@ -22,10 +71,122 @@ pub struct Opt {
     )]
     pub buffer: usize,
 
+    #[structopt(
+        long = "flush-interval",
+        help = "Insert buffered lines once the oldest has waited this long, even if the buffer isn't full (eg: 5s)",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    pub flush_interval: Option<Duration>,
+
+    #[structopt(
+        long = "dedup",
+        help = "Skip lines that have already been stored, so that overlapping input can be ingested again"
+    )]
+    pub dedup: bool,
+
+    #[structopt(
+        long = "retention",
+        help = "Delete rows once they are older than the duration (eg: 90days). Checked hourly while ingesting. Day rollups are kept, unless --monthly-shards deletes the month",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    pub retention: Option<Duration>,
+
     #[structopt(
         long = "db",
         help = "Filepath to sqlite database",
         default_value = "logs.db"
     )]
     pub db: String,
+
+    #[structopt(
+        long = "monthly-shards",
+        help = "Store the rows of each month in their own db next to --db (eg: logs-2026-10.db), so that retention deletes whole files"
+    )]
+    pub monthly_shards: bool,
+
+    #[structopt(
+        long = "format",
+        help = "nginx log_format string (or one of the presets: vhost, combined, main, apache_common, apache_combined)",
+        default_value = "vhost"
+    )]
+    pub format: LogFormat,
+
+    #[structopt(
+        long = "host",
+        help = "Virtual host to store when the log format doesn't contain one (eg: apache_common)"
+    )]
+    pub host: Option<String>,
+
+    #[structopt(
+        long = "json",
+        help = "Parse lines as JSON objects written by nginx's log_format escape=json"
+    )]
+    pub json: bool,
+
+    #[structopt(
+        long = "json-key",
+        help = "Map a JSON key to the nginx variable it contains (eg: ua=http_user_agent)"
+    )]
+    pub json_keys: Vec<String>,
+
+    #[structopt(
+        long = "follow",
+        help = "Follow the log file instead of reading stdin. Rotation is detected and the position is checkpointed in the db, so restarts resume where they left off",
+        parse(from_os_str),
+        conflicts_with = "dry-run"
+    )]
+    pub follow: Vec<PathBuf>,
+
+    #[structopt(
+        long = "syslog-udp",
+        help = "Listen for syslog messages on the UDP address (eg: 127.0.0.1:5140) instead of reading stdin",
+        conflicts_with_all = &["dry-run", "follow"]
+    )]
+    pub syslog_udp: Option<String>,
+
+    #[structopt(
+        long = "syslog-tcp",
+        help = "Listen for syslog messages on the TCP address instead of reading stdin",
+        conflicts_with_all = &["dry-run", "follow"]
+    )]
+    pub syslog_tcp: Option<String>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    #[structopt(
+        name = "import",
+        about = "Ingest existing log files (eg: rotated logs), which may be gzipped"
+    )]
+    Import {
+        #[structopt(
+            help = "Log files or glob patterns (eg: '/var/log/nginx/access.log.*.gz')",
+            required = true
+        )]
+        files: Vec<String>,
+    },
+
+    #[structopt(
+        name = "backfill",
+        about = "Rebuild the minute, hour, and day rollups from the stored rows (eg: rows stored before rollups existed)"
+    )]
+    Backfill,
+
+    #[structopt(
+        name = "prune",
+        about = "Delete the rows older than --retention now, rather than waiting for it to happen while ingesting"
+    )]
+    Prune {
+        #[structopt(long = "dry-run", help = "Report the rows that would be deleted")]
+        dry_run: bool,
+    },
+
+    #[structopt(
+        name = "reprocess",
+        about = "Parse the lines that failed to parse again (eg: after fixing --format) and move the ones that succeed into the logs"
+    )]
+    Reprocess,
 }
@@ -0,0 +1,353 @@
+//! Enforces `--retention` by deleting the rows that have aged out of it. Rows are deleted in small
+//! batches found through `idx_epoch`, so that ingestion and the server are never locked out of
+//! the db for long, and the freed pages are then handed back to the filesystem with an
+//! incremental vacuum. The minute and hour rollups of the expired rows go with them, while the day
+//! rollups are kept so that long ranges can still be graphed. With `--monthly-shards`, shards that
+//! only hold expired rows are deleted outright instead, day rollups included, so graphs of sharded
+//! dbs only reach back as far as the retention.
+
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Integer, Nullable};
+use rrinlog_core::rollup::Resolution;
+use rrinlog_core::shard::Month;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use store::{Key, Store};
+
+/// Number of rows deleted per transaction
+const PRUNE_BATCH: i64 = 1000;
+
+/// How often rows are pruned while ingesting
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Rollups that are deleted along with the raw rows
+const PRUNED_ROLLUPS: [Resolution; 2] = [Resolution::Minute, Resolution::Hour];
+
+/// `PRAGMA auto_vacuum` value of a db that can be incrementally vacuumed
+const AUTO_VACUUM_INCREMENTAL: i32 = 2;
+
+#[derive(QueryableByName)]
+struct AutoVacuum {
+    #[sql_type = "Integer"]
+    auto_vacuum: i32,
+}
+
+/// Rows that are older than the retention
+#[derive(Debug, PartialEq, QueryableByName)]
+pub struct Expired {
+    #[sql_type = "BigInt"]
+    pub rows: i64,
+
+    #[sql_type = "Nullable<BigInt>"]
+    pub oldest: Option<i64>,
+}
+
+/// Epoch before which rows are deleted
+pub fn cutoff(retention: Duration, now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.timestamp(now.timestamp() - retention.as_secs() as i64, 0)
+}
+
+pub fn expired(conn: &SqliteConnection, cutoff: DateTime<Utc>) -> QueryResult<Expired> {
+    sql_query("SELECT COUNT(*) AS rows, MIN(epoch) AS oldest FROM logs WHERE epoch < ?")
+        .bind::<BigInt, _>(cutoff.timestamp())
+        .get_result(conn)
+}
+
+const PRUNE_QUERY: &str = r#"
+DELETE FROM logs
+WHERE  ri IN (SELECT ri
+              FROM   logs
+              WHERE  epoch < ?
+              LIMIT  ?)
+"#;
+
+/// Deletes the rows older than the cutoff and their minute and hour rollups, and shrinks the file.
+/// Returns the number of rows deleted.
+pub fn prune(conn: &SqliteConnection, cutoff: DateTime<Utc>) -> QueryResult<usize> {
+    let mut deleted = 0;
+    loop {
+        let rows = sql_query(PRUNE_QUERY)
+            .bind::<BigInt, _>(cutoff.timestamp())
+            .bind::<BigInt, _>(PRUNE_BATCH)
+            .execute(conn)?;
+        deleted += rows;
+        if (rows as i64) < PRUNE_BATCH {
+            break;
+        }
+    }
+
+    // Only the buckets that end by the cutoff, as the others still count rows that are kept
+    let mut buckets = 0;
+    for &resolution in &PRUNED_ROLLUPS {
+        let qs = format!("DELETE FROM {} WHERE bucket <= ?", resolution.table());
+        buckets += sql_query(qs)
+            .bind::<BigInt, _>(cutoff.timestamp() - resolution.seconds())
+            .execute(conn)?;
+    }
+
+    if deleted > 0 || buckets > 0 {
+        vacuum(conn)?;
+    }
+
+    Ok(deleted)
+}
+
+/// Dbs created before pruning existed don't track free pages, so the file only shrinks once they
+/// are converted with a one time `PRAGMA auto_vacuum=INCREMENTAL; VACUUM;`
+fn vacuum(conn: &SqliteConnection) -> QueryResult<()> {
+    let mode: AutoVacuum = diesel::sql_query("PRAGMA auto_vacuum").get_result(conn)?;
+    if mode.auto_vacuum == AUTO_VACUUM_INCREMENTAL {
+        conn.batch_execute("PRAGMA incremental_vacuum;")
+    } else {
+        warn!("Pruned rows are reused, but the db won't shrink until it is converted with `PRAGMA auto_vacuum=INCREMENTAL; VACUUM;`");
+        Ok(())
+    }
+}
+
+/// Shards whose rows are all older than the cutoff
+pub fn expired_shards(store: &Store, cutoff: DateTime<Utc>) -> Result<Vec<Month>, String> {
+    Ok(store
+        .keys()?
+        .into_iter()
+        .flatten()
+        .filter(|month| month.next().start() <= cutoff.timestamp())
+        .collect())
+}
+
+/// The dbs that may hold rows older than the cutoff, other than the expired shards
+fn partially_expired(store: &Store, cutoff: DateTime<Utc>) -> Result<Vec<Key>, String> {
+    Ok(store
+        .keys()?
+        .into_iter()
+        .filter(|key| match *key {
+            Some(month) => {
+                month.start() < cutoff.timestamp() && month.next().start() > cutoff.timestamp()
+            }
+            None => true,
+        })
+        .collect())
+}
+
+/// Rows older than the cutoff that are left once the expired shards are deleted
+pub fn expired_rows(store: &Store, cutoff: DateTime<Utc>) -> Result<Expired, String> {
+    let mut total = Expired {
+        rows: 0,
+        oldest: None,
+    };
+
+    for key in partially_expired(store, cutoff)? {
+        let conn = store.conn(key)?;
+        let expired = expired(&conn, cutoff).map_err(|e| e.to_string())?;
+        total.rows += expired.rows;
+        total.oldest = match (total.oldest, expired.oldest) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    Ok(total)
+}
+
+/// Deletes the expired shards, along with their rollups, and the rows older than the cutoff in the
+/// others. Returns the paths of the shards deleted and the number of rows deleted.
+pub fn prune_store(store: &Store, cutoff: DateTime<Utc>) -> Result<(Vec<String>, usize), String> {
+    let mut removed = Vec::new();
+    for month in expired_shards(store, cutoff)? {
+        store.remove(month)?;
+        removed.push(store.path(Some(month)));
+    }
+
+    let mut deleted = 0;
+    for key in partially_expired(store, cutoff)? {
+        let conn = store.conn(key)?;
+        deleted += prune(&conn, cutoff).map_err(|e| format!("{}: {}", store.path(key), e))?;
+    }
+
+    Ok((removed, deleted))
+}
+
+/// Prunes while ingesting, at most once per `PRUNE_INTERVAL`
+pub struct Pruner {
+    retention: Duration,
+    last: Cell<Option<Instant>>,
+}
+
+impl Pruner {
+    pub fn new(retention: Duration) -> Pruner {
+        Pruner {
+            retention,
+            last: Cell::new(None),
+        }
+    }
+
+    pub fn maybe_prune(&self, store: &Store) {
+        if self
+            .last
+            .get()
+            .is_some_and(|x| x.elapsed() < PRUNE_INTERVAL)
+        {
+            return;
+        }
+
+        // A failed prune waits for the next interval too, rather than being retried with every
+        // batch of lines
+        self.last.set(Some(Instant::now()));
+        let cutoff = cutoff(self.retention, Utc::now());
+        match prune_store(store, cutoff) {
+            Ok((removed, rows)) => {
+                for path in removed {
+                    info!(
+                        "Deleted {} as it is older than {}",
+                        path,
+                        cutoff.to_rfc3339()
+                    );
+                }
+
+                if rows > 0 {
+                    info!("Pruned {} rows older than {}", rows, cutoff.to_rfc3339());
+                }
+            }
+            Err(e) => error!(
+                "Unable to prune rows older than {}: {}",
+                cutoff.to_rfc3339(),
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use rrinlog_core::models::NewLog;
+    use rrinlog_core::rollup;
+    use rrinlog_core::schema::logs;
+
+    fn insert_epochs(conn: &SqliteConnection, epochs: &[i64]) {
+        let rows: Vec<NewLog> = epochs
+            .iter()
+            .map(|&epoch| NewLog {
+                epoch,
+                host: "a.com".into(),
+                ..NewLog::default()
+            })
+            .collect();
+        diesel::insert_into(logs::table)
+            .values(&rows)
+            .execute(conn)
+            .unwrap();
+        rollup::record(conn, &rows.iter().collect::<Vec<_>>()).unwrap();
+    }
+
+    fn buckets(conn: &SqliteConnection, resolution: Resolution) -> Vec<i64> {
+        #[derive(QueryableByName)]
+        struct Bucket {
+            #[sql_type = "BigInt"]
+            bucket: i64,
+        }
+
+        let qs = format!("SELECT bucket FROM {} ORDER BY bucket", resolution.table());
+        let rows: Vec<Bucket> = sql_query(qs).load(conn).unwrap();
+        rows.into_iter().map(|x| x.bucket).collect()
+    }
+
+    #[test]
+    fn test_cutoff() {
+        let now = Utc.timestamp(1_509_818_735, 0);
+        let retention = Duration::from_secs(90 * 24 * 60 * 60);
+        assert_eq!(cutoff(retention, now), Utc.timestamp(1_502_042_735, 0));
+    }
+
+    #[test]
+    fn test_prune() {
+        let conn = rrinlog_core::db::setup(":memory:").unwrap();
+        let mut epochs: Vec<i64> = (0..PRUNE_BATCH * 2 + 5).collect();
+        epochs.extend(&[5_000, 6_000]);
+        insert_epochs(&conn, &epochs);
+
+        let cutoff = Utc.timestamp(5_000, 0);
+        let expected = Expired {
+            rows: PRUNE_BATCH * 2 + 5,
+            oldest: Some(0),
+        };
+        assert_eq!(expired(&conn, cutoff).unwrap(), expected);
+
+        assert_eq!(
+            prune(&conn, cutoff).unwrap(),
+            (PRUNE_BATCH * 2 + 5) as usize
+        );
+        let left: Vec<i64> = logs::table.select(logs::epoch).load(&conn).unwrap();
+        assert_eq!(left, vec![5_000, 6_000]);
+
+        // Buckets that still count a row that is kept stay
+        assert_eq!(buckets(&conn, Resolution::Minute), vec![4_980, 6_000]);
+        assert_eq!(buckets(&conn, Resolution::Hour), vec![3_600]);
+        assert_eq!(buckets(&conn, Resolution::Day), vec![0]);
+        assert_eq!(
+            expired(&conn, cutoff).unwrap(),
+            Expired {
+                rows: 0,
+                oldest: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_maybe_prune() {
+        let store = Store::single(rrinlog_core::db::setup(":memory:").unwrap());
+        let conn = store.conn(None).unwrap();
+        let now = Utc::now().timestamp();
+        insert_epochs(&conn, &[now - 7_200, now]);
+
+        let pruner = Pruner::new(Duration::from_secs(3_600));
+        pruner.maybe_prune(&store);
+        assert_eq!(logs::table.count().get_result::<i64>(&*conn).unwrap(), 1);
+
+        // Not pruned again until the interval has passed
+        insert_epochs(&conn, &[now - 7_200]);
+        pruner.maybe_prune(&store);
+        assert_eq!(logs::table.count().get_result::<i64>(&*conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_prune_shards() {
+        let dir = tempdir::TempDir::new("rrinlog-prune").unwrap();
+        let db = dir.path().join("logs.db");
+        let store = Store::open(db.to_str().unwrap(), true).unwrap();
+
+        let oct = Month {
+            year: 2017,
+            month: 10,
+        };
+        let nov = oct.next();
+        for &epoch in &[oct.start(), nov.start(), nov.start() + 10] {
+            insert_epochs(&store.conn(store.key(epoch)).unwrap(), &[epoch]);
+        }
+
+        // October is deleted whole, while November is pruned row by row
+        let cutoff = Utc.timestamp(nov.start() + 5, 0);
+        assert_eq!(expired_shards(&store, cutoff).unwrap(), vec![oct]);
+        assert_eq!(
+            expired_rows(&store, cutoff).unwrap(),
+            Expired {
+                rows: 1,
+                oldest: Some(nov.start())
+            }
+        );
+
+        let (removed, rows) = prune_store(&store, cutoff).unwrap();
+        assert_eq!(removed, vec![oct.path(&db).display().to_string()]);
+        assert_eq!(rows, 1);
+        assert!(!oct.path(&db).exists());
+        assert_eq!(store.keys().unwrap(), vec![Some(nov)]);
+
+        // October's day rollups went with its shard, while November's are kept
+        let conn = store.conn(Some(nov)).unwrap();
+        assert_eq!(buckets(&conn, Resolution::Day), vec![nov.start()]);
+    }
+}
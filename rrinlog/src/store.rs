@@ -0,0 +1,187 @@
+//! Where rows are stored: the db given with `--db`, or with `--monthly-shards`, a db per month next
+//! to it (eg: `logs-2026-10.db`). Shards are created as rows for their month arrive.
+
+use diesel::prelude::*;
+use rrinlog_core::db;
+use rrinlog_core::models::Checkpoint;
+use rrinlog_core::shard::{self, Month};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use tail;
+
+/// Identifies the db that a row is stored in: the month for shards, and `None` otherwise
+pub type Key = Option<Month>;
+
+pub enum Store {
+    Single(Rc<SqliteConnection>),
+    Monthly {
+        db: PathBuf,
+        shards: RefCell<BTreeMap<Month, Rc<SqliteConnection>>>,
+    },
+}
+
+impl Store {
+    /// Connects to the db, creating or upgrading its schema first. For shards, the existing ones
+    /// are upgraded now, so that a shard with a newer schema is refused on startup.
+    pub fn open(db: &str, monthly: bool) -> Result<Store, String> {
+        if !monthly {
+            return Ok(Store::single(db::setup(db).map_err(|e| e.to_string())?));
+        }
+
+        let store = Store::Monthly {
+            db: PathBuf::from(db),
+            shards: RefCell::new(BTreeMap::new()),
+        };
+
+        for month in store.months()? {
+            store.conn(Some(month))?;
+        }
+
+        Ok(store)
+    }
+
+    pub fn single(conn: SqliteConnection) -> Store {
+        Store::Single(Rc::new(conn))
+    }
+
+    /// The db that rows with the epoch are stored in
+    pub fn key(&self, epoch: i64) -> Key {
+        match *self {
+            Store::Single(_) => None,
+            Store::Monthly { .. } => Some(Month::of(epoch)),
+        }
+    }
+
+    /// The db that rejected lines and the checkpoints that files are resumed from are stored in:
+    /// the db of the epoch, or a newer shard if one exists. These checkpoints then only ever move
+    /// to newer shards, so the newest shard with a checkpoint has the latest one, even after
+    /// reading late lines for an older month.
+    pub fn home(&self, epoch: i64) -> Key {
+        match *self {
+            Store::Single(_) => None,
+            Store::Monthly { ref shards, .. } => {
+                let newest = shards.borrow().keys().next_back().cloned();
+                newest.max(self.key(epoch))
+            }
+        }
+    }
+
+    pub fn conn(&self, key: Key) -> Result<Rc<SqliteConnection>, String> {
+        match (self, key) {
+            (Store::Single(conn), _) => Ok(Rc::clone(conn)),
+            (Store::Monthly { .. }, None) => Err(String::from("A shard requires a month")),
+            (Store::Monthly { db, shards }, Some(month)) => {
+                if let Some(conn) = shards.borrow().get(&month) {
+                    return Ok(Rc::clone(conn));
+                }
+
+                let path = month.path(db);
+                let conn = db::setup(&path.to_string_lossy()).map_err(|e| e.to_string())?;
+                let conn = Rc::new(conn);
+                shards.borrow_mut().insert(month, Rc::clone(&conn));
+                Ok(conn)
+            }
+        }
+    }
+
+    /// Months of the shards that exist, from the oldest
+    fn months(&self) -> Result<Vec<Month>, String> {
+        match *self {
+            Store::Single(_) => Ok(Vec::new()),
+            Store::Monthly { ref db, .. } => shard::existing(db)
+                .map(|x| x.into_iter().map(|(month, _)| month).collect())
+                .map_err(|e| format!("Unable to list the shards of {}: {}", db.display(), e)),
+        }
+    }
+
+    /// Every db, from the oldest shard
+    pub fn keys(&self) -> Result<Vec<Key>, String> {
+        match *self {
+            Store::Single(_) => Ok(vec![None]),
+            Store::Monthly { .. } => Ok(self.months()?.into_iter().map(Some).collect()),
+        }
+    }
+
+    /// Path of the db, for messages
+    pub fn path(&self, key: Key) -> String {
+        match (self, key) {
+            (Store::Monthly { db, .. }, Some(month)) => month.path(db).display().to_string(),
+            _ => String::from("db"),
+        }
+    }
+
+    /// Deletes the shard's file
+    pub fn remove(&self, month: Month) -> Result<(), String> {
+        match *self {
+            Store::Single(_) => Err(String::from("Only shards can be removed")),
+            Store::Monthly { ref db, ref shards } => {
+                shards.borrow_mut().remove(&month);
+                let path = month.path(db);
+                shard::remove(&path)
+                    .map_err(|e| format!("Unable to remove {}: {}", path.display(), e))
+            }
+        }
+    }
+
+    /// Checkpoints are stored in the `home` db, so the newest shard with a checkpoint for the file
+    /// has the latest one
+    pub fn load_checkpoint(&self, path: &Path) -> Result<Option<Checkpoint>, String> {
+        for key in self.keys()?.into_iter().rev() {
+            let conn = self.conn(key)?;
+            if let Some(cp) = tail::load_checkpoint(&conn, path).map_err(|e| e.to_string())? {
+                return Ok(Some(cp));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_monthly_store() {
+        let dir = tempdir::TempDir::new("rrinlog-store").unwrap();
+        let dir = dir.path();
+        let db = dir.join("logs.db");
+
+        let store = Store::open(db.to_str().unwrap(), true).unwrap();
+        assert_eq!(store.keys().unwrap(), vec![]);
+
+        let nov = store.key(1_509_818_735);
+        let dec = store.key(1_512_086_400);
+        store.conn(dec).unwrap();
+        store.conn(nov).unwrap();
+        assert!(dir.join("logs-2017-11.db").exists());
+        assert_eq!(store.keys().unwrap(), vec![nov, dec]);
+
+        // A late line for November keeps the checkpoint in December
+        assert_eq!(store.home(1_509_818_735), dec);
+        assert_eq!(store.home(1_514_764_800), store.key(1_514_764_800));
+
+        let checkpoint = |offset| Checkpoint {
+            path: String::from("access.log"),
+            inode: 1,
+            byte_offset: offset,
+        };
+        for &(key, offset) in &[(nov, 10), (dec, 20)] {
+            diesel::insert_into(::rrinlog_core::schema::checkpoints::table)
+                .values(&checkpoint(offset))
+                .execute(&*store.conn(key).unwrap())
+                .unwrap();
+        }
+        let cp = store.load_checkpoint(Path::new("access.log")).unwrap();
+        assert_eq!(cp.map(|x| x.byte_offset), Some(20));
+
+        store.remove(dec.unwrap()).unwrap();
+        assert_eq!(store.keys().unwrap(), vec![nov]);
+        let cp = store.load_checkpoint(Path::new("access.log")).unwrap();
+        assert_eq!(cp.map(|x| x.byte_offset), Some(10));
+    }
+}
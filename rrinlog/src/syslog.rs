@@ -0,0 +1,156 @@
+//! Listens for syslog messages sent by nginx's `access_log syslog:server=...`. Each listener runs
+//! on its own thread and forwards the raw messages to the thread inserting into the db. The
+//! envelope is stripped later by `rrinlog_core::syslog::SyslogParser`.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+
+/// Syslog over UDP caps messages at the size of a datagram
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Binds the socket and receives messages on a new thread. Returns the address that was bound.
+pub fn listen_udp(addr: &str, tx: SyncSender<Vec<u8>>) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(addr)?;
+    let local = socket.local_addr()?;
+    info!("listening for syslog messages on udp://{}", local);
+    thread::spawn(move || {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, _)) => {
+                    if tx.send(buf[..len].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => error!("Unable to receive syslog message: {}", e),
+            }
+        }
+    });
+    Ok(local)
+}
+
+/// Binds the socket and accepts connections on a new thread, with a thread per connection. Returns
+/// the address that was bound.
+pub fn listen_tcp(addr: &str, tx: SyncSender<Vec<u8>>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    info!("listening for syslog messages on tcp://{}", local);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || read_stream(stream, &tx));
+                }
+                Err(e) => error!("Unable to accept syslog connection: {}", e),
+            }
+        }
+    });
+    Ok(local)
+}
+
+fn read_stream(stream: TcpStream, tx: &SyncSender<Vec<u8>>) {
+    let peer = stream
+        .peer_addr()
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut frame = Vec::new();
+        match read_frame(&mut reader, &mut frame) {
+            Ok(true) => {
+                if tx.send(frame).is_err() {
+                    break;
+                }
+            }
+            Ok(false) => break,
+            Err(e) => {
+                error!("Unable to read syslog message from {}: {}", peer, e);
+                break;
+            }
+        }
+    }
+}
+
+/// Reads a message framed with either of the methods in RFC 6587: prefixed with its length in
+/// bytes (octet counting) or terminated by a newline. Returns false once the stream has ended.
+fn read_frame<R: BufRead>(reader: &mut R, frame: &mut Vec<u8>) -> io::Result<bool> {
+    let octet_counting = match reader.fill_buf()?.first() {
+        None => return Ok(false),
+        Some(b) => b.is_ascii_digit(),
+    };
+
+    if !octet_counting {
+        reader.read_until(b'\n', frame)?;
+        return Ok(true);
+    }
+
+    let mut len = Vec::new();
+    reader.read_until(b' ', &mut len)?;
+    let len = String::from_utf8_lossy(&len)
+        .trim_end()
+        .parse::<u64>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid message length"))?;
+    reader.take(len).read_to_end(frame)?;
+    if frame.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "stream ended within a message",
+        ));
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn read_frames(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut reader = Cursor::new(data);
+        let mut frames = Vec::new();
+        let mut frame = Vec::new();
+        while read_frame(&mut reader, &mut frame)? {
+            frames.push(std::mem::take(&mut frame));
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let frames = read_frames(b"<13>Nov  4 a b: c\n11 <13>1 x\ny z<13>d\n").unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                b"<13>Nov  4 a b: c\n".to_vec(),
+                b"<13>1 x\ny z".to_vec(),
+                b"<13>d\n".to_vec()
+            ]
+        );
+
+        assert!(read_frames(b"20 <13>1 x").is_err());
+        assert!(read_frames(b"2x <13>1 x").is_err());
+    }
+
+    #[test]
+    fn test_listen() {
+        let (tx, rx) = mpsc::sync_channel(10);
+        let timeout = Duration::from_secs(5);
+
+        let udp_addr = listen_udp("127.0.0.1:0", tx.clone()).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"<13>hi", udp_addr).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), b"<13>hi".to_vec());
+
+        let tcp_addr = listen_tcp("127.0.0.1:0", tx).unwrap();
+        let mut stream = TcpStream::connect(tcp_addr).unwrap();
+        stream.write_all(b"5 hello6 world!").unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), b"hello".to_vec());
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), b"world!".to_vec());
+    }
+}
@@ -0,0 +1,386 @@
+//! Follows a log file like `tail -F`, but tracks the byte offset of the last complete line read so
+//! that ingestion can resume where it left off. Rotation is detected when the path refers to a
+//! new inode (logrotate's default of renaming the file) or when the file shrinks below the offset
+//! (logrotate's `copytruncate`). A renamed file is read until nginx has stopped writing to it
+//! for a while before switching to the new one, as nginx keeps writing to the renamed file until
+//! it is told to reopen its logs. Rotation is detected through inodes, which are only exposed on
+//! unix, so files can't be followed elsewhere.
+
+use diesel::prelude::*;
+use rrinlog_core::models::Checkpoint;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long a renamed log must go without new lines before the new log is read instead
+const ROTATION_GRACE: Duration = Duration::from_secs(10);
+
+pub struct Tailer {
+    path: PathBuf,
+    reader: BufReader<File>,
+    inode: u64,
+
+    /// Offset just past the last complete line read
+    offset: u64,
+
+    /// Offset of the first line returned by the last `read_lines`
+    read_from: u64,
+
+    /// A line that has been partially written to the file
+    partial: Vec<u8>,
+
+    /// When the file was last seen active after the path was found to refer to a new file
+    rotated: Option<Instant>,
+    grace: Duration,
+}
+
+impl Tailer {
+    /// Opens the file at the position recorded in the checkpoint. Without a checkpoint, only lines
+    /// written from now on are read. If the file was rotated while we weren't looking, the rest of
+    /// the rotated file is read first, provided it is still in the same directory.
+    pub fn open(path: &Path, checkpoint: Option<&Checkpoint>) -> io::Result<Tailer> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        let cp = match checkpoint {
+            Some(cp) => cp,
+            None => return Tailer::from_file(path, file, meta.len()),
+        };
+
+        let (inode, offset) = (cp.inode as u64, cp.byte_offset as u64);
+        if file_id(&meta)? == inode {
+            // A file that has shrunk was truncated by copytruncate
+            let offset = if meta.len() < offset { 0 } else { offset };
+            return Tailer::from_file(path, file, offset);
+        }
+
+        match find_rotated(path, inode)? {
+            Some(rotated) => {
+                info!(
+                    "resuming {} from rotated {}",
+                    path.display(),
+                    rotated.display()
+                );
+                let mut tailer = Tailer::from_file(path, File::open(rotated)?, offset)?;
+                tailer.inode = inode;
+                Ok(tailer)
+            }
+            None => {
+                warn!(
+                    "unable to find the rotated {}, so the lines after byte {} are lost",
+                    path.display(),
+                    offset
+                );
+                Tailer::from_file(path, file, 0)
+            }
+        }
+    }
+
+    fn from_file(path: &Path, mut file: File, offset: u64) -> io::Result<Tailer> {
+        let inode = file_id(&file.metadata()?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Tailer {
+            path: path.to_path_buf(),
+            reader: BufReader::new(file),
+            inode,
+            offset,
+            read_from: offset,
+            partial: Vec::new(),
+            rotated: None,
+            grace: ROTATION_GRACE,
+        })
+    }
+
+    /// Reads up to `max` complete lines. A line without a trailing newline is still being written,
+    /// so it is held back until the rest of it arrives.
+    pub fn read_lines(&mut self, max: usize) -> io::Result<Vec<Vec<u8>>> {
+        self.read_from = self.offset;
+        let mut lines = Vec::new();
+        while lines.len() < max {
+            let read = self.reader.read_until(b'\n', &mut self.partial)?;
+            if read == 0 || !self.partial.ends_with(b"\n") {
+                break;
+            }
+
+            self.offset += self.partial.len() as u64;
+            lines.push(mem::take(&mut self.partial));
+        }
+
+        if !lines.is_empty() && self.rotated.is_some() {
+            self.rotated = Some(Instant::now());
+        }
+
+        Ok(lines)
+    }
+
+    /// Moves back to the start of the lines returned by the last `read_lines`, so that they are
+    /// read again when they couldn't be stored
+    pub fn rewind(&mut self) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(self.read_from))?;
+        self.offset = self.read_from;
+        self.partial.clear();
+        Ok(())
+    }
+
+    /// Switches to the new file once the log has been rotated and the renamed file has been idle
+    /// for the grace period. This should only be called once `read_lines` has caught up to the end
+    /// of the file, else the rest of a renamed file is lost.
+    pub fn check_rotation(&mut self) -> io::Result<()> {
+        let meta = match fs::metadata(&self.path) {
+            Ok(meta) => meta,
+
+            // The log has been renamed, but the new one hasn't been created yet
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if file_id(&meta)? != self.inode {
+            let since = match self.rotated {
+                Some(since) => since,
+                None => {
+                    info!("{} has been rotated", self.path.display());
+                    let now = Instant::now();
+                    self.rotated = Some(now);
+                    now
+                }
+            };
+
+            if since.elapsed() < self.grace {
+                return Ok(());
+            }
+
+            if !self.partial.is_empty() {
+                warn!("discarding incomplete last line of the rotated log");
+            }
+
+            let grace = self.grace;
+            *self = Tailer::from_file(&self.path, File::open(&self.path)?, 0)?;
+            self.grace = grace;
+        } else if meta.len() < self.offset + self.partial.len() as u64 {
+            info!("{} has been truncated", self.path.display());
+            self.reader.seek(SeekFrom::Start(0))?;
+            self.offset = 0;
+            self.partial.clear();
+        }
+
+        Ok(())
+    }
+
+    /// The checkpoint to persist once the lines read so far are stored
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            path: checkpoint_key(&self.path),
+            inode: self.inode as i64,
+            byte_offset: self.offset as i64,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> io::Result<u64> {
+    Ok(meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "files can only be followed on unix",
+    ))
+}
+
+fn checkpoint_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+pub fn load_checkpoint(conn: &SqliteConnection, path: &Path) -> QueryResult<Option<Checkpoint>> {
+    use rrinlog_core::schema::checkpoints;
+
+    checkpoints::table
+        .find(checkpoint_key(path))
+        .first(conn)
+        .optional()
+}
+
+/// Looks for the file with the given inode next to the log (eg: `access.log.1`)
+fn find_rotated(path: &Path, inode: u64) -> io::Result<Option<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if file_id(&entry.metadata()?)? == inode {
+            return Ok(Some(entry.path()));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use diesel::connection::SimpleConnection;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn append(path: &Path, data: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    fn read_all(tailer: &mut Tailer) -> Vec<String> {
+        tailer
+            .read_lines(100)
+            .unwrap()
+            .into_iter()
+            .map(|x| String::from_utf8(x).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_tail_partial_lines() {
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let path = dir.path().join("access.log");
+        append(&path, "old\n");
+
+        let mut tailer = Tailer::open(&path, None).unwrap();
+        assert!(read_all(&mut tailer).is_empty());
+
+        append(&path, "a\nb");
+        assert_eq!(read_all(&mut tailer), vec!["a\n"]);
+        assert_eq!(tailer.checkpoint().byte_offset, 6);
+
+        append(&path, "c\nd\n");
+        assert_eq!(tailer.read_lines(1).unwrap(), vec![b"bc\n".to_vec()]);
+        assert_eq!(read_all(&mut tailer), vec!["d\n"]);
+        assert_eq!(tailer.checkpoint().byte_offset, 11);
+    }
+
+    #[test]
+    fn test_tail_rename_rotation() {
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let path = dir.path().join("access.log");
+        append(&path, "");
+
+        let mut tailer = Tailer::open(&path, None).unwrap();
+        append(&path, "a\n");
+        fs::rename(&path, dir.path().join("access.log.1")).unwrap();
+        tailer.check_rotation().unwrap();
+
+        append(&dir.path().join("access.log.1"), "b\n");
+        append(&path, "c\n");
+        assert_eq!(read_all(&mut tailer), vec!["a\n", "b\n"]);
+
+        // nginx writes to the renamed file until it reopens its logs
+        tailer.check_rotation().unwrap();
+        append(&dir.path().join("access.log.1"), "d\n");
+        assert_eq!(read_all(&mut tailer), vec!["d\n"]);
+
+        tailer.grace = Duration::from_secs(0);
+        tailer.check_rotation().unwrap();
+        assert_eq!(read_all(&mut tailer), vec!["c\n"]);
+        assert_eq!(tailer.checkpoint().byte_offset, 2);
+    }
+
+    #[test]
+    fn test_tail_rewind() {
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let path = dir.path().join("access.log");
+        append(&path, "");
+
+        let mut tailer = Tailer::open(&path, None).unwrap();
+        append(&path, "a\n");
+        assert_eq!(read_all(&mut tailer), vec!["a\n"]);
+
+        append(&path, "b\nc\nd");
+        assert_eq!(read_all(&mut tailer), vec!["b\n", "c\n"]);
+        tailer.rewind().unwrap();
+        assert_eq!(tailer.checkpoint().byte_offset, 2);
+        assert_eq!(read_all(&mut tailer), vec!["b\n", "c\n"]);
+        assert_eq!(tailer.checkpoint().byte_offset, 6);
+    }
+
+    #[test]
+    fn test_tail_copytruncate() {
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let path = dir.path().join("access.log");
+        append(&path, "");
+
+        let mut tailer = Tailer::open(&path, None).unwrap();
+        append(&path, "aaaa\n");
+        assert_eq!(read_all(&mut tailer), vec!["aaaa\n"]);
+
+        File::create(&path).unwrap();
+        append(&path, "b\n");
+        tailer.check_rotation().unwrap();
+        assert_eq!(read_all(&mut tailer), vec!["b\n"]);
+    }
+
+    #[test]
+    fn test_tail_resume_from_checkpoint() {
+        let dir = tempdir::TempDir::new("rrinlog").unwrap();
+        let path = dir.path().join("access.log");
+        append(&path, "a\n");
+
+        let mut tailer = Tailer::open(&path, None).unwrap();
+        append(&path, "b\n");
+        assert_eq!(read_all(&mut tailer), vec!["b\n"]);
+        let checkpoint = tailer.checkpoint();
+
+        // Lines written while we're down are read on restart
+        append(&path, "c\n");
+        let mut tailer = Tailer::open(&path, Some(&checkpoint)).unwrap();
+        assert_eq!(read_all(&mut tailer), vec!["c\n"]);
+        let checkpoint = tailer.checkpoint();
+
+        // Including when the log was rotated in the meantime
+        append(&path, "d\n");
+        fs::rename(&path, dir.path().join("access.log.1")).unwrap();
+        append(&path, "e\n");
+        let mut tailer = Tailer::open(&path, Some(&checkpoint)).unwrap();
+        assert_eq!(read_all(&mut tailer), vec!["d\n"]);
+        tailer.grace = Duration::from_secs(0);
+        tailer.check_rotation().unwrap();
+        assert_eq!(read_all(&mut tailer), vec!["e\n"]);
+    }
+
+    #[test]
+    fn test_load_checkpoint() {
+        use rrinlog_core::schema::checkpoints;
+
+        let conn = SqliteConnection::establish(":memory:").unwrap();
+        conn.batch_execute(include_str!(
+            "../../migrations/2026-10-17-000005_checkpoints/up.sql"
+        ))
+        .unwrap();
+
+        let path = Path::new("/var/log/nginx/access.log");
+        assert_eq!(load_checkpoint(&conn, path), Ok(None));
+
+        let checkpoint = Checkpoint {
+            path: String::from("/var/log/nginx/access.log"),
+            inode: 10,
+            byte_offset: 20,
+        };
+        for _ in 0..2 {
+            diesel::replace_into(checkpoints::table)
+                .values(&checkpoint)
+                .execute(&conn)
+                .unwrap();
+        }
+        assert_eq!(load_checkpoint(&conn, path), Ok(Some(checkpoint)));
+    }
+}
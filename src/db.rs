@@ -0,0 +1,152 @@
+//! Opens the database, creating or upgrading its schema with the migrations embedded from
+//! `migrations/`, so that neither binary needs the diesel CLI to set up a database.
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::result::{ConnectionError, Error as DsError};
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel_migrations::{self, MigrationConnection, RunMigrationsError};
+
+embed_migrations!("migrations");
+
+/// Version of the latest migration in `migrations/`. A database with a later migration was
+/// written by a newer rrinlog, whose rows this version may not understand.
+pub const SCHEMA_VERSION: &str = "20261017000012";
+
+#[derive(Fail, Debug)]
+pub enum SetupError {
+    #[fail(display = "Unable to connect to database {}: {}", _0, _1)]
+    Connection(String, #[cause] ConnectionError),
+
+    #[fail(display = "Unable to set up database {}: {}", _0, _1)]
+    Query(String, #[cause] DsError),
+
+    #[fail(display = "Unable to migrate database {}: {}", _0, _1)]
+    Migration(String, #[cause] RunMigrationsError),
+
+    #[fail(
+        display = "Database {} has schema version {}, which is newer than the supported {}. Upgrade rrinlog",
+        _0, _1, _2
+    )]
+    NewerSchema(String, String, &'static str),
+}
+
+/// Connects to the database, creating it if it doesn't exist, and runs the migrations it is
+/// missing. Refuses databases that have been migrated past `SCHEMA_VERSION`.
+pub fn setup(db: &str) -> Result<SqliteConnection, SetupError> {
+    let conn =
+        SqliteConnection::establish(db).map_err(|e| SetupError::Connection(db.to_owned(), e))?;
+    let query_err = |e| SetupError::Query(db.to_owned(), e);
+
+    // Changing the journal mode is not allowed within a transaction, which is what the migrations
+    // run in. WAL mode lets the server read while lines are being inserted, and it persists in the
+    // file once set. Incremental auto vacuum, which lets pruning shrink the file, only takes effect
+    // when set before the first table is created.
+    conn.batch_execute("PRAGMA auto_vacuum=INCREMENTAL; PRAGMA journal_mode=WAL;")
+        .map_err(query_err)?;
+
+    diesel_migrations::setup_database(&conn).map_err(query_err)?;
+    if let Some(version) = conn.latest_run_migration_version().map_err(query_err)? {
+        if version.as_str() > SCHEMA_VERSION {
+            return Err(SetupError::NewerSchema(
+                db.to_owned(),
+                version,
+                SCHEMA_VERSION,
+            ));
+        }
+    }
+
+    embedded_migrations::run(&conn).map_err(|e| SetupError::Migration(db.to_owned(), e))?;
+    Ok(conn)
+}
+
+pub fn metadata(conn: &SqliteConnection, key: &str) -> QueryResult<Option<String>> {
+    use schema::metadata;
+
+    metadata::table
+        .find(key)
+        .select(metadata::value)
+        .first(conn)
+        .optional()
+}
+
+/// Stores the value under the key. The row is left untouched when the value is unchanged, so that
+/// this can be called with every batch of rows without writing to the db.
+pub fn set_metadata(conn: &SqliteConnection, key: &str, value: &str) -> QueryResult<()> {
+    sql_query(
+        r#"
+INSERT INTO metadata (key, value)
+VALUES      (?, ?)
+ON CONFLICT (key) DO UPDATE
+SET    value = excluded.value
+WHERE  value <> excluded.value
+"#,
+    )
+    .bind::<Text, _>(key)
+    .bind::<Text, _>(value)
+    .execute(conn)
+    .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use diesel_migrations::version_from_path;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_schema_version_is_latest_migration() {
+        let latest = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations"))
+            .unwrap()
+            .map(|entry| version_from_path(&entry.unwrap().path()).unwrap())
+            .max()
+            .unwrap();
+        assert_eq!(latest, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_setup() {
+        let conn = setup(":memory:").unwrap();
+        let versions = conn.previously_run_migration_versions().unwrap();
+        assert!(versions.contains(SCHEMA_VERSION));
+        conn.batch_execute("SELECT ri, fingerprint FROM logs")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_metadata() {
+        let conn = setup(":memory:").unwrap();
+        assert_eq!(metadata(&conn, "ip_anonymization").unwrap(), None);
+        set_metadata(&conn, "ip_anonymization", "none").unwrap();
+        set_metadata(&conn, "ip_anonymization", "truncate").unwrap();
+        assert_eq!(
+            metadata(&conn, "ip_anonymization").unwrap(),
+            Some(String::from("truncate"))
+        );
+    }
+
+    #[test]
+    fn test_setup_refuses_newer_schema() {
+        let dir = tempdir::TempDir::new("rrinlog-db").unwrap();
+        let path = dir.path().join("newer.db");
+        let db = path.to_str().unwrap();
+
+        let conn = setup(db).unwrap();
+        conn.insert_new_migration("99991231000000").unwrap();
+        drop(conn);
+
+        match setup(db) {
+            Err(SetupError::NewerSchema(_, version, _)) => assert_eq!(version, "99991231000000"),
+            x => panic!("expected a newer schema error: {:?}", x.map(|_| ())),
+        }
+
+        // Setting up twice is a no-op
+        let _ = fs::remove_file(&path);
+        setup(db).unwrap();
+        setup(db).unwrap();
+    }
+}
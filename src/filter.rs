@@ -0,0 +1,509 @@
+//! Rules that decide which parsed lines are stored. A rule either allows or denies the lines it
+//! matches, and the first rule that matches a line decides its fate, like nginx's `allow` and
+//! `deny` directives. Lines that no rule matches are stored. Rules are written as
+//! `<allow|deny> <field> <pattern>`:
+//!
+//! ```text
+//! allow ip 10.0.0.5
+//! deny ip 10.0.0.0/8
+//! deny path ^/health$
+//! deny user_agent (?i)bot
+//! deny host ^staging\.
+//! deny status 400-499
+//! ```
+
+use models::NewLog;
+use regex::Regex;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Fail, Debug, PartialEq, Clone)]
+pub enum FilterError {
+    #[fail(
+        display = "Expected a rule of the form `<allow|deny> <field> <pattern>`: `{}`",
+        _0
+    )]
+    InvalidRule(String),
+
+    #[fail(display = "Expected `allow` or `deny` but found `{}`", _0)]
+    InvalidAction(String),
+
+    #[fail(
+        display = "Expected a field of `ip`, `path`, `user_agent`, `host`, or `status` but found `{}`",
+        _0
+    )]
+    InvalidField(String),
+
+    #[fail(
+        display = "Expected an ip address or CIDR block (eg: 10.0.0.0/8): `{}`",
+        _0
+    )]
+    InvalidCidr(String),
+
+    #[fail(display = "Invalid regex `{}`: {}", _0, _1)]
+    InvalidRegex(String, String),
+
+    #[fail(
+        display = "Expected a status (eg: 404), comparison (eg: >=500), or range (eg: 400-499): `{}`",
+        _0
+    )]
+    InvalidStatus(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// A block of ip addresses. IPv4 and IPv6 blocks never match each other's addresses, except for
+/// IPv4-mapped IPv6 addresses (eg: `::ffff:10.0.0.1`), which are treated as the IPv4 address they
+/// map, as that is how a dual stack socket reports IPv4 clients.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        match (self.addr, addr) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask(self.addr, self.prefix) == mask(addr, self.prefix)
+            }
+            _ => false,
+        }
+    }
+
+    /// The first address of the block (eg: `10.0.0.0` of `10.1.2.3/8`)
+    pub fn network(&self) -> IpAddr {
+        mask(self.addr, self.prefix)
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+}
+
+/// Zeroes the bits of the address after the prefix
+pub fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = FilterError;
+
+    /// Parses `10.0.0.0/8` or `2001:db8::/32`. An address without a prefix matches only itself.
+    fn from_str(s: &str) -> Result<Cidr, FilterError> {
+        let err = || FilterError::InvalidCidr(String::from(s));
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| err())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|_| err())?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(err());
+        }
+
+        // A block of IPv4-mapped addresses is the IPv4 block
+        match addr.to_canonical() {
+            IpAddr::V4(ip) if addr.is_ipv6() && prefix >= 96 => Ok(Cidr {
+                addr: IpAddr::V4(ip),
+                prefix: prefix - 96,
+            }),
+            _ => Ok(Cidr { addr, prefix }),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Inclusive range of statuses
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StatusRange {
+    min: i32,
+    max: i32,
+}
+
+impl StatusRange {
+    pub fn contains(&self, status: i32) -> bool {
+        self.min <= status && status <= self.max
+    }
+}
+
+impl FromStr for StatusRange {
+    type Err = FilterError;
+
+    /// Parses `404`, `400-499`, `>=500`, `>499`, `<=399`, or `<400`
+    fn from_str(s: &str) -> Result<StatusRange, FilterError> {
+        let err = || FilterError::InvalidStatus(String::from(s));
+        let num = |x: &str| x.trim().parse::<i32>().map_err(|_| err());
+        let (min, max) = if let Some(x) = s.strip_prefix(">=") {
+            (num(x)?, i32::MAX)
+        } else if let Some(x) = s.strip_prefix('>') {
+            (num(x)?.checked_add(1).ok_or_else(err)?, i32::MAX)
+        } else if let Some(x) = s.strip_prefix("<=") {
+            (i32::MIN, num(x)?)
+        } else if let Some(x) = s.strip_prefix('<') {
+            (i32::MIN, num(x)?.checked_sub(1).ok_or_else(err)?)
+        } else if let Some(ind) = s.find('-') {
+            (num(&s[..ind])?, num(&s[ind + 1..])?)
+        } else {
+            let status = num(s)?;
+            (status, status)
+        };
+
+        Ok(StatusRange { min, max })
+    }
+}
+
+impl fmt::Display for StatusRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.min, self.max) {
+            (min, max) if min == max => write!(f, "{}", min),
+            (min, i32::MAX) => write!(f, ">={}", min),
+            (i32::MIN, max) => write!(f, "<={}", max),
+            (min, max) => write!(f, "{}-{}", min, max),
+        }
+    }
+}
+
+/// The remote addresses matched by an `ip` rule
+#[derive(Debug, PartialEq, Clone)]
+pub enum Address {
+    Block(Cidr),
+
+    /// An address that isn't an ip (eg: `unix:` when nginx listens on a unix socket), which is
+    /// matched as is
+    Other(String),
+}
+
+impl Address {
+    pub fn matches(&self, addr: &str) -> bool {
+        match *self {
+            Address::Block(ref cidr) => addr.parse::<IpAddr>().is_ok_and(|x| cidr.contains(&x)),
+            Address::Other(ref other) => addr == other,
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = FilterError;
+
+    /// Values made only of the characters of an ip or CIDR block must be one, so that a mistyped
+    /// block is reported rather than never matching
+    fn from_str(s: &str) -> Result<Address, FilterError> {
+        let ip_like = s
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() || c == '.' || c == ':' || c == '/');
+        match s.parse() {
+            Ok(cidr) => Ok(Address::Block(cidr)),
+            Err(e) if ip_like => Err(e),
+            Err(_) => Ok(Address::Other(String::from(s))),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Block(ref cidr) => cidr.fmt(f),
+            Address::Other(ref other) => f.write_str(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Ip(Address),
+    Path(Regex),
+    UserAgent(Regex),
+    Host(Regex),
+    Status(StatusRange),
+}
+
+impl Matcher {
+    pub fn matches(&self, log: &NewLog) -> bool {
+        let regex = |re: &Regex, value: Option<&str>| value.is_some_and(|x| re.is_match(x));
+        match *self {
+            Matcher::Ip(ref addr) => log.remote_addr.as_ref().is_some_and(|x| addr.matches(x)),
+            Matcher::Path(ref re) => regex(re, log.path.as_deref()),
+            Matcher::UserAgent(ref re) => regex(re, log.user_agent.as_deref()),
+            Matcher::Host(ref re) => re.is_match(&log.host),
+            Matcher::Status(ref range) => log.status.is_some_and(|x| range.contains(x)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub action: Action,
+    pub matcher: Matcher,
+}
+
+impl Rule {
+    pub fn deny(matcher: Matcher) -> Rule {
+        Rule {
+            action: Action::Deny,
+            matcher,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Rule, FilterError> {
+        let (action, rest) = split_word(s.trim());
+        let (field, pattern) = split_word(rest);
+        if pattern.is_empty() {
+            return Err(FilterError::InvalidRule(String::from(s)));
+        }
+
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return Err(FilterError::InvalidAction(String::from(action))),
+        };
+
+        let regex = |pattern: &str| {
+            Regex::new(pattern)
+                .map_err(|e| FilterError::InvalidRegex(String::from(pattern), e.to_string()))
+        };
+
+        let matcher = match field {
+            "ip" => Matcher::Ip(pattern.parse()?),
+            "path" => Matcher::Path(regex(pattern)?),
+            "user_agent" => Matcher::UserAgent(regex(pattern)?),
+            "host" => Matcher::Host(regex(pattern)?),
+            "status" => Matcher::Status(pattern.parse()?),
+            _ => return Err(FilterError::InvalidField(String::from(field))),
+        };
+
+        Ok(Rule { action, matcher })
+    }
+}
+
+/// Splits off the first word, trimming the whitespace that follows it
+fn split_word(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(ind) => (&s[..ind], s[ind..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+
+        match self.matcher {
+            Matcher::Ip(ref addr) => write!(f, "{} ip {}", action, addr),
+            Matcher::Path(ref re) => write!(f, "{} path {}", action, re),
+            Matcher::UserAgent(ref re) => write!(f, "{} user_agent {}", action, re),
+            Matcher::Host(ref re) => write!(f, "{} host {}", action, re),
+            Matcher::Status(ref range) => write!(f, "{} status {}", action, range),
+        }
+    }
+}
+
+/// An ordered list of rules
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+}
+
+impl Filter {
+    pub fn new(rules: Vec<Rule>) -> Filter {
+        Filter { rules }
+    }
+
+    /// Returns true if the first rule that matches denies the line
+    pub fn is_denied(&self, log: &NewLog) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matcher.matches(log))
+            .is_some_and(|rule| rule.action == Action::Deny)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn log<'a>(remote_addr: &'a str, path: &'a str, status: i32) -> NewLog<'a> {
+        NewLog {
+            remote_addr: Some(Cow::Borrowed(remote_addr)),
+            path: Some(Cow::Borrowed(path)),
+            user_agent: Some(Cow::Borrowed("curl/7.55.1")),
+            host: Cow::Borrowed("example.com"),
+            status: Some(status),
+            ..NewLog::default()
+        }
+    }
+
+    fn filter(rules: &[&str]) -> Filter {
+        Filter::new(rules.iter().map(|x| x.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains(&"2001:db9::1".parse().unwrap()));
+
+        let cidr: Cidr = "127.0.0.1".parse().unwrap();
+        assert_eq!(cidr.to_string(), "127.0.0.1/32");
+        assert!(cidr.contains(&"127.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"127.0.0.2".parse().unwrap()));
+
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(&"8.8.8.8".parse().unwrap()));
+
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.network(), "10.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(cidr.prefix(), 8);
+        assert_eq!(
+            mask("2001:db8:1::1".parse().unwrap(), 32),
+            "2001:db8::".parse::<IpAddr>().unwrap()
+        );
+
+        assert_eq!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(FilterError::InvalidCidr(String::from("10.0.0.0/33")))
+        );
+        assert!("localhost".parse::<Cidr>().is_err());
+
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"::ffff:11.0.0.1".parse().unwrap()));
+
+        let cidr: Cidr = "::ffff:10.1.2.3/104".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.1.2.3/8");
+        assert_eq!(cidr.network(), "10.0.0.0".parse::<IpAddr>().unwrap());
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_address() {
+        let addr: Address = "unix:".parse().unwrap();
+        assert_eq!(addr, Address::Other(String::from("unix:")));
+        assert!(addr.matches("unix:"));
+        assert!(!addr.matches("10.0.0.1"));
+
+        let addr: Address = "10.0.0.0/8".parse().unwrap();
+        assert!(addr.matches("10.0.0.1"));
+        assert!(!addr.matches("unix:"));
+
+        for invalid in &["", "10.0.0.0/33", "10.0.0.256", "fd00::/129"] {
+            assert!(invalid.parse::<Address>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_status_range() {
+        let parse = |x: &str| x.parse::<StatusRange>().unwrap();
+        assert!(parse("404").contains(404));
+        assert!(!parse("404").contains(403));
+        assert!(parse("400-499").contains(451));
+        assert!(!parse("400-499").contains(500));
+        assert!(parse(">=500").contains(500));
+        assert!(!parse(">500").contains(500));
+        assert!(parse("<400").contains(399));
+        assert!(!parse("<=399").contains(400));
+        assert_eq!(parse(">499").to_string(), ">=500");
+        assert!("4xx".parse::<StatusRange>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule: Rule = "deny  path ^/health$".parse().unwrap();
+        assert_eq!(rule.to_string(), "deny path ^/health$");
+
+        let rule: Rule = "allow user_agent Mozilla (compatible)".parse().unwrap();
+        assert_eq!(rule.to_string(), "allow user_agent Mozilla (compatible)");
+
+        assert_eq!(
+            "deny ip".parse::<Rule>().unwrap_err(),
+            FilterError::InvalidRule(String::from("deny ip"))
+        );
+        assert_eq!(
+            "drop ip 10.0.0.1".parse::<Rule>().unwrap_err(),
+            FilterError::InvalidAction(String::from("drop"))
+        );
+        assert_eq!(
+            "deny referer x".parse::<Rule>().unwrap_err(),
+            FilterError::InvalidField(String::from("referer"))
+        );
+        assert!("deny path (".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = filter(&[
+            "allow ip 10.0.0.5",
+            "deny ip 10.0.0.0/8",
+            "deny ip fd00::/8",
+            "deny path ^/health$",
+            "deny user_agent ^curl/",
+            "allow status >=500",
+            "deny host ^example\\.com$",
+        ]);
+
+        assert!(!filter.is_denied(&log("10.0.0.5", "/", 200)));
+        assert!(filter.is_denied(&log("10.0.0.6", "/", 200)));
+        assert!(filter.is_denied(&log("fd12::1", "/", 200)));
+        assert!(!filter.is_denied(&NewLog {
+            user_agent: None,
+            ..log("192.168.0.1", "/", 500)
+        }));
+        assert!(filter.is_denied(&NewLog {
+            user_agent: None,
+            ..log("192.168.0.1", "/health", 200)
+        }));
+        assert!(filter.is_denied(&log("192.168.0.1", "/", 500)));
+        assert!(filter.is_denied(&NewLog {
+            user_agent: None,
+            ..log("192.168.0.1", "/", 200)
+        }));
+
+        // Lines that no rule matches are kept, including unparseable addresses
+        assert!(!Filter::default().is_denied(&log("10.0.0.6", "/", 200)));
+        assert!(!filter.is_denied(&NewLog {
+            user_agent: None,
+            host: Cow::Borrowed("a.com"),
+            ..log("unix:", "/", 200)
+        }));
+
+        let filter = self::filter(&["deny ip unix:"]);
+        assert!(filter.is_denied(&log("unix:", "/", 200)));
+        assert!(!filter.is_denied(&log("10.0.0.1", "/", 200)));
+        assert_eq!(filter.rules[0].to_string(), "deny ip unix:");
+    }
+}
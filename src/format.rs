@@ -0,0 +1,649 @@
+//! Compiles nginx `log_format` definitions into parsers that fill `NewLog`. A format is broken
+//! down into a sequence of literal text and variables. When parsing, a variable consumes input up
+//! until the literal text that follows it, so `"$request" $status` captures everything between
+//! the quotes as the request. A variable at the end of the format consumes the rest of the line,
+//! except for variables that never contain whitespace (eg: `$body_bytes_sent`), which consume a
+//! single value. Fields appended after it (eg: the referer and user agent of a combined line read
+//! with a common format) are ignored.
+
+use chrono::prelude::*;
+use models::NewLog;
+use parser::{parse_date, LogParser, ParseError};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Fail, Debug, PartialEq, Clone)]
+pub enum FormatError {
+    #[fail(
+        display = "Variables `${}` and `${}` must be separated by text",
+        _0, _1
+    )]
+    AdjacentVariables(String, String),
+
+    #[fail(display = "Expected a variable name after `$` at position {}", _0)]
+    EmptyVariable(usize),
+
+    #[fail(display = "Unterminated `${{` variable at position {}", _0)]
+    UnterminatedVariable(usize),
+
+    #[fail(display = "Unterminated quoted string in log_format directive")]
+    UnterminatedQuote,
+
+    #[fail(display = "log_format directive is missing a format string")]
+    MissingFormat,
+
+    #[fail(display = "Expected a mapping of the form `key=variable`: `{}`", _0)]
+    InvalidMapping(String),
+
+    #[fail(display = "Format must contain `$time_local`, `$time_iso8601`, or `$msec`")]
+    MissingTime,
+}
+
+/// The `NewLog` column that an nginx variable is stored in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Field {
+    RemoteAddr,
+    RemoteUser,
+    TimeLocal,
+    TimeIso8601,
+    Msec,
+    Request,
+    Method,
+    RequestUri,
+    Protocol,
+    Status,
+    BodyBytesSent,
+    Referer,
+    UserAgent,
+    Host,
+    RequestTime,
+    UpstreamResponseTime,
+    UpstreamAddr,
+    UpstreamStatus,
+
+    /// A variable that is matched but not stored (eg: `$http_x_forwarded_for`)
+    Ignored,
+}
+
+impl Field {
+    /// Maps an nginx variable name (without the `$`) to its column
+    pub fn from_variable(name: &str) -> Field {
+        match name {
+            "remote_addr" => Field::RemoteAddr,
+            "remote_user" => Field::RemoteUser,
+            "time_local" => Field::TimeLocal,
+            "time_iso8601" => Field::TimeIso8601,
+            "msec" => Field::Msec,
+            "request" => Field::Request,
+            "request_method" => Field::Method,
+            "request_uri" => Field::RequestUri,
+            "server_protocol" => Field::Protocol,
+            "status" => Field::Status,
+            "body_bytes_sent" => Field::BodyBytesSent,
+            "http_referer" => Field::Referer,
+            "http_user_agent" => Field::UserAgent,
+            "host" | "http_host" | "server_name" => Field::Host,
+            "request_time" => Field::RequestTime,
+            "upstream_response_time" => Field::UpstreamResponseTime,
+            "upstream_addr" => Field::UpstreamAddr,
+            "upstream_status" => Field::UpstreamStatus,
+            _ => Field::Ignored,
+        }
+    }
+
+    /// Whether the values of the variable never contain whitespace
+    fn is_token(self) -> bool {
+        match self {
+            Field::RemoteAddr
+            | Field::RemoteUser
+            | Field::TimeIso8601
+            | Field::Msec
+            | Field::Method
+            | Field::RequestUri
+            | Field::Protocol
+            | Field::Status
+            | Field::BodyBytesSent
+            | Field::Host
+            | Field::RequestTime
+            | Field::UpstreamStatus => true,
+            Field::TimeLocal
+            | Field::Request
+            | Field::Referer
+            | Field::UserAgent
+            | Field::UpstreamResponseTime
+            | Field::UpstreamAddr
+            | Field::Ignored => false,
+        }
+    }
+
+    /// The nginx variable (without the `$`) that is stored in the column
+    pub fn variable(self) -> Option<&'static str> {
+        match self {
+            Field::RemoteAddr => Some("remote_addr"),
+            Field::RemoteUser => Some("remote_user"),
+            Field::TimeLocal => Some("time_local"),
+            Field::TimeIso8601 => Some("time_iso8601"),
+            Field::Msec => Some("msec"),
+            Field::Request => Some("request"),
+            Field::Method => Some("request_method"),
+            Field::RequestUri => Some("request_uri"),
+            Field::Protocol => Some("server_protocol"),
+            Field::Status => Some("status"),
+            Field::BodyBytesSent => Some("body_bytes_sent"),
+            Field::Referer => Some("http_referer"),
+            Field::UserAgent => Some("http_user_agent"),
+            Field::Host => Some("host"),
+            Field::RequestTime => Some("request_time"),
+            Field::UpstreamResponseTime => Some("upstream_response_time"),
+            Field::UpstreamAddr => Some("upstream_addr"),
+            Field::UpstreamStatus => Some("upstream_status"),
+            Field::Ignored => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Literal(String),
+    Variable(String, Field),
+}
+
+/// Predefined formats that can be referenced by name instead of by format string
+pub static PRESETS: &[(&str, &str)] = &[
+    (
+        "vhost",
+        r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" "$host""#,
+    ),
+    (
+        "combined",
+        r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#,
+    ),
+    (
+        "main",
+        r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent" "$http_x_forwarded_for""#,
+    ),
+    // Apache's `%h %l %u %t "%r" %>s %b`. The identd field (`%l`) is almost always `-`, but it is
+    // matched as a variable in case `IdentityCheck` is enabled
+    (
+        "apache_common",
+        r#"$remote_addr $remote_ident $remote_user [$time_local] "$request" $status $body_bytes_sent"#,
+    ),
+    (
+        "apache_combined",
+        r#"$remote_addr $remote_ident $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#,
+    ),
+];
+
+/// A compiled nginx `log_format`
+#[derive(Debug, PartialEq, Clone)]
+pub struct LogFormat {
+    tokens: Vec<Token>,
+    default_host: Option<String>,
+}
+
+impl LogFormat {
+    /// Compiles either a bare format string (`$remote_addr - $remote_user ...`) or a complete
+    /// `log_format name '...' '...';` directive copied out of an nginx config.
+    pub fn compile(definition: &str) -> Result<LogFormat, FormatError> {
+        let definition = definition.trim();
+        let format = match definition.strip_prefix("log_format") {
+            Some(args) => directive_format(args)?,
+            None => String::from(definition),
+        };
+
+        let tokens = tokenize(&format)?;
+        let has_time = tokens.iter().any(|x| {
+            matches!(
+                *x,
+                Token::Variable(_, Field::TimeLocal)
+                    | Token::Variable(_, Field::TimeIso8601)
+                    | Token::Variable(_, Field::Msec)
+            )
+        });
+
+        if !has_time {
+            return Err(FormatError::MissingTime);
+        }
+
+        Ok(LogFormat {
+            tokens,
+            default_host: None,
+        })
+    }
+
+    /// Looks up one of the `PRESETS` by name
+    pub fn preset(name: &str) -> Option<LogFormat> {
+        PRESETS
+            .iter()
+            .find(|&&(preset, _)| preset == name)
+            .map(|&(_, format)| LogFormat::compile(format).expect("preset to compile"))
+    }
+
+    /// Host to store for formats that don't log `$host`, `$http_host`, or `$server_name`
+    pub fn with_default_host(mut self, host: &str) -> LogFormat {
+        self.default_host = Some(String::from(host));
+        self
+    }
+
+    pub fn parse<'a>(&'a self, text: &'a str) -> Result<NewLog<'a>, ParseError> {
+        let mut log = NewLog {
+            host: Cow::Borrowed(self.default_host.as_deref().unwrap_or("-")),
+            ..NewLog::default()
+        };
+
+        let mut pos = 0;
+        let mut tokens = self.tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            let rest = &text[pos..];
+            match *token {
+                Token::Literal(ref lit) => {
+                    // Variables consume up until their following literal, so only a literal at
+                    // the start of the format can fail to match. Blame the variable that follows.
+                    if !rest.starts_with(lit.as_str()) {
+                        let field = match tokens.peek() {
+                            Some(Token::Variable(name, _)) => format!("${}", name),
+                            _ => String::from("line"),
+                        };
+                        let expected = format!("`{}`", lit);
+                        return Err(ParseError::unexpected(text, &field, pos, &expected));
+                    }
+                    pos += lit.len();
+                }
+                Token::Variable(ref name, field) => {
+                    // A variable ends where the next literal begins. Tokenizing guarantees that
+                    // a variable is never directly followed by another variable
+                    let end = match tokens.peek() {
+                        Some(Token::Literal(lit)) => rest.find(lit.as_str()).ok_or_else(|| {
+                            let expected = format!("`{}` after the value", lit);
+                            ParseError::unexpected(text, &format!("${}", name), pos, &expected)
+                        })?,
+                        _ if field.is_token() => {
+                            rest.find(char::is_whitespace).unwrap_or(rest.len())
+                        }
+                        _ => rest.len(),
+                    };
+
+                    assign(&mut log, field, Cow::Borrowed(&rest[..end])).map_err(|expected| {
+                        ParseError::unexpected(text, &format!("${}", name), pos, expected)
+                    })?;
+                    pos += end;
+                }
+            }
+        }
+
+        Ok(log)
+    }
+}
+
+impl LogParser for LogFormat {
+    fn parse<'a>(&'a self, line: &'a str) -> Result<NewLog<'a>, ParseError> {
+        LogFormat::parse(self, line)
+    }
+
+    fn describe(&self) -> String {
+        self.to_string()
+    }
+
+    fn has_host(&self) -> bool {
+        self.default_host.is_some()
+            || self
+                .tokens
+                .iter()
+                .any(|x| matches!(*x, Token::Variable(_, Field::Host)))
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = FormatError;
+
+    /// Resolves a preset name, falling back to compiling the text as a format
+    fn from_str(s: &str) -> Result<LogFormat, FormatError> {
+        LogFormat::preset(s).map_or_else(|| LogFormat::compile(s), Ok)
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in &self.tokens {
+            match *token {
+                Token::Literal(ref lit) => write!(f, "{}", lit)?,
+                Token::Variable(ref name, _) => write!(f, "${}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stores the value of a variable in the corresponding column. Owned values (eg: unescaped JSON
+/// strings) stay owned, while borrowed values continue to borrow from the line. An invalid value
+/// is rejected with a description of what was expected instead.
+pub(crate) fn assign<'a>(
+    log: &mut NewLog<'a>,
+    field: Field,
+    value: Cow<'a, str>,
+) -> Result<(), &'static str> {
+    match field {
+        Field::RemoteAddr => log.remote_addr = Some(value),
+        Field::RemoteUser => log.remote_user = Some(value),
+        Field::TimeLocal => {
+            log.epoch =
+                parse_date(&value).map_err(|_| "a timestamp like `10/Oct/2000:13:55:36 -0700`")?
+        }
+        Field::TimeIso8601 => {
+            log.epoch = DateTime::parse_from_rfc3339(&value)
+                .map(|x| x.timestamp())
+                .map_err(|_| "a timestamp like `2000-10-10T13:55:36-07:00`")?
+        }
+        Field::Msec => {
+            log.epoch = value
+                .parse::<f64>()
+                .map(|x| x as i64)
+                .map_err(|_| "seconds since the epoch")?
+        }
+        Field::Request => log.set_request(value),
+        Field::Method => log.method = Some(value),
+        Field::RequestUri => log.set_target(value),
+        Field::Protocol => {
+            log.version = Some(match value {
+                Cow::Borrowed(x) => Cow::Borrowed(x.trim_start_matches("HTTP/")),
+                Cow::Owned(x) => Cow::Owned(String::from(x.trim_start_matches("HTTP/"))),
+            })
+        }
+        Field::Status => log.status = number(&value)?,
+        Field::BodyBytesSent => log.body_bytes_sent = number(&value)?,
+        Field::Referer => log.referer = Some(value),
+        Field::UserAgent => log.user_agent = Some(value),
+        Field::Host => log.host = value,
+        Field::RequestTime => log.request_time = value.parse::<f64>().ok(),
+        Field::UpstreamResponseTime => log.upstream_response_time = upstream_time(&value),
+        Field::UpstreamAddr => log.upstream_addr = Some(value),
+        Field::UpstreamStatus => log.upstream_status = upstream_status(&value),
+        Field::Ignored => {}
+    }
+
+    Ok(())
+}
+
+/// nginx logs `-` for a number it doesn't have, anything else has to be a number
+pub(crate) fn number(value: &str) -> Result<Option<i32>, &'static str> {
+    match value {
+        "-" => Ok(None),
+        _ => value.parse::<i32>().map(Some).map_err(|_| "a number"),
+    }
+}
+
+/// nginx separates the values of each upstream contacted with `, ` (and with ` : ` across internal
+/// redirects)
+fn upstream_values(value: &str) -> impl DoubleEndedIterator<Item = &str> {
+    value.split([',', ':']).map(str::trim)
+}
+
+/// Sums the response times of every upstream contacted, which is the total time spent waiting on
+/// upstreams. Upstreams that weren't reached are logged as `-` and ignored.
+fn upstream_time(value: &str) -> Option<f64> {
+    upstream_values(value)
+        .filter_map(|x| x.parse::<f64>().ok())
+        .fold(None, |acc, x| Some(acc.unwrap_or(0.0) + x))
+}
+
+/// The last upstream contacted is the one whose response was sent to the client
+fn upstream_status(value: &str) -> Option<i32> {
+    upstream_values(value).last()?.parse::<i32>().ok()
+}
+
+fn is_variable_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn tokenize(format: &str) -> Result<Vec<Token>, FormatError> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        if c != '$' {
+            literal.push(c);
+            continue;
+        }
+
+        let braced = chars.peek().map(|&(_, c)| c == '{').unwrap_or(false);
+        if braced {
+            chars.next();
+        }
+
+        let mut name = String::new();
+        while let Some(&(_, c)) = chars.peek() {
+            if !is_variable_char(c) {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+
+        if braced && chars.next().map(|(_, c)| c) != Some('}') {
+            return Err(FormatError::UnterminatedVariable(pos));
+        }
+
+        if name.is_empty() {
+            return Err(FormatError::EmptyVariable(pos));
+        }
+
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal.clone()));
+            literal.clear();
+        } else if let Some(Token::Variable(prev, _)) = tokens.last() {
+            return Err(FormatError::AdjacentVariables(prev.clone(), name));
+        }
+
+        let field = Field::from_variable(&name);
+        tokens.push(Token::Variable(name, field));
+    }
+
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+
+    Ok(tokens)
+}
+
+/// Extracts the format string from the arguments of a `log_format` directive. The arguments are
+/// the format's name, an optional `escape=` parameter, and one or more strings that nginx
+/// concatenates together.
+fn directive_format(args: &str) -> Result<String, FormatError> {
+    let mut words: Vec<String> = Vec::new();
+    let mut chars = args.trim().trim_end_matches(';').chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut word = String::new();
+        if c == '\'' || c == '"' {
+            chars.next();
+            let mut terminated = false;
+            while let Some(x) = chars.next() {
+                if x == '\\' {
+                    word.extend(chars.next());
+                } else if x == c {
+                    terminated = true;
+                    break;
+                } else {
+                    word.push(x);
+                }
+            }
+
+            if !terminated {
+                return Err(FormatError::UnterminatedQuote);
+            }
+        } else {
+            while let Some(&x) = chars.peek() {
+                if x.is_whitespace() {
+                    break;
+                }
+                word.push(x);
+                chars.next();
+            }
+        }
+
+        words.push(word);
+    }
+
+    // Skip the name of the format and any parameters
+    let format: String = words
+        .into_iter()
+        .skip(1)
+        .skip_while(|x| x.starts_with("escape="))
+        .collect();
+
+    if format.is_empty() {
+        Err(FormatError::MissingFormat)
+    } else {
+        Ok(format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_nginx_line;
+
+    static LINE: &str = r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET /js/embed.min.js HTTP/2.0" 200 20480 "https://nbsoftsolutions.com/blog/monitoring-windows-system-metrics-with-grafana" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/61.0.3163.100 Safari/537.36" "comments.nbsoftsolutions.com""#;
+
+    #[test]
+    fn test_vhost_matches_nginx_parser() {
+        let format: LogFormat = "vhost".parse().unwrap();
+        assert_eq!(parse_nginx_line(LINE).unwrap(), format.parse(LINE).unwrap());
+    }
+
+    #[test]
+    fn test_compile_directive() {
+        let directive = r#"log_format vhost escape=default '$remote_addr - $remote_user [$time_local] '
+                    '"$request" $status $body_bytes_sent '
+                    '"$http_referer" "$http_user_agent" "$host"';"#;
+        let format = LogFormat::compile(directive).unwrap();
+        assert_eq!(format, LogFormat::preset("vhost").unwrap());
+        assert_eq!(format.to_string(), PRESETS[0].1);
+    }
+
+    #[test]
+    fn test_custom_format() {
+        let format = LogFormat::compile(
+            "${time_iso8601}|$request_method|$request_uri|$server_protocol|$status|$http_host",
+        )
+        .unwrap();
+        let log = format
+            .parse("2017-11-04T13:05:35-05:00|POST|/count|HTTP/1.1|201|example.com")
+            .unwrap();
+        assert_eq!(log.epoch, 1509818735);
+        assert_eq!(log.method, Some("POST".into()));
+        assert_eq!(log.path, Some("/count".into()));
+        assert_eq!(log.version, Some("1.1".into()));
+        assert_eq!(log.status, Some(201));
+        assert_eq!(log.host, "example.com");
+        assert_eq!(log.remote_addr, None);
+    }
+
+    #[test]
+    fn test_timing_format() {
+        let format = LogFormat::compile(
+            r#"[$time_local] $status $request_time "$upstream_response_time" "$upstream_addr" "$upstream_status""#,
+        )
+        .unwrap();
+        let log = format
+            .parse(r#"[04/Nov/2017:13:05:35 -0500] 200 0.105 "0.050, 0.051" "10.0.0.1:80, 10.0.0.2:80" "502, 200""#)
+            .unwrap();
+        assert_eq!(log.request_time, Some(0.105));
+        assert_eq!(log.upstream_response_time, Some(0.101));
+        assert_eq!(log.upstream_addr, Some("10.0.0.1:80, 10.0.0.2:80".into()));
+        assert_eq!(log.upstream_status, Some(200));
+
+        let log = format
+            .parse(r#"[04/Nov/2017:13:05:35 -0500] 200 0.000 "-" "-" "-""#)
+            .unwrap();
+        assert_eq!(log.request_time, Some(0.0));
+        assert_eq!(log.upstream_response_time, None);
+        assert_eq!(log.upstream_status, None);
+    }
+
+    #[test]
+    fn test_default_host() {
+        let format = LogFormat::preset("combined").unwrap();
+        assert!(!format.has_host());
+        let format = format.with_default_host("example.com");
+        assert!(format.has_host());
+        let line =
+            r#"10.0.0.1 - bob [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 304 0 "-" "curl/7.55""#;
+        let log = format.parse(line).unwrap();
+        assert_eq!(log.host, "example.com");
+        assert_eq!(log.remote_user, Some("bob".into()));
+        assert_eq!(log.user_agent, Some("curl/7.55".into()));
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert_eq!(
+            LogFormat::compile("$remote_addr$remote_user [$time_local]"),
+            Err(FormatError::AdjacentVariables(
+                "remote_addr".to_string(),
+                "remote_user".to_string()
+            ))
+        );
+        assert_eq!(
+            LogFormat::compile("$remote_addr"),
+            Err(FormatError::MissingTime)
+        );
+        assert_eq!(
+            LogFormat::compile("[${time_local"),
+            Err(FormatError::UnterminatedVariable(1))
+        );
+        assert_eq!(
+            LogFormat::compile("log_format main;"),
+            Err(FormatError::MissingFormat)
+        );
+    }
+
+    #[test]
+    fn test_format_no_match() {
+        let format = LogFormat::preset("vhost").unwrap();
+        assert_eq!(
+            format.parse("Cats are alright"),
+            Err(ParseError::unexpected(
+                "Cats are alright",
+                "$remote_addr",
+                0,
+                "` - ` after the value"
+            ))
+        );
+
+        let line =
+            r#"127.0.0.1 - - [04/Nov/2017:13:05:3X -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#;
+        assert_eq!(
+            format.parse(line),
+            Err(ParseError::unexpected(
+                line,
+                "$time_local",
+                15,
+                "a timestamp like `10/Oct/2000:13:55:36 -0700`"
+            ))
+        );
+
+        let line =
+            r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 2x0 0 "-" "-" "a.com""#;
+        assert_eq!(
+            format.parse(line),
+            Err(ParseError::unexpected(line, "$status", 60, "a number"))
+        );
+
+        let format = LogFormat::compile("[$time_local] $status").unwrap();
+        assert_eq!(
+            format.parse("04/Nov/2017:13:05:35 -0500] 200"),
+            Err(ParseError::unexpected(
+                "04/Nov/2017:13:05:35 -0500] 200",
+                "$time_local",
+                0,
+                "`[`"
+            ))
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Parses access logs written by nginx with `log_format name escape=json '{...}'`, where each line
+//! is a JSON object. A key is mapped to a column by the nginx variable that is logged under it, so
+//! `"ua":"$http_user_agent"` is configured as `ua=http_user_agent`. By default, keys are expected
+//! to be named after their variables (`"remote_addr":"$remote_addr"`).
+
+use format::{assign, Field, FormatError};
+use models::NewLog;
+use parser::{LogParser, ParseError};
+use serde_json::{self, Map, Value};
+use std::borrow::Cow;
+
+static DEFAULT_KEYS: &[&str] = &[
+    "remote_addr",
+    "remote_user",
+    "time_local",
+    "time_iso8601",
+    "msec",
+    "request",
+    "request_method",
+    "request_uri",
+    "server_protocol",
+    "status",
+    "body_bytes_sent",
+    "http_referer",
+    "http_user_agent",
+    "host",
+    "request_time",
+    "upstream_response_time",
+    "upstream_addr",
+    "upstream_status",
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct JsonFormat {
+    keys: Vec<(String, Field)>,
+    default_host: Option<String>,
+}
+
+impl Default for JsonFormat {
+    fn default() -> JsonFormat {
+        JsonFormat {
+            keys: DEFAULT_KEYS
+                .iter()
+                .map(|&key| (String::from(key), Field::from_variable(key)))
+                .collect(),
+            default_host: None,
+        }
+    }
+}
+
+impl JsonFormat {
+    /// Stores the value of the JSON key in the column of the given nginx variable. Any previous
+    /// mapping of the key or to the same column is replaced.
+    pub fn map_key(mut self, key: &str, variable: &str) -> JsonFormat {
+        let field = Field::from_variable(variable.trim_start_matches('$'));
+        self.keys
+            .retain(|&(ref k, f)| k != key && (f != field || field == Field::Ignored));
+        self.keys.push((String::from(key), field));
+        self
+    }
+
+    /// Parses a `key=variable` mapping (eg: `ua=http_user_agent`) and applies it
+    pub fn map_key_str(self, mapping: &str) -> Result<JsonFormat, FormatError> {
+        let mut parts = mapping.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(variable)) if !key.is_empty() && !variable.is_empty() => {
+                Ok(self.map_key(key, variable))
+            }
+            _ => Err(FormatError::InvalidMapping(String::from(mapping))),
+        }
+    }
+
+    /// Host to store for lines that don't contain a key mapped to the host
+    pub fn with_default_host(mut self, host: &str) -> JsonFormat {
+        self.default_host = Some(String::from(host));
+        self
+    }
+
+    pub fn parse<'a>(&'a self, text: &'a str) -> Result<NewLog<'a>, ParseError> {
+        let mut obj: Map<String, Value> = serde_json::from_str(text).map_err(|e| {
+            let offset = e.column().saturating_sub(1);
+            ParseError::unexpected(text, "line", offset, "a JSON object")
+        })?;
+
+        let mut log = NewLog {
+            host: Cow::Borrowed(self.default_host.as_deref().unwrap_or("-")),
+            ..NewLog::default()
+        };
+
+        let mut has_time = false;
+        for &(ref key, field) in &self.keys {
+            // nginx writes unset variables as empty strings, so treat those as missing
+            let value = match obj.remove(key) {
+                Some(Value::String(ref s)) if s.is_empty() => continue,
+                Some(Value::String(s)) => s,
+                Some(Value::Number(n)) => n.to_string(),
+                _ => continue,
+            };
+
+            has_time |=
+                field == Field::TimeLocal || field == Field::TimeIso8601 || field == Field::Msec;
+            assign(&mut log, field, Cow::Owned(value)).map_err(|expected| {
+                // Values may have been unescaped, so point at the key instead
+                let offset = text.find(&format!("\"{}\"", key)).unwrap_or(0);
+                ParseError::unexpected(text, key, offset, expected)
+            })?;
+        }
+
+        if has_time {
+            Ok(log)
+        } else {
+            let expected = "a key mapped to `$time_local`, `$time_iso8601`, or `$msec`";
+            Err(ParseError::unexpected(text, "line", text.len(), expected))
+        }
+    }
+}
+
+impl LogParser for JsonFormat {
+    fn parse<'a>(&'a self, line: &'a str) -> Result<NewLog<'a>, ParseError> {
+        JsonFormat::parse(self, line)
+    }
+
+    /// Describes the format as the object nginx would be configured to write
+    fn describe(&self) -> String {
+        let pairs: Vec<_> = self
+            .keys
+            .iter()
+            .filter_map(|&(ref key, field)| {
+                let key = serde_json::to_string(key).expect("key to serialize");
+                field.variable().map(|x| format!("{}:\"${}\"", key, x))
+            })
+            .collect();
+        format!("escape=json '{{{}}}'", pairs.join(","))
+    }
+
+    fn has_host(&self) -> bool {
+        self.default_host.is_some() || self.keys.iter().any(|&(_, field)| field == Field::Host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_json_line;
+
+    #[test]
+    fn test_parse_json_escaped() {
+        let line = r#"{"time_local":"04/Nov/2017:13:05:35 -0500","remote_addr":"127.0.0.1","remote_user":"","request":"GET /search?q=\"rust\" HTTP/1.1","status":"200","body_bytes_sent":"512","http_referer":"","http_user_agent":"Mozilla/5.0 \"quoted\"","host":"example.com"}"#;
+        let log = parse_json_line(line).unwrap();
+        assert_eq!(log.epoch, 1509818735);
+        assert_eq!(log.remote_addr, Some("127.0.0.1".into()));
+        assert_eq!(log.remote_user, None);
+        assert_eq!(log.method, Some("GET".into()));
+        assert_eq!(log.path, Some("/search".into()));
+        assert_eq!(log.query, Some(r#"q="rust""#.into()));
+        assert_eq!(log.version, Some("1.1".into()));
+        assert_eq!(log.status, Some(200));
+        assert_eq!(log.body_bytes_sent, Some(512));
+        assert_eq!(log.referer, None);
+        assert_eq!(log.user_agent, Some(r#"Mozilla/5.0 "quoted""#.into()));
+        assert_eq!(log.host, "example.com");
+    }
+
+    #[test]
+    fn test_parse_json_mapped_keys() {
+        let format = JsonFormat::default()
+            .map_key_str("ts=msec")
+            .unwrap()
+            .map_key_str("ua=http_user_agent")
+            .unwrap()
+            .map_key("code", "$status")
+            .with_default_host("example.com");
+        let line =
+            r#"{"ts":1509818735.123,"ua":"curl/7.55","code":404,"http_user_agent":"ignored"}"#;
+        let log = format.parse(line).unwrap();
+        assert_eq!(log.epoch, 1509818735);
+        assert_eq!(log.user_agent, Some("curl/7.55".into()));
+        assert_eq!(log.status, Some(404));
+        assert_eq!(log.host, "example.com");
+    }
+
+    #[test]
+    fn test_describe_json() {
+        let format = JsonFormat::default().map_key("ua", "http_user_agent");
+        let description = format.describe();
+        assert!(description.starts_with(r#"escape=json '{"remote_addr":"$remote_addr","#));
+        assert!(description
+            .ends_with(r#""upstream_status":"$upstream_status","ua":"$http_user_agent"}'"#));
+    }
+
+    #[test]
+    fn test_parse_json_errors() {
+        let format = JsonFormat::default();
+        assert_eq!(
+            format.parse("Cats are alright"),
+            Err(ParseError::unexpected(
+                "Cats are alright",
+                "line",
+                0,
+                "a JSON object"
+            ))
+        );
+        assert!(format.parse(r#"{"remote_addr":"127.0.0.1"}"#).is_err());
+
+        let line = r#"{"status":200,"msec":"yesterday"}"#;
+        assert_eq!(
+            format.parse(line),
+            Err(ParseError::unexpected(
+                line,
+                "msec",
+                14,
+                "seconds since the epoch"
+            ))
+        );
+        assert_eq!(
+            format.map_key_str("ua"),
+            Err(FormatError::InvalidMapping("ua".to_string()))
+        );
+    }
+}
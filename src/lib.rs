@@ -4,11 +4,23 @@ extern crate chrono;
 #[macro_use]
 extern crate diesel;
 #[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate lazy_static;
 extern crate regex;
+extern crate serde_json;
 
+pub mod db;
+pub mod filter;
+pub mod format;
+pub mod json;
 pub mod models;
 pub mod parser;
+pub mod rollup;
 pub mod schema;
+pub mod shard;
+pub mod syslog;
+pub mod uri;
+pub mod vhost;
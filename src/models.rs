@@ -1,5 +1,7 @@
-use schema::logs;
+use schema::{checkpoints, logs, rejected_lines};
+use std::borrow::Cow;
 use std::fmt;
+use uri;
 
 #[derive(Debug, Queryable, PartialEq)]
 pub struct Log {
@@ -15,22 +17,169 @@ pub struct Log {
     pub referer: Option<String>,
     pub user_agent: Option<String>,
     pub host: String,
+    pub query: Option<String>,
+    pub fragment: Option<String>,
+    pub query_params: Option<String>,
+    pub request_time: Option<f64>,
+    pub upstream_response_time: Option<f64>,
+    pub upstream_addr: Option<String>,
+    pub upstream_status: Option<i32>,
+    pub malformed: bool,
+    pub invalid_utf8: bool,
+    pub syslog_hostname: Option<String>,
+    pub syslog_tag: Option<String>,
+    pub fingerprint: Option<Vec<u8>>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub asn: Option<i64>,
+    pub as_org: Option<String>,
 }
 
-#[derive(Debug, Insertable, PartialEq)]
+/// A log line ready for insertion. Columns typically borrow from the line they were parsed from,
+/// but are owned when parsing requires transforming the text (eg: unescaping JSON).
+#[derive(Debug, Default, Insertable, PartialEq)]
 #[table_name = "logs"]
 pub struct NewLog<'a> {
     pub epoch: i64,
-    pub remote_addr: Option<&'a str>,
-    pub remote_user: Option<&'a str>,
+    pub remote_addr: Option<Cow<'a, str>>,
+    pub remote_user: Option<Cow<'a, str>>,
     pub status: Option<i32>,
-    pub method: Option<&'a str>,
-    pub path: Option<&'a str>,
-    pub version: Option<&'a str>,
+    pub method: Option<Cow<'a, str>>,
+    pub path: Option<Cow<'a, str>>,
+    pub version: Option<Cow<'a, str>>,
     pub body_bytes_sent: Option<i32>,
-    pub referer: Option<&'a str>,
-    pub user_agent: Option<&'a str>,
-    pub host: &'a str,
+    pub referer: Option<Cow<'a, str>>,
+    pub user_agent: Option<Cow<'a, str>>,
+    pub host: Cow<'a, str>,
+
+    /// Raw query string of the request target (without the leading `?`)
+    pub query: Option<Cow<'a, str>>,
+    pub fragment: Option<Cow<'a, str>>,
+
+    /// Percent-decoded query parameters as a JSON object
+    pub query_params: Option<Cow<'a, str>>,
+
+    /// Seconds between reading the first byte of the request and writing the last byte of the
+    /// response (`$request_time`)
+    pub request_time: Option<f64>,
+
+    /// Seconds spent waiting on responses from all upstreams that were contacted
+    pub upstream_response_time: Option<f64>,
+    pub upstream_addr: Option<Cow<'a, str>>,
+
+    /// Status of the last upstream contacted
+    pub upstream_status: Option<i32>,
+
+    /// The request line isn't an HTTP request (eg: a TLS handshake or `-`), so the method, path,
+    /// and version are left empty
+    pub malformed: bool,
+
+    /// The line wasn't valid UTF-8, so invalid sequences were replaced with U+FFFD
+    pub invalid_utf8: bool,
+
+    /// Hostname of the server that sent the line over syslog
+    pub syslog_hostname: Option<Cow<'a, str>>,
+
+    /// Tag (or RFC 5424 app name) of the syslog message, which defaults to `nginx`
+    pub syslog_tag: Option<Cow<'a, str>>,
+
+    /// Hash of the line, which is unique among rows when set so that duplicate lines are skipped
+    pub fingerprint: Option<Vec<u8>>,
+
+    /// ISO code of the visitor's country (eg: GB), looked up from the address
+    pub country: Option<Cow<'a, str>>,
+
+    /// English name of the visitor's region, which is the country's largest subdivision
+    pub region: Option<Cow<'a, str>>,
+    pub city: Option<Cow<'a, str>>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+
+    /// Number of the autonomous system that announces the visitor's address (eg: 15169)
+    pub asn: Option<i64>,
+
+    /// Organization that operates the autonomous system (eg: Google LLC)
+    pub as_org: Option<Cow<'a, str>>,
+}
+
+impl<'a> NewLog<'a> {
+    /// Splits the request line (eg: `GET /index.html HTTP/1.1`) into the method, target, and
+    /// version. Request lines that can't be split are flagged as malformed instead of failing the
+    /// line, as scanners and clients speaking other protocols are traffic worth seeing.
+    pub fn set_request(&mut self, request: Cow<'a, str>) {
+        let (method, target, version) = match request {
+            Cow::Borrowed(request) => match split_request(request) {
+                Some((method, target, version)) => (method.into(), target.into(), version.into()),
+                None => return self.set_malformed(),
+            },
+            Cow::Owned(ref request) => match split_request(request) {
+                Some((method, target, version)) => (
+                    String::from(method).into(),
+                    String::from(target).into(),
+                    String::from(version).into(),
+                ),
+                None => return self.set_malformed(),
+            },
+        };
+
+        self.method = Some(method);
+        self.version = Some(version);
+        self.set_target(target);
+    }
+
+    fn set_malformed(&mut self) {
+        self.malformed = true;
+        self.method = None;
+        self.path = None;
+        self.version = None;
+        self.query = None;
+        self.fragment = None;
+        self.query_params = None;
+    }
+
+    /// Splits the request target (eg: `/blog/?page=2`) into the normalized path, query string,
+    /// fragment, and decoded query parameters
+    pub fn set_target(&mut self, target: Cow<'a, str>) {
+        match target {
+            Cow::Borrowed(target) => {
+                let (path, query, fragment) = uri::split(target);
+                self.path = Some(uri::normalize_path(path));
+                self.query = query.map(Cow::Borrowed);
+                self.fragment = fragment.map(Cow::Borrowed);
+            }
+            Cow::Owned(ref target) => {
+                let (path, query, fragment) = uri::split(target);
+                self.path = Some(Cow::Owned(uri::normalize_path(path).into_owned()));
+                self.query = query.map(|x| Cow::Owned(String::from(x)));
+                self.fragment = fragment.map(|x| Cow::Owned(String::from(x)));
+            }
+        }
+
+        self.query_params = self
+            .query
+            .as_ref()
+            .map(|x| Cow::Owned(uri::query_params(x)));
+    }
+}
+
+/// Splits `GET /index.html HTTP/1.1` into the method, target, and version (`1.1`)
+fn split_request(request: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = request.splitn(3, ' ');
+    let method = parts.next().filter(|x| !x.is_empty())?;
+    let target = parts.next()?;
+    let version = parts.next()?;
+    if version.starts_with("HTTP/") && !version.contains(' ') {
+        Some((method, target, &version["HTTP/".len()..]))
+    } else {
+        None
+    }
+}
+
+fn or_na<'a>(field: &'a Option<Cow<str>>) -> &'a str {
+    field.as_ref().map(|x| x.as_ref()).unwrap_or("NA")
 }
 
 impl<'a> fmt::Display for NewLog<'a> {
@@ -39,16 +188,47 @@ impl<'a> fmt::Display for NewLog<'a> {
             f,
             "{} {} {} {} {} {} {} {} {} {} {}",
             self.epoch,
-            self.remote_addr.unwrap_or("NA"),
-            self.remote_user.unwrap_or("NA"),
+            or_na(&self.remote_addr),
+            or_na(&self.remote_user),
             self.status.unwrap_or(200),
-            self.method.unwrap_or("NA"),
-            self.path.unwrap_or("NA"),
-            self.version.unwrap_or("NA"),
+            or_na(&self.method),
+            or_na(&self.path),
+            or_na(&self.version),
             self.body_bytes_sent.unwrap_or(0),
-            self.referer.unwrap_or("NA"),
-            self.user_agent.unwrap_or("NA"),
+            or_na(&self.referer),
+            or_na(&self.user_agent),
             self.host
         )
     }
 }
+
+/// Where ingestion of a followed log file left off. The inode identifies the file that the offset
+/// belongs to, so that a file that has been rotated isn't resumed at the offset of its predecessor.
+#[derive(Debug, Clone, Queryable, Insertable, PartialEq)]
+#[table_name = "checkpoints"]
+pub struct Checkpoint {
+    pub path: String,
+    pub inode: i64,
+    pub byte_offset: i64,
+}
+
+/// A line that failed to parse. The line is stored as it was read, even if it isn't valid UTF-8.
+#[derive(Debug, Queryable, PartialEq)]
+pub struct RejectedLine {
+    pub id: i32,
+
+    /// When the line was rejected
+    pub epoch: i64,
+    pub line: Vec<u8>,
+
+    /// Why the line couldn't be parsed
+    pub error: String,
+}
+
+#[derive(Debug, Insertable, PartialEq)]
+#[table_name = "rejected_lines"]
+pub struct NewRejectedLine<'a> {
+    pub epoch: i64,
+    pub line: &'a [u8],
+    pub error: String,
+}
@@ -1,5 +1,7 @@
 use chrono::prelude::*;
 use regex::Regex;
+use std::cell::Cell;
+use std::cmp;
 
 #[derive(Fail, Debug, PartialEq, Clone)]
 pub enum ParseError {
@@ -7,11 +9,103 @@ pub enum ParseError {
     NoMatch(String),
     #[fail(display = "Text could not be parsed into date `{}`", _0)]
     InvalidDate(String),
+    #[fail(
+        display = "Unable to parse {} at byte {}: expected {}",
+        field, offset, expected
+    )]
+    Unexpected {
+        line: String,
+        field: String,
+        offset: usize,
+        expected: String,
+    },
 }
 
+/// Parses access log lines into rows. rrinlog ships implementations for nginx formats
+/// (`VhostParser`, `LogFormat`, and `JsonFormat`), and other formats can be ingested by
+/// implementing this trait.
+pub trait LogParser {
+    /// Parses a line, where columns may borrow from the line or from the parser
+    fn parse<'a>(&'a self, line: &'a str) -> Result<NewLog<'a>, ParseError>;
+
+    /// Describes the format of the lines that are parsed (eg: the nginx `log_format` string)
+    fn describe(&self) -> String;
+
+    /// Returns true if parsed rows carry the virtual host of the request, either from the line or
+    /// from a host supplied by the user. `rrinlog` refuses to ingest with parsers that don't.
+    fn has_host(&self) -> bool;
+}
+
+/// Number of bytes shown on either side of the failing position in an excerpt
+const EXCERPT_CONTEXT: usize = 30;
+
+impl ParseError {
+    pub fn unexpected(line: &str, field: &str, offset: usize, expected: &str) -> ParseError {
+        ParseError::Unexpected {
+            line: String::from(line),
+            field: String::from(field),
+            offset,
+            expected: String::from(expected),
+        }
+    }
+
+    /// Renders the text surrounding the failing position with the value found there underlined.
+    /// Only errors that know the position can be rendered.
+    ///
+    /// ```text
+    /// ...-0500] "GET / HTTP/1.1" 2x0 512 "-" "curl...
+    ///                            ^^^
+    /// ```
+    pub fn excerpt(&self) -> Option<String> {
+        let (line, offset) = match *self {
+            ParseError::Unexpected {
+                ref line, offset, ..
+            } => (line.as_str(), floor_char_boundary(line, offset)),
+            _ => return None,
+        };
+
+        let start = floor_char_boundary(line, offset.saturating_sub(EXCERPT_CONTEXT));
+        let end = ceil_char_boundary(line, offset + EXCERPT_CONTEXT);
+        let value = line[offset..end]
+            .split(|c: char| c.is_whitespace() || c == '"')
+            .next()
+            .unwrap_or("");
+
+        let prefix = if start > 0 { "..." } else { "" };
+        let suffix = if end < line.len() { "..." } else { "" };
+        let indent = prefix.len() + line[start..offset].chars().count();
+        Some(format!(
+            "{}{}{}\n{}{}",
+            prefix,
+            &line[start..end],
+            suffix,
+            " ".repeat(indent),
+            "^".repeat(cmp::max(value.chars().count(), 1))
+        ))
+    }
+}
+
+fn floor_char_boundary(text: &str, ind: usize) -> usize {
+    let mut ind = cmp::min(ind, text.len());
+    while !text.is_char_boundary(ind) {
+        ind -= 1;
+    }
+    ind
+}
+
+fn ceil_char_boundary(text: &str, ind: usize) -> usize {
+    let mut ind = cmp::min(ind, text.len());
+    while !text.is_char_boundary(ind) {
+        ind += 1;
+    }
+    ind
+}
+
+use format::LogFormat;
+use json::JsonFormat;
 use models::*;
 
-pub fn parse_nginx_line(text: &str) -> Result<NewLog, ParseError> {
+pub fn parse_nginx_line(text: &str) -> Result<NewLog<'_>, ParseError> {
     lazy_static! {
         static ref RE: Regex = Regex::new(
             r#"(?x)
@@ -21,11 +115,7 @@ pub fn parse_nginx_line(text: &str) -> Result<NewLog, ParseError> {
         \s\[
         (?P<time_local>[^\]]+)
         \]\s"
-        (?P<method>[^\s]+)
-        \s
-        (?P<path>[^\s]*)
-        \s
-        HTTP/(?P<version>[^\s]+)"
+        (?P<request>[^"]*)"
         \s
         (?P<status>[^\s]+)
         \s
@@ -41,29 +131,66 @@ pub fn parse_nginx_line(text: &str) -> Result<NewLog, ParseError> {
     }
 
     if let Some(caps) = RE.captures(text) {
-        Ok(NewLog {
+        let mut log = NewLog {
             epoch: parse_date(caps.name("time_local").unwrap().as_str())?,
-            remote_addr: Some(caps.name("remote_addr").unwrap().as_str()),
-            remote_user: Some(caps.name("remote_user").unwrap().as_str()),
+            remote_addr: Some(caps.name("remote_addr").unwrap().as_str().into()),
+            remote_user: Some(caps.name("remote_user").unwrap().as_str().into()),
             status: caps.name("status").unwrap().as_str().parse::<i32>().ok(),
-            method: Some(caps.name("method").unwrap().as_str()),
-            path: Some(caps.name("path").unwrap().as_str()),
-            version: Some(caps.name("version").unwrap().as_str()),
             body_bytes_sent: caps
                 .name("body_bytes_sent")
                 .unwrap()
                 .as_str()
                 .parse::<i32>()
                 .ok(),
-            referer: Some(caps.name("referer").unwrap().as_str()),
-            user_agent: Some(caps.name("user_agent").unwrap().as_str()),
-            host: caps.name("host").unwrap().as_str(),
-        })
+            referer: Some(caps.name("referer").unwrap().as_str().into()),
+            user_agent: Some(caps.name("user_agent").unwrap().as_str().into()),
+            host: caps.name("host").unwrap().as_str().into(),
+            ..NewLog::default()
+        };
+
+        log.set_request(caps.name("request").unwrap().as_str().into());
+        Ok(log)
     } else {
         Err(ParseError::NoMatch(String::from(text)))
     }
 }
 
+/// Parses a line in Apache's Common Log Format. The format doesn't record the virtual host, so the
+/// given host is used instead.
+pub fn parse_apache_common<'a>(text: &'a str, host: &'a str) -> Result<NewLog<'a>, ParseError> {
+    lazy_static! {
+        static ref COMMON: LogFormat = LogFormat::preset("apache_common").unwrap();
+    }
+
+    COMMON.parse(text).map(|log| NewLog {
+        host: host.into(),
+        ..log
+    })
+}
+
+/// Parses a line in Apache's Combined Log Format, which is the Common Log Format with the referer
+/// and user agent appended.
+pub fn parse_apache_combined<'a>(text: &'a str, host: &'a str) -> Result<NewLog<'a>, ParseError> {
+    lazy_static! {
+        static ref COMBINED: LogFormat = LogFormat::preset("apache_combined").unwrap();
+    }
+
+    COMBINED.parse(text).map(|log| NewLog {
+        host: host.into(),
+        ..log
+    })
+}
+
+/// Parses a JSON line written with nginx's `escape=json` where each key is named after the
+/// variable it logs (eg: `{"remote_addr":"$remote_addr","time_local":"$time_local",...}`)
+pub fn parse_json_line(text: &str) -> Result<NewLog<'_>, ParseError> {
+    lazy_static! {
+        static ref JSON: JsonFormat = JsonFormat::default();
+    }
+
+    JSON.parse(text)
+}
+
 pub fn parse_date(text: &str) -> Result<i64, ParseError> {
     if let Ok(dt) = DateTime::parse_from_str(text, "%d/%b/%Y:%H:%M:%S %z") {
         Ok(dt.timestamp())
@@ -72,10 +199,93 @@ pub fn parse_date(text: &str) -> Result<i64, ParseError> {
     }
 }
 
+/// Length of a `$time_local` timestamp (eg: `03/Nov/2017:06:49:45 -0500`)
+const TIME_LOCAL_LEN: usize = 26;
+
+/// Caches the most recent `parse_date` result. Access logs are written in chronological order, so
+/// consecutive lines overwhelmingly share the same timestamp text and can skip chrono's parsing.
+#[derive(Debug, Default)]
+pub struct DateCache {
+    last: Cell<Option<([u8; TIME_LOCAL_LEN], i64)>>,
+}
+
+impl DateCache {
+    pub fn parse(&self, text: &str) -> Result<i64, ParseError> {
+        let bytes = text.as_bytes();
+        if bytes.len() != TIME_LOCAL_LEN {
+            return parse_date(text);
+        }
+
+        if let Some((cached, epoch)) = self.last.get() {
+            if cached[..] == bytes[..] {
+                return Ok(epoch);
+            }
+        }
+
+        let epoch = parse_date(text)?;
+        let mut key = [0; TIME_LOCAL_LEN];
+        key.copy_from_slice(bytes);
+        self.last.set(Some((key, epoch)));
+        Ok(epoch)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Parses lines of `<epoch> <host> <path>`
+    struct SpaceParser;
+
+    impl LogParser for SpaceParser {
+        fn parse<'a>(&'a self, line: &'a str) -> Result<NewLog<'a>, ParseError> {
+            let mut parts = line.splitn(3, ' ');
+            let epoch = parts.next().and_then(|x| x.parse::<i64>().ok());
+            match (epoch, parts.next(), parts.next()) {
+                (Some(epoch), Some(host), Some(path)) => {
+                    let mut log = NewLog {
+                        epoch,
+                        host: host.into(),
+                        ..NewLog::default()
+                    };
+                    log.set_target(path.into());
+                    Ok(log)
+                }
+                _ => Err(ParseError::NoMatch(String::from(line))),
+            }
+        }
+
+        fn describe(&self) -> String {
+            String::from("<epoch> <host> <path>")
+        }
+
+        fn has_host(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_custom_log_parser() {
+        let parsers: Vec<Box<dyn LogParser>> = vec![
+            Box::new(SpaceParser),
+            Box::new(LogFormat::preset("combined").unwrap()),
+        ];
+
+        let actual = parsers[0].parse("1509818735 a.com /b?c=d").unwrap();
+        assert_eq!(actual.epoch, 1509818735);
+        assert_eq!(actual.host, "a.com");
+        assert_eq!(actual.path, Some("/b".into()));
+        assert_eq!(actual.query, Some("c=d".into()));
+        assert!(parsers[0].parse("a.com /b").is_err());
+        assert!(parsers[0].has_host());
+
+        assert!(!parsers[1].has_host());
+        assert_eq!(
+            parsers[1].describe(),
+            r#"$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent""#
+        );
+    }
+
     #[test]
     fn test_parse_date() {
         let expected = FixedOffset::west(5 * 3600)
@@ -85,6 +295,16 @@ mod tests {
         assert_eq!(expected.timestamp(), actual);
     }
 
+    #[test]
+    fn test_date_cache() {
+        let cache = DateCache::default();
+        assert_eq!(cache.parse("03/Nov/2017:06:49:45 -0500"), Ok(1509709785));
+        assert_eq!(cache.parse("03/Nov/2017:06:49:45 -0500"), Ok(1509709785));
+        assert_eq!(cache.parse("03/Nov/2017:06:49:46 -0500"), Ok(1509709786));
+        assert!(cache.parse("03/Nov/2017:06:49:4X -0500").is_err());
+        assert_eq!(cache.parse("03/Nov/2017:06:49:46 -0500"), Ok(1509709786));
+    }
+
     #[test]
     fn test_parse_bad_date() {
         let actual = parse_date("2017-12-01");
@@ -94,6 +314,28 @@ mod tests {
         assert_eq!("Text could not be parsed into date `2017-12-01`", s);
     }
 
+    #[test]
+    fn test_excerpt() {
+        let line = r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 2x0 512"#;
+        let err = ParseError::unexpected(line, "$status", 60, "a number");
+        assert_eq!(
+            err.to_string(),
+            "Unable to parse $status at byte 60: expected a number"
+        );
+        assert_eq!(
+            err.excerpt().unwrap(),
+            concat!(
+                r#"...05:35 -0500] "GET / HTTP/1.1" 2x0 512"#,
+                "\n",
+                "                                 ^^^"
+            )
+        );
+
+        let err = ParseError::unexpected("ü", "$remote_addr", 1, "a space");
+        assert_eq!(err.excerpt().unwrap(), "ü\n^");
+        assert_eq!(parse_date("2017-12-01").unwrap_err().excerpt(), None);
+    }
+
     #[test]
     fn test_parse_nginx() {
         let line = r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET /js/embed.min.js HTTP/2.0" 200 20480 "https://nbsoftsolutions.com/blog/monitoring-windows-system-metrics-with-grafana" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/61.0.3163.100 Safari/537.36" "comments.nbsoftsolutions.com""#;
@@ -101,22 +343,118 @@ mod tests {
         assert_eq!(
             NewLog {
                 epoch: 1509818735,
-                remote_addr: Some("127.0.0.1"),
-                remote_user: Some("-"),
+                remote_addr: Some("127.0.0.1".into()),
+                remote_user: Some("-".into()),
                 status: Some(200),
-                method: Some("GET"),
-                path: Some("/js/embed.min.js"),
-                version: Some("2.0"),
+                method: Some("GET".into()),
+                path: Some("/js/embed.min.js".into()),
+                version: Some("2.0".into()),
                 body_bytes_sent: Some(20480),
                 referer: Some(
-                    "https://nbsoftsolutions.com/blog/monitoring-windows-system-metrics-with-grafana"
+                    "https://nbsoftsolutions.com/blog/monitoring-windows-system-metrics-with-grafana".into()
                 ),
                 user_agent: Some(
-                    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/61.0.3163.100 Safari/537.36"
+                    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/61.0.3163.100 Safari/537.36".into()
                 ),
-                host: "comments.nbsoftsolutions.com",
+                host: "comments.nbsoftsolutions.com".into(),
+                ..NewLog::default()
+            },
+            actual
+        )
+    }
+
+    #[test]
+    fn test_parse_nginx_query() {
+        let line = r#"127.0.0.1 - - [14/Nov/2017:06:29:19 -0600] "GET /?uri=%2Fblog%2Flinux-virtualization&nested_limit=5 HTTP/2.0" 200 3775 "-" "-" "comments.nbsoftsolutions.com""#;
+        let actual = parse_nginx_line(line).unwrap();
+        assert_eq!(actual.path, Some("/".into()));
+        assert_eq!(
+            actual.query,
+            Some("uri=%2Fblog%2Flinux-virtualization&nested_limit=5".into())
+        );
+        assert_eq!(actual.fragment, None);
+        assert_eq!(
+            actual.query_params,
+            Some(r#"{"nested_limit":"5","uri":"/blog/linux-virtualization"}"#.into())
+        );
+    }
+
+    #[test]
+    fn test_parse_nginx_malformed() {
+        let line = r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "\x16\x03\x01\x00\xA5\x01" 400 157 "-" "-" "comments.nbsoftsolutions.com""#;
+        let actual = parse_nginx_line(line).unwrap();
+        assert_eq!(
+            NewLog {
+                epoch: 1509818735,
+                remote_addr: Some("127.0.0.1".into()),
+                remote_user: Some("-".into()),
+                status: Some(400),
+                body_bytes_sent: Some(157),
+                referer: Some("-".into()),
+                user_agent: Some("-".into()),
+                host: "comments.nbsoftsolutions.com".into(),
+                malformed: true,
+                ..NewLog::default()
+            },
+            actual
+        );
+
+        let line = r#"127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "-" 400 0 "-" "-" "comments.nbsoftsolutions.com""#;
+        let actual = parse_nginx_line(line).unwrap();
+        assert!(actual.malformed);
+        assert_eq!(actual.method, None);
+        assert_eq!(actual.path, None);
+        assert_eq!(actual.version, None);
+        assert_eq!(actual.body_bytes_sent, Some(0));
+    }
+
+    #[test]
+    fn test_parse_apache_common() {
+        let line = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#;
+        let actual = parse_apache_common(line, "example.com").unwrap();
+        assert_eq!(
+            NewLog {
+                epoch: 971211336,
+                remote_addr: Some("127.0.0.1".into()),
+                remote_user: Some("frank".into()),
+                status: Some(200),
+                method: Some("GET".into()),
+                path: Some("/apache_pb.gif".into()),
+                version: Some("1.0".into()),
+                body_bytes_sent: Some(2326),
+                referer: None,
+                user_agent: None,
+                host: "example.com".into(),
+                ..NewLog::default()
             },
             actual
         )
     }
+
+    #[test]
+    fn test_parse_apache_combined() {
+        let line = r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "HEAD / HTTP/1.1" 304 - "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#;
+        let actual = parse_apache_combined(line, "example.com").unwrap();
+        assert_eq!(actual.remote_user, Some("-".into()));
+        assert_eq!(actual.body_bytes_sent, None);
+        assert_eq!(
+            actual.referer,
+            Some("http://www.example.com/start.html".into())
+        );
+        assert_eq!(
+            actual.user_agent,
+            Some("Mozilla/4.08 [en] (Win98; I ;Nav)".into())
+        );
+        assert_eq!(actual.host, "example.com");
+
+        assert!(parse_apache_combined("Cats are alright", "example.com").is_err());
+
+        // The referer and user agent of a combined line are ignored by the common format, but the
+        // bytes sent are still parsed
+        let line = r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET / HTTP/1.1" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#;
+        let actual = parse_apache_common(line, "example.com").unwrap();
+        assert_eq!(actual.body_bytes_sent, Some(2326));
+        assert_eq!(actual.referer, None);
+        assert_eq!(actual.user_agent, None);
+    }
 }
@@ -0,0 +1,258 @@
+//! Requests and bytes sent per host and status class, pre-aggregated into minute, hour, and day
+//! buckets as rows are inserted, so that graphs over long ranges don't scan the raw rows.
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Integer, Text};
+use models::NewLog;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+/// From the finest to the coarsest
+pub const RESOLUTIONS: [Resolution; 3] = [Resolution::Minute, Resolution::Hour, Resolution::Day];
+
+impl Resolution {
+    pub fn seconds(self) -> i64 {
+        match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 60 * 60,
+            Resolution::Day => 24 * 60 * 60,
+        }
+    }
+
+    pub fn table(self) -> &'static str {
+        match self {
+            Resolution::Minute => "rollups_minute",
+            Resolution::Hour => "rollups_hour",
+            Resolution::Day => "rollups_day",
+        }
+    }
+
+    /// Start of the bucket that contains the epoch
+    pub fn bucket(self, epoch: i64) -> i64 {
+        epoch - epoch.rem_euclid(self.seconds())
+    }
+
+    /// The coarsest resolution whose buckets add up to the interval exactly. Intervals that aren't
+    /// a whole number of minutes have to be computed from the raw rows.
+    pub fn coarsest_for(interval_secs: i64) -> Option<Resolution> {
+        RESOLUTIONS
+            .iter()
+            .rev()
+            .find(|x| interval_secs > 0 && interval_secs % x.seconds() == 0)
+            .cloned()
+    }
+}
+
+/// 2 for a 2xx, 4 for a 4xx, etc. Rows without a status (eg: a 444 that closed the connection
+/// before a status was logged) are class 0.
+pub fn status_class(status: Option<i32>) -> i32 {
+    status.map_or(0, |x| x / 100)
+}
+
+/// Adds the rows to the rollups. Call within the transaction that inserts the rows, so the two
+/// can't disagree.
+pub fn record(conn: &SqliteConnection, rows: &[&NewLog]) -> QueryResult<()> {
+    for &resolution in &RESOLUTIONS {
+        let mut totals: BTreeMap<(i64, &str, i32), (i64, i64)> = BTreeMap::new();
+        for row in rows {
+            let key = (
+                resolution.bucket(row.epoch),
+                row.host.as_ref(),
+                status_class(row.status),
+            );
+            let total = totals.entry(key).or_insert((0, 0));
+            total.0 += 1;
+            total.1 += i64::from(row.body_bytes_sent.unwrap_or(0));
+        }
+
+        let qs = format!(
+            r#"
+INSERT INTO {} (bucket, host, status_class, requests, bytes)
+VALUES      (?, ?, ?, ?, ?)
+ON CONFLICT (bucket, host, status_class) DO UPDATE
+SET    requests = requests + excluded.requests,
+       bytes = bytes + excluded.bytes
+"#,
+            resolution.table()
+        );
+
+        for ((bucket, host, class), (requests, bytes)) in totals {
+            sql_query(qs.as_str())
+                .bind::<BigInt, _>(bucket)
+                .bind::<Text, _>(host)
+                .bind::<Integer, _>(class)
+                .bind::<BigInt, _>(requests)
+                .bind::<BigInt, _>(bytes)
+                .execute(conn)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct Oldest {
+    #[sql_type = "diesel::sql_types::Nullable<BigInt>"]
+    epoch: Option<i64>,
+}
+
+/// Rebuilds the rollups from the raw rows (eg: rows ingested before rollups existed). Buckets that
+/// are older than the oldest raw row are left alone, as their rows may have been pruned, and a
+/// bucket that was partially pruned is only built if it doesn't exist yet. Returns the number of
+/// buckets written.
+pub fn backfill(conn: &SqliteConnection) -> QueryResult<usize> {
+    conn.transaction(|| {
+        let oldest: Oldest = sql_query("SELECT MIN(epoch) AS epoch FROM logs").get_result(conn)?;
+        let oldest = match oldest.epoch {
+            Some(epoch) => epoch,
+            None => return Ok(0),
+        };
+
+        let mut written = 0;
+        for &resolution in &RESOLUTIONS {
+            let secs = resolution.seconds();
+            let table = resolution.table();
+
+            // The first bucket that all raw rows are still around for
+            let whole = resolution.bucket(oldest + secs - 1);
+            sql_query(format!("DELETE FROM {} WHERE bucket >= ?", table))
+                .bind::<BigInt, _>(whole)
+                .execute(conn)?;
+
+            let qs = format!(
+                r#"
+INSERT OR IGNORE INTO {table} (bucket, host, status_class, requests, bytes)
+SELECT epoch - (epoch % {secs}) AS b,
+       host,
+       COALESCE(status / 100, 0) AS c,
+       COUNT(*),
+       COALESCE(SUM(body_bytes_sent), 0)
+FROM   logs
+GROUP  BY b, host, c
+"#,
+                table = table,
+                secs = secs
+            );
+            written += sql_query(qs).execute(conn)?;
+        }
+
+        Ok(written)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db;
+    use schema::{logs, rollups_day, rollups_hour, rollups_minute};
+    use std::borrow::Cow;
+
+    fn log(epoch: i64, host: &str, status: Option<i32>, bytes: i32) -> NewLog<'_> {
+        NewLog {
+            epoch,
+            host: Cow::Borrowed(host),
+            status,
+            body_bytes_sent: Some(bytes),
+            ..NewLog::default()
+        }
+    }
+
+    type Row = (i64, String, i32, i64, i64);
+
+    fn minutes(conn: &SqliteConnection) -> Vec<Row> {
+        rollups_minute::table
+            .order((rollups_minute::bucket, rollups_minute::host))
+            .load(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_coarsest_for() {
+        assert_eq!(Resolution::coarsest_for(1), None);
+        assert_eq!(Resolution::coarsest_for(90), None);
+        assert_eq!(Resolution::coarsest_for(60), Some(Resolution::Minute));
+        assert_eq!(Resolution::coarsest_for(30 * 60), Some(Resolution::Minute));
+        assert_eq!(
+            Resolution::coarsest_for(2 * 60 * 60),
+            Some(Resolution::Hour)
+        );
+        assert_eq!(
+            Resolution::coarsest_for(7 * 24 * 60 * 60),
+            Some(Resolution::Day)
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let conn = db::setup(":memory:").unwrap();
+        let rows = [
+            log(1_509_818_735, "a.com", Some(200), 10),
+            log(1_509_818_759, "a.com", Some(204), 5),
+            log(1_509_818_761, "a.com", Some(404), 1),
+            log(1_509_818_761, "b.com", None, 1),
+        ];
+        let refs: Vec<&NewLog> = rows.iter().collect();
+        record(&conn, &refs[..2]).unwrap();
+        record(&conn, &refs[1..]).unwrap();
+
+        assert_eq!(
+            minutes(&conn),
+            vec![
+                (1_509_818_700, String::from("a.com"), 2, 3, 20),
+                (1_509_818_760, String::from("a.com"), 4, 1, 1),
+                (1_509_818_760, String::from("b.com"), 0, 1, 1),
+            ]
+        );
+
+        let hours: Vec<Row> = rollups_hour::table.load(&conn).unwrap();
+        assert_eq!(hours.len(), 3);
+        assert!(hours.iter().all(|x| x.0 == 1_509_818_400));
+
+        let days: Vec<Row> = rollups_day::table
+            .filter(rollups_day::host.eq("a.com"))
+            .filter(rollups_day::status_class.eq(2))
+            .load(&conn)
+            .unwrap();
+        assert_eq!(days, vec![(1_509_753_600, String::from("a.com"), 2, 3, 20)]);
+    }
+
+    #[test]
+    fn test_backfill() {
+        let conn = db::setup(":memory:").unwrap();
+        assert_eq!(backfill(&conn).unwrap(), 0);
+
+        let rows = vec![
+            log(1_509_818_735, "a.com", Some(200), 10),
+            log(1_509_818_761, "a.com", Some(200), 5),
+        ];
+        diesel::insert_into(logs::table)
+            .values(&rows)
+            .execute(&conn)
+            .unwrap();
+
+        // A bucket from rows that have since been pruned is kept
+        let pruned = log(1_509_000_000, "a.com", Some(200), 1);
+        record(&conn, &[&pruned]).unwrap();
+
+        // Minute buckets, then the hour and the day
+        assert_eq!(backfill(&conn).unwrap(), 4);
+
+        // Only the minute that all of its rows are still around for is rebuilt
+        assert_eq!(backfill(&conn).unwrap(), 1);
+        assert_eq!(
+            minutes(&conn),
+            vec![
+                (1_509_000_000, String::from("a.com"), 2, 1, 1),
+                (1_509_818_700, String::from("a.com"), 2, 1, 10),
+                (1_509_818_760, String::from("a.com"), 2, 1, 5),
+            ]
+        );
+    }
+}
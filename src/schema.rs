@@ -12,5 +12,78 @@ table! {
         referer -> Nullable<Text>,
         user_agent -> Nullable<Text>,
         host -> Text,
+        query -> Nullable<Text>,
+        fragment -> Nullable<Text>,
+        query_params -> Nullable<Text>,
+        request_time -> Nullable<Double>,
+        upstream_response_time -> Nullable<Double>,
+        upstream_addr -> Nullable<Text>,
+        upstream_status -> Nullable<Integer>,
+        malformed -> Bool,
+        invalid_utf8 -> Bool,
+        syslog_hostname -> Nullable<Text>,
+        syslog_tag -> Nullable<Text>,
+        fingerprint -> Nullable<Binary>,
+        country -> Nullable<Text>,
+        region -> Nullable<Text>,
+        city -> Nullable<Text>,
+        latitude -> Nullable<Double>,
+        longitude -> Nullable<Double>,
+        asn -> Nullable<BigInt>,
+        as_org -> Nullable<Text>,
+    }
+}
+
+table! {
+    checkpoints (path) {
+        path -> Text,
+        inode -> BigInt,
+        byte_offset -> BigInt,
+    }
+}
+
+table! {
+    rejected_lines (id) {
+        id -> Integer,
+        epoch -> BigInt,
+        line -> Binary,
+        error -> Text,
+    }
+}
+
+table! {
+    rollups_minute (bucket, host, status_class) {
+        bucket -> BigInt,
+        host -> Text,
+        status_class -> Integer,
+        requests -> BigInt,
+        bytes -> BigInt,
+    }
+}
+
+table! {
+    rollups_hour (bucket, host, status_class) {
+        bucket -> BigInt,
+        host -> Text,
+        status_class -> Integer,
+        requests -> BigInt,
+        bytes -> BigInt,
+    }
+}
+
+table! {
+    rollups_day (bucket, host, status_class) {
+        bucket -> BigInt,
+        host -> Text,
+        status_class -> Integer,
+        requests -> BigInt,
+        bytes -> BigInt,
+    }
+}
+
+table! {
+    metadata (key) {
+        key -> Text,
+        value -> Text,
     }
 }
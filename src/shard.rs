@@ -0,0 +1,214 @@
+//! Rows can be split into a db file per month (eg: `logs-2026-10.db` next to `logs.db`), so that a
+//! month is dropped by deleting its file. A row belongs to the month of its epoch in UTC.
+
+use chrono::{Datelike, TimeZone, Utc};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Month {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl Month {
+    /// The month that the epoch falls in
+    pub fn of(epoch: i64) -> Month {
+        let date = Utc.timestamp(epoch, 0);
+        Month {
+            year: date.year(),
+            month: date.month(),
+        }
+    }
+
+    /// Epoch of the first second of the month
+    pub fn start(self) -> i64 {
+        Utc.ymd(self.year, self.month, 1)
+            .and_hms(0, 0, 0)
+            .timestamp()
+    }
+
+    pub fn next(self) -> Month {
+        if self.month == 12 {
+            Month {
+                year: self.year + 1,
+                month: 1,
+            }
+        } else {
+            Month {
+                year: self.year,
+                month: self.month + 1,
+            }
+        }
+    }
+
+    /// Path of the month's shard of the db (eg: `logs.db` becomes `logs-2026-10.db`)
+    pub fn path(self, db: &Path) -> PathBuf {
+        let stem = db
+            .file_stem()
+            .map(|x| x.to_string_lossy())
+            .unwrap_or_default();
+        let name = match db.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, self, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, self),
+        };
+        db.with_file_name(name)
+    }
+
+    /// Inverse of `path`
+    fn from_path(db: &Path, path: &Path) -> Option<Month> {
+        let stem = db.file_stem()?.to_str()?;
+        let name = path.file_name()?.to_str()?;
+        let rest = name.strip_prefix(stem)?.strip_prefix('-')?;
+        let (date, ext) = match rest.find('.') {
+            Some(ind) => (&rest[..ind], Some(&rest[ind + 1..])),
+            None => (rest, None),
+        };
+
+        if ext != db.extension().and_then(|x| x.to_str()) || date.len() != 7 {
+            return None;
+        }
+
+        let year = date[..4].parse().ok()?;
+        let month = date[5..].parse().ok()?;
+        if &date[4..5] != "-" || !(1..=12).contains(&month) {
+            return None;
+        }
+
+        Some(Month { year, month })
+    }
+}
+
+impl fmt::Display for Month {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// Months that overlap the epochs from `from` until `to`
+pub fn months_between(from: i64, to: i64) -> Vec<Month> {
+    let mut months = Vec::new();
+    let mut month = Month::of(from);
+    while month.start() < to || months.is_empty() {
+        months.push(month);
+        month = month.next();
+    }
+    months
+}
+
+/// The shards of the db that exist, from the oldest to the newest month
+pub fn existing(db: &Path) -> io::Result<Vec<(Month, PathBuf)>> {
+    let dir = match db.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut shards = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(month) = Month::from_path(db, &path) {
+            shards.push((month, db.with_file_name(path.file_name().unwrap())));
+        }
+    }
+
+    shards.sort();
+    Ok(shards)
+}
+
+/// Deletes the shard along with the WAL files that SQLite keeps next to it
+pub fn remove(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    for suffix in &["-wal", "-shm"] {
+        let mut side = path.as_os_str().to_owned();
+        side.push(suffix);
+        if let Err(e) = fs::remove_file(&side) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+
+    fn month(year: i32, month: u32) -> Month {
+        Month { year, month }
+    }
+
+    #[test]
+    fn test_month() {
+        assert_eq!(Month::of(1_509_818_735), month(2017, 11));
+        assert_eq!(month(2017, 11).start(), 1_509_494_400);
+        assert_eq!(month(2017, 12).next(), month(2018, 1));
+        assert_eq!(
+            month(2026, 10).path(Path::new("/var/lib/rrinlog/logs.db")),
+            PathBuf::from("/var/lib/rrinlog/logs-2026-10.db")
+        );
+        assert_eq!(
+            month(2026, 1).path(Path::new("logs")),
+            PathBuf::from("logs-2026-01")
+        );
+
+        let db = Path::new("/a/logs.db");
+        let parse = |x: &str| Month::from_path(db, Path::new(x));
+        assert_eq!(parse("/a/logs-2026-10.db"), Some(month(2026, 10)));
+        assert_eq!(parse("/a/logs-2026-10.db-wal"), None);
+        assert_eq!(parse("/a/logs-2026-13.db"), None);
+        assert_eq!(parse("/a/logs.db"), None);
+        assert_eq!(parse("/a/other-2026-10.db"), None);
+    }
+
+    #[test]
+    fn test_months_between() {
+        let nov = month(2017, 11).start();
+        assert_eq!(months_between(nov, nov + 10), vec![month(2017, 11)]);
+        assert_eq!(
+            months_between(nov - 10, month(2018, 1).start()),
+            vec![month(2017, 10), month(2017, 11), month(2017, 12)]
+        );
+        assert_eq!(
+            months_between(nov - 10, month(2018, 1).start() + 1),
+            vec![
+                month(2017, 10),
+                month(2017, 11),
+                month(2017, 12),
+                month(2018, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_existing() {
+        let dir = tempdir::TempDir::new("rrinlog-shard").unwrap();
+        let dir = dir.path();
+        for name in &[
+            "logs-2026-10.db",
+            "logs-2026-09.db",
+            "logs-2026-09.db-wal",
+            "x.db",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let db = dir.join("logs.db");
+        let shards = existing(&db).unwrap();
+        assert_eq!(
+            shards,
+            vec![
+                (month(2026, 9), dir.join("logs-2026-09.db")),
+                (month(2026, 10), dir.join("logs-2026-10.db")),
+            ]
+        );
+
+        remove(&shards[0].1).unwrap();
+        assert!(!dir.join("logs-2026-09.db-wal").exists());
+        assert_eq!(existing(&db).unwrap().len(), 1);
+    }
+}
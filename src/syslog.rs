@@ -0,0 +1,315 @@
+//! Strips the envelope from syslog messages so that nginx can ship access logs with
+//! `access_log syslog:server=...`. Both the BSD format (RFC 3164), which nginx sends, and the
+//! newer RFC 5424 format are understood. The payload is handed to another parser and the hostname
+//! and tag of the message are kept on the row.
+
+use models::NewLog;
+use parser::{LogParser, ParseError};
+use std::borrow::Cow;
+
+/// The parts of a syslog message that are kept. The priority and timestamp are discarded as the
+/// access log line has its own timestamp.
+#[derive(Debug, PartialEq)]
+pub struct SyslogMessage<'a> {
+    pub hostname: Option<&'a str>,
+    pub tag: Option<&'a str>,
+    pub payload: &'a str,
+}
+
+/// Parses the payload of syslog messages with the wrapped parser
+pub struct SyslogParser {
+    inner: Box<dyn LogParser>,
+}
+
+impl SyslogParser {
+    pub fn new(inner: Box<dyn LogParser>) -> SyslogParser {
+        SyslogParser { inner }
+    }
+}
+
+impl LogParser for SyslogParser {
+    fn parse<'a>(&'a self, line: &'a str) -> Result<NewLog<'a>, ParseError> {
+        let msg = parse_message(line)?;
+        let mut log = self.inner.parse(msg.payload)?;
+        log.syslog_hostname = msg.hostname.map(Cow::Borrowed);
+        log.syslog_tag = msg.tag.map(Cow::Borrowed);
+        Ok(log)
+    }
+
+    fn describe(&self) -> String {
+        format!("syslog messages containing {}", self.inner.describe())
+    }
+
+    fn has_host(&self) -> bool {
+        self.inner.has_host()
+    }
+}
+
+/// Splits a syslog message into the hostname, tag, and payload. In RFC 5424 messages the app name
+/// is used as the tag.
+pub fn parse_message(line: &str) -> Result<SyslogMessage<'_>, ParseError> {
+    let mut cursor = Cursor { text: line, pos: 0 };
+    cursor.expect("syslog priority", "<")?;
+    let pri = cursor.take_while(|b| b.is_ascii_digit());
+    if pri.is_empty() || pri.len() > 3 {
+        return Err(cursor.error("syslog priority", "a priority between `<` and `>`"));
+    }
+    cursor.expect("syslog priority", ">")?;
+
+    if cursor.rest().starts_with("1 ") {
+        cursor.pos += 2;
+        parse_rfc5424(cursor)
+    } else {
+        parse_rfc3164(cursor)
+    }
+}
+
+/// `<190>Nov  4 13:05:35 web1 nginx: 127.0.0.1 - - ...`
+fn parse_rfc3164(mut cursor: Cursor) -> Result<SyslogMessage, ParseError> {
+    let timestamp = cursor.rest().as_bytes();
+    let is_timestamp = timestamp.len() > 15
+        && timestamp[3] == b' '
+        && timestamp[6] == b' '
+        && timestamp[9] == b':'
+        && timestamp[12] == b':'
+        && timestamp[15] == b' ';
+    if !is_timestamp {
+        return Err(cursor.error("syslog timestamp", "a timestamp like `Nov  4 13:05:35`"));
+    }
+    cursor.pos += 16;
+
+    let hostname = cursor.take_value("syslog hostname")?;
+    cursor.expect("syslog tag", " ")?;
+    let tag_start = cursor.pos;
+    let tag = cursor.take_while(|b| b != b':' && b != b' ');
+    cursor.expect("syslog tag", ":").map_err(|_| {
+        ParseError::unexpected(cursor.text, "syslog tag", tag_start, "`:` after the tag")
+    })?;
+    cursor.skip(b' ');
+
+    // The tag may be followed by the process id (eg: `nginx[1234]`)
+    let tag = tag.split('[').next().unwrap_or(tag);
+    Ok(SyslogMessage {
+        hostname: Some(hostname),
+        tag: Some(tag).filter(|x| !x.is_empty()),
+        payload: cursor.rest(),
+    })
+}
+
+/// `<190>1 2017-11-04T13:05:35-05:00 web1 nginx 1234 - [meta x="y"] 127.0.0.1 - - ...`
+fn parse_rfc5424(mut cursor: Cursor) -> Result<SyslogMessage, ParseError> {
+    cursor.take_value("syslog timestamp")?;
+    cursor.expect("syslog hostname", " ")?;
+    let hostname = cursor.take_value("syslog hostname")?;
+    cursor.expect("syslog app name", " ")?;
+    let app_name = cursor.take_value("syslog app name")?;
+    cursor.expect("syslog process id", " ")?;
+    cursor.take_value("syslog process id")?;
+    cursor.expect("syslog message id", " ")?;
+    cursor.take_value("syslog message id")?;
+    cursor.expect("syslog structured data", " ")?;
+    cursor.skip_structured_data()?;
+
+    // The message is optional and may start with a byte order mark
+    cursor.skip(b' ');
+    let payload = cursor.rest();
+    let payload = payload.trim_start_matches('\u{feff}');
+    Ok(SyslogMessage {
+        hostname: nil(hostname),
+        tag: nil(app_name),
+        payload,
+    })
+}
+
+fn nil(value: &str) -> Option<&str> {
+    Some(value).filter(|&x| x != "-")
+}
+
+/// Tracks the position within the message. Only ASCII bytes are matched, so every slice lands on a
+/// char boundary.
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn error(&self, field: &str, expected: &str) -> ParseError {
+        ParseError::unexpected(self.text, field, self.pos, expected)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn take_while<F: Fn(u8) -> bool>(&mut self, pred: F) -> &'a str {
+        let len = self
+            .rest()
+            .bytes()
+            .position(|b| !pred(b))
+            .unwrap_or_else(|| self.rest().len());
+        let value = &self.text[self.pos..self.pos + len];
+        self.pos += len;
+        value
+    }
+
+    /// Returns the non-empty text up until the next space
+    fn take_value(&mut self, field: &str) -> Result<&'a str, ParseError> {
+        let value = self.take_while(|b| b != b' ');
+        if value.is_empty() {
+            Err(self.error(field, "a value"))
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn skip(&mut self, b: u8) {
+        if self.rest().as_bytes().first() == Some(&b) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, field: &str, lit: &str) -> Result<(), ParseError> {
+        if self.rest().starts_with(lit) {
+            self.pos += lit.len();
+            Ok(())
+        } else {
+            Err(self.error(field, &format!("`{}`", lit)))
+        }
+    }
+
+    /// Skips `-` or one or more `[id key="value"]` elements, where values may contain escaped
+    /// quotes and brackets
+    fn skip_structured_data(&mut self) -> Result<(), ParseError> {
+        if self.rest().starts_with('-') {
+            self.pos += 1;
+            return Ok(());
+        }
+
+        let field = "syslog structured data";
+        if !self.rest().starts_with('[') {
+            return Err(self.error(field, "`-` or `[`"));
+        }
+
+        let start = self.pos;
+        let (mut quoted, mut escaped) = (false, false);
+        while let Some(&b) = self.rest().as_bytes().first() {
+            self.pos += 1;
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' if quoted => escaped = true,
+                b'"' => quoted = !quoted,
+                b']' if !quoted && !self.rest().starts_with('[') => return Ok(()),
+                _ => {}
+            }
+        }
+
+        self.pos = start;
+        Err(self.error(field, "a closing `]`"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vhost::VhostParser;
+
+    #[test]
+    fn test_parse_rfc3164() {
+        let line = "<190>Nov  4 13:05:35 web1 nginx: 127.0.0.1 - - [...]";
+        assert_eq!(
+            parse_message(line),
+            Ok(SyslogMessage {
+                hostname: Some("web1"),
+                tag: Some("nginx"),
+                payload: "127.0.0.1 - - [...]",
+            })
+        );
+
+        let line = "<13>Oct 11 22:14:15 web2 nginx[1234]: hello";
+        assert_eq!(
+            parse_message(line),
+            Ok(SyslogMessage {
+                hostname: Some("web2"),
+                tag: Some("nginx"),
+                payload: "hello",
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_rfc5424() {
+        let line = "<165>1 2003-10-11T22:14:15.003Z web1 nginx 1234 ID47 [a b=\"c\\]\"][d] \u{feff}hello world";
+        assert_eq!(
+            parse_message(line),
+            Ok(SyslogMessage {
+                hostname: Some("web1"),
+                tag: Some("nginx"),
+                payload: "hello world",
+            })
+        );
+
+        let line = "<165>1 - - - - - - hello";
+        assert_eq!(
+            parse_message(line),
+            Ok(SyslogMessage {
+                hostname: None,
+                tag: None,
+                payload: "hello",
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_message("Nov  4 13:05:35 web1 nginx: hello"),
+            Err(ParseError::unexpected(
+                "Nov  4 13:05:35 web1 nginx: hello",
+                "syslog priority",
+                0,
+                "`<`"
+            ))
+        );
+
+        assert_eq!(
+            parse_message("<190>web1 nginx: hello"),
+            Err(ParseError::unexpected(
+                "<190>web1 nginx: hello",
+                "syslog timestamp",
+                5,
+                "a timestamp like `Nov  4 13:05:35`"
+            ))
+        );
+
+        assert_eq!(
+            parse_message("<190>Nov  4 13:05:35 web1 hello"),
+            Err(ParseError::unexpected(
+                "<190>Nov  4 13:05:35 web1 hello",
+                "syslog tag",
+                26,
+                "`:` after the tag"
+            ))
+        );
+
+        assert_eq!(
+            parse_message("<165>1 - - - - - [a b=\"]"),
+            Err(ParseError::unexpected(
+                "<165>1 - - - - - [a b=\"]",
+                "syslog structured data",
+                17,
+                "a closing `]`"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_syslog_parser() {
+        let parser = SyslogParser::new(Box::new(VhostParser::default()));
+        let line = r#"<190>Nov  4 13:05:35 web1 nginx: 127.0.0.1 - - [04/Nov/2017:13:05:35 -0500] "GET / HTTP/1.1" 200 0 "-" "-" "a.com""#;
+        let log = parser.parse(line).unwrap();
+        assert_eq!(log.epoch, 1509818735);
+        assert_eq!(log.host, "a.com");
+        assert_eq!(log.syslog_hostname, Some("web1".into()));
+        assert_eq!(log.syslog_tag, Some("nginx".into()));
+    }
+}
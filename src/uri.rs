@@ -0,0 +1,188 @@
+//! Splits a request target (eg: `/?uri=%2Fblog%2F&nested_limit=5`) into its path, query string,
+//! and fragment so that rows can be grouped by page regardless of query parameters. Paths are
+//! normalized according to RFC 3986: percent-encoded unreserved characters are decoded, the
+//! remaining percent-encodings are uppercased, and `.` and `..` segments are removed.
+
+use serde_json::{self, Map, Value};
+use std::borrow::Cow;
+
+/// Splits a request target into its path, query string, and fragment
+pub fn split(target: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (rest, fragment) = match target.find('#') {
+        Some(ind) => (&target[..ind], Some(&target[ind + 1..])),
+        None => (target, None),
+    };
+
+    match rest.find('?') {
+        Some(ind) => (&rest[..ind], Some(&rest[ind + 1..]), fragment),
+        None => (rest, None, fragment),
+    }
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_' || b == b'~'
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Returns the decoded byte of the percent-encoding that starts at the given index
+fn percent_byte(bytes: &[u8], ind: usize) -> Option<u8> {
+    if bytes.get(ind) != Some(&b'%') {
+        return None;
+    }
+
+    let hi = hex_value(*bytes.get(ind + 1)?)?;
+    let lo = hex_value(*bytes.get(ind + 2)?)?;
+    Some(hi << 4 | lo)
+}
+
+/// Normalizes a path, only allocating when the path changes. Targets that aren't paths (eg: `*`
+/// or the absolute URLs that proxies receive) are left untouched.
+pub fn normalize_path(path: &str) -> Cow<'_, str> {
+    let has_dot_segments = path.split('/').any(|x| x == "." || x == "..");
+    if !path.starts_with('/') || (!path.contains('%') && !has_dot_segments) {
+        return Cow::Borrowed(path);
+    }
+
+    let bytes = path.as_bytes();
+    let mut normalized = String::with_capacity(path.len());
+    let mut ind = 0;
+    while ind < bytes.len() {
+        match percent_byte(bytes, ind) {
+            Some(b) if is_unreserved(b) => {
+                normalized.push(char::from(b));
+                ind += 3;
+            }
+            Some(_) => {
+                normalized.push_str(&path[ind..ind + 3].to_ascii_uppercase());
+                ind += 3;
+            }
+            None => {
+                // Walk to the next percent sign so that multi-byte characters are copied whole
+                let end = path[ind + 1..]
+                    .find('%')
+                    .map_or(path.len(), |x| x + ind + 1);
+                normalized.push_str(&path[ind..end]);
+                ind = end;
+            }
+        }
+    }
+
+    Cow::Owned(remove_dot_segments(&normalized))
+}
+
+/// Resolves `.` and `..` segments in an absolute path (RFC 3986 section 5.2.4)
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path[1..].split('/').peekable();
+    while let Some(segment) = parts.next() {
+        let is_last = parts.peek().is_none();
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    segments.pop();
+                }
+
+                // A trailing dot segment refers to a directory, so keep the trailing slash
+                if is_last {
+                    segments.push("");
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    format!("/{}", segments.join("/"))
+}
+
+/// Decodes a query string component, where `+` is a space. Invalid UTF-8 is replaced.
+pub fn decode_component(component: &str) -> Cow<'_, str> {
+    if !component.contains('%') && !component.contains('+') {
+        return Cow::Borrowed(component);
+    }
+
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut ind = 0;
+    while ind < bytes.len() {
+        if let Some(b) = percent_byte(bytes, ind) {
+            decoded.push(b);
+            ind += 3;
+        } else {
+            decoded.push(if bytes[ind] == b'+' { b' ' } else { bytes[ind] });
+            ind += 1;
+        }
+    }
+
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Decodes the query string's parameters into a JSON object. Parameters that are repeated are
+/// collected into an array, so `?a=1&a=2&b` becomes `{"a":["1","2"],"b":""}`.
+pub fn query_params(query: &str) -> String {
+    let mut params = Map::new();
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        let key = decode_component(kv.next().unwrap_or("")).into_owned();
+        let value = Value::String(decode_component(kv.next().unwrap_or("")).into_owned());
+        let entry = params.entry(key).or_insert(Value::Null);
+        match *entry {
+            Value::Null => *entry = value,
+            Value::Array(ref mut values) => values.push(value),
+            _ => {
+                let first = entry.take();
+                *entry = Value::Array(vec![first, value]);
+            }
+        }
+    }
+
+    serde_json::to_string(&params).expect("query parameters to serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("/"), ("/", None, None));
+        assert_eq!(split("/a?b=c#d"), ("/a", Some("b=c"), Some("d")));
+        assert_eq!(split("/a#d?e"), ("/a", None, Some("d?e")));
+        assert_eq!(split("/a?"), ("/a", Some(""), None));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/js/embed.min.js"), "/js/embed.min.js");
+        assert_eq!(normalize_path("/%7euser/%2fa%2Fb"), "/~user/%2Fa%2Fb");
+        assert_eq!(normalize_path("/a/./b/../c"), "/a/c");
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/ü%zz%4"), "/ü%zz%4");
+        assert_eq!(normalize_path("*"), "*");
+        assert_eq!(
+            normalize_path("http://example.com/./a"),
+            "http://example.com/./a"
+        );
+    }
+
+    #[test]
+    fn test_query_params() {
+        assert_eq!(
+            query_params("uri=%2Fblog%2Fsome-post&nested_limit=5"),
+            r#"{"nested_limit":"5","uri":"/blog/some-post"}"#
+        );
+        assert_eq!(
+            query_params("a=1&a=2&a=3&b&&q=hello+world%21"),
+            r#"{"a":["1","2","3"],"b":"","q":"hello world!"}"#
+        );
+        assert_eq!(query_params("bad=%ff"), "{\"bad\":\"\u{fffd}\"}");
+    }
+}
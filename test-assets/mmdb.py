@@ -0,0 +1,159 @@
+#!/usr/bin/env python3
+"""Writes the small MaxMind DB files (city and ASN) that the tests enrich rows from.
+
+Only the parts of the format (https://maxmind.github.io/MaxMind-DB/) that the fixtures need are
+implemented: an IPv6 tree with IPv4 networks under ::/96, 24 bit records, and maps, arrays,
+strings, doubles, and unsigned ints. Run from the repo root with `python3 test-assets/mmdb.py`.
+"""
+
+import ipaddress
+import struct
+
+RECORD_BITS = 24
+
+# Fixed, so that the files only change when the networks do
+BUILD_EPOCH = 1792195200
+
+
+def encode(value):
+    if isinstance(value, dict):
+        body = b"".join(encode(k) + encode(v) for k, v in value.items())
+        return control(7, len(value)) + body
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(x) for x in value)
+    if isinstance(value, str):
+        data = value.encode("utf-8")
+        return control(2, len(data)) + data
+    if isinstance(value, float):
+        return control(3, 8) + struct.pack(">d", value)
+    if isinstance(value, int):
+        data = value.to_bytes((value.bit_length() + 7) // 8, "big")
+        if value < 1 << 16:
+            return control(5, len(data)) + data
+        if value < 1 << 32:
+            return control(6, len(data)) + data
+        return control(9, len(data)) + data
+    raise TypeError(value)
+
+
+def control(kind, size):
+    assert size < 29 + 256, "long values aren't needed by the fixtures"
+    extra = b""
+    if size >= 29:
+        size, extra = 29, bytes([size - 29])
+    if kind <= 7:
+        return bytes([(kind << 5) | size]) + extra
+    return bytes([size, kind - 7]) + extra
+
+
+def bits(network):
+    network = ipaddress.ip_network(network)
+    addr = int(network.network_address)
+    prefix = network.prefixlen + (96 if network.version == 4 else 0)
+    return [(addr >> (127 - i)) & 1 for i in range(prefix)]
+
+
+def write(path, database_type, networks):
+    """`networks` maps a network (eg: "81.2.69.0/24") to the record of its addresses"""
+    data = b""
+    offsets = []
+    for record in networks.values():
+        offsets.append(len(data))
+        data += encode(record)
+
+    # Each node is [left, right], which is either another node, a data offset, or None
+    nodes = [[None, None]]
+    for (network, _), offset in zip(networks.items(), offsets):
+        node = 0
+        path_bits = bits(network)
+        for bit in path_bits[:-1]:
+            child = nodes[node][bit]
+            if child is None:
+                nodes.append([None, None])
+                child = ("node", len(nodes) - 1)
+                nodes[node][bit] = child
+            node = child[1]
+        nodes[node][path_bits[-1]] = ("data", offset)
+
+    count = len(nodes)
+
+    def record(value):
+        if value is None:
+            return count
+        if value[0] == "node":
+            return value[1]
+        return count + 16 + value[1]
+
+    tree = b"".join(
+        record(left).to_bytes(3, "big") + record(right).to_bytes(3, "big")
+        for left, right in nodes
+    )
+
+    metadata = {
+        "node_count": count,
+        "record_size": RECORD_BITS,
+        "ip_version": 6,
+        "database_type": database_type,
+        "languages": ["en"],
+        "binary_format_major_version": 2,
+        "binary_format_minor_version": 0,
+        "build_epoch": BUILD_EPOCH,
+        "description": {"en": "rrinlog test fixture"},
+    }
+
+    with open(path, "wb") as f:
+        f.write(tree)
+        f.write(b"\x00" * 16)
+        f.write(data)
+        f.write(b"\xab\xcd\xefMaxMind.com")
+        f.write(encode(metadata))
+
+
+def city(name, country, iso_code, region, region_code, lat, lon):
+    return {
+        "city": {"names": {"en": name}},
+        "country": {"iso_code": iso_code, "names": {"en": country}},
+        "subdivisions": [{"iso_code": region_code, "names": {"en": region}}],
+        "location": {"latitude": lat, "longitude": lon},
+    }
+
+
+def asn(number, organization):
+    return {
+        "autonomous_system_number": number,
+        "autonomous_system_organization": organization,
+    }
+
+
+if __name__ == "__main__":
+    write(
+        "test-assets/GeoLite2-City-Test.mmdb",
+        "GeoLite2-City",
+        {
+            "81.2.69.0/24": city(
+                "London", "United Kingdom", "GB", "England", "ENG", 51.5142, -0.0931
+            ),
+            "89.160.20.0/24": city(
+                "Linköping", "Sweden", "SE", "Östergötland County", "E", 58.4167, 15.6167
+            ),
+            "2001:db8::/32": city(
+                "Boxford", "United Kingdom", "GB", "England", "ENG", 51.75, -1.25
+            ),
+            # Only the country is known
+            "175.16.199.0/24": {
+                "country": {"iso_code": "CN", "names": {"en": "China"}},
+                "location": {"latitude": 34.7725, "longitude": 113.7266},
+            },
+        },
+    )
+    write(
+        "test-assets/GeoLite2-ASN-Test.mmdb",
+        "GeoLite2-ASN",
+        {
+            "81.2.69.0/24": asn(20712, "Andrews & Arnold Ltd"),
+            "89.160.20.0/24": asn(29518, "Bredband2 AB"),
+            "2001:db8::/32": asn(64496, "Example Networks"),
+            # Above 2^16, so stored as a uint32
+            "175.16.199.0/24": asn(4200000000, "Example Private AS"),
+        },
+    )